@@ -12,8 +12,9 @@ use bevy::{
     render::camera::WindowOrigin,
 };
 use bevy_networking_turbulence::{
-    ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings, NetworkEvent,
-    NetworkResource, NetworkingPlugin, ReliableChannelSettings,
+    ConnectionChannelsBuilder, ConnectionHandle, MessageChannelMode, MessageChannelSettings,
+    NetworkEvent, NetworkResource, NetworkingPlugin, ReliableChannelSettings, SnapshotBuffer,
+    SnapshotInterpolation, SnapshotInterpolationPlugin,
 };
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -32,7 +33,7 @@ fn main() {
 
 #[derive(Component)]
 struct Pawn {
-    controller: u32,
+    controller: ConnectionHandle,
 }
 #[derive(Component)]
 struct Ball {
@@ -67,6 +68,7 @@ impl Plugin for BallsExample {
             .add_plugins(DefaultPlugins)
             .insert_resource(ClearColor(Color::rgb(0.3, 0.3, 0.3)))
             .add_startup_system(client_setup.system())
+            .add_plugin(SnapshotInterpolationPlugin::<Transform>::default())
             .add_system_to_stage(CoreStage::PreUpdate, handle_messages_client.system())
             .insert_resource(ServerIds::default())
             .add_system(ball_control_system.system())
@@ -110,7 +112,8 @@ fn server_setup(mut net: ResMut<NetworkResource>) {
         bevy_networking_turbulence::find_my_ip_address().expect("can't find ip address");
     let socket_address = SocketAddr::new(ip_address, SERVER_PORT);
     info!("Starting server");
-    net.listen(socket_address, None, None);
+    net.listen(socket_address, None, None)
+        .expect("can't listen");
 }
 
 fn client_setup(mut commands: Commands, mut net: ResMut<NetworkResource>) {
@@ -177,7 +180,9 @@ const CLIENT_STATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSett
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GameStateMessage {
     frame: u32,
-    balls: Vec<(u32, Vec3, Vec3)>,
+    server_time: f64,
+    /// server entity id, controlling connection of the server, velocity and translation
+    balls: Vec<(u32, ConnectionHandle, Vec3, Vec3)>,
 }
 
 const GAME_STATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
@@ -190,18 +195,23 @@ const GAME_STATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettin
 fn network_broadcast_system(
     mut state: ResMut<NetworkBroadcast>,
     mut net: ResMut<NetworkResource>,
-    ball_query: Query<(Entity, &Ball, &Transform)>,
+    time: Res<Time>,
+    ball_query: Query<(Entity, &Ball, &Pawn, &Transform)>,
 ) {
     let mut message = GameStateMessage {
         frame: state.frame,
+        server_time: time.seconds_since_startup(),
         balls: Vec::new(),
     };
     state.frame += 1;
 
-    for (entity, ball, transform) in ball_query.iter() {
-        message
-            .balls
-            .push((entity.id(), ball.velocity, transform.translation));
+    for (entity, ball, pawn, transform) in ball_query.iter() {
+        message.balls.push((
+            entity.id(),
+            pawn.controller,
+            ball.velocity,
+            transform.translation,
+        ));
     }
 
     net.broadcast_message(message);
@@ -306,8 +316,10 @@ type ServerIds = HashMap<u32, (u32, u32)>;
 fn handle_messages_client(
     mut commands: Commands,
     mut net: ResMut<NetworkResource>,
+    time: Res<Time>,
+    mut interpolation: ResMut<SnapshotInterpolation>,
     mut server_ids: ResMut<ServerIds>,
-    mut balls: Query<(Entity, &mut Ball, &mut Transform)>,
+    mut balls: Query<(Entity, &mut Ball, &mut SnapshotBuffer<Transform>)>,
 ) {
     for (handle, connection) in net.connections.iter_mut() {
         let channels = connection.channels().unwrap();
@@ -316,17 +328,19 @@ fn handle_messages_client(
         }
 
         // it is possible that many state updates came at the same time - spawn once
-        let mut to_spawn: HashMap<u32, (u32, Vec3, Vec3)> = HashMap::new();
+        let mut to_spawn: HashMap<u32, (u32, f64, ConnectionHandle, Vec3, Vec3)> = HashMap::new();
 
         while let Some(mut state_message) = channels.recv::<GameStateMessage>() {
             let message_frame = state_message.frame;
+            let server_time = state_message.server_time;
             info!(
                 "GameStateMessage received on [{}]: {:?}",
                 handle, state_message
             );
+            interpolation.observe_server_time(server_time, time.seconds_since_startup());
 
             // update all balls
-            for (entity, mut ball, mut transform) in balls.iter_mut() {
+            for (entity, mut ball, mut snapshots) in balls.iter_mut() {
                 let server_id_entry = server_ids.get_mut(&entity.id()).unwrap();
                 let (server_id, update_frame) = *server_id_entry;
 
@@ -335,7 +349,8 @@ fn handle_messages_client(
                     .iter()
                     .position(|&update| update.0 == server_id)
                 {
-                    let (_id, velocity, translation) = state_message.balls.remove(index);
+                    let (_id, _controller, velocity, translation) =
+                        state_message.balls.remove(index);
 
                     if update_frame > message_frame {
                         continue;
@@ -343,24 +358,35 @@ fn handle_messages_client(
                     server_id_entry.1 = message_frame;
 
                     ball.velocity = velocity;
-                    transform.translation = translation;
+                    snapshots.push(server_time, Transform::from_translation(translation));
                 } else {
                     // TODO: despawn disconnected balls
                 }
             }
             // create new balls
-            for (id, velocity, translation) in state_message.balls.drain(..) {
-                if let Some((frame, _velocity, _translation)) = to_spawn.get(&id) {
+            for (id, controller, velocity, translation) in state_message.balls.drain(..) {
+                if let Some((frame, ..)) = to_spawn.get(&id) {
                     if *frame > message_frame {
                         continue;
                     }
                 };
-                to_spawn.insert(id, (message_frame, velocity, translation));
+                to_spawn.insert(
+                    id,
+                    (
+                        message_frame,
+                        server_time,
+                        controller,
+                        velocity,
+                        translation,
+                    ),
+                );
             }
         }
 
-        for (id, (frame, velocity, translation)) in to_spawn.iter() {
+        for (id, (frame, server_time, controller, velocity, translation)) in to_spawn.iter() {
             info!("Spawning {} @{}", id, frame);
+            let mut snapshots = SnapshotBuffer::<Transform>::default();
+            snapshots.push(*server_time, Transform::from_translation(*translation));
             let entity = commands
                 .spawn_bundle(SpriteBundle {
                     transform: Transform::from_translation(*translation),
@@ -374,7 +400,10 @@ fn handle_messages_client(
                 .insert(Ball {
                     velocity: *velocity,
                 })
-                .insert(Pawn { controller: *id })
+                .insert(Pawn {
+                    controller: *controller,
+                })
+                .insert(snapshots)
                 .id();
             server_ids.insert(entity.id(), (*id, *frame));
         }
@@ -0,0 +1,88 @@
+use bevy::{app::ScheduleRunnerSettings, log::LogPlugin, prelude::*};
+use bevy_networking_turbulence::{
+    ConnectTarget, NetworkEvent, NetworkResource, NetworkingPlugin, Packet, RendezvousServer,
+    UdpListener,
+};
+
+use std::{net::SocketAddr, time::Duration};
+
+mod utils;
+use utils::{parse_rendezvous_args, RendezvousArgs as Args, RendezvousRole};
+
+fn main() {
+    let args = parse_rendezvous_args();
+    let rendezvous_address: SocketAddr = args
+        .rendezvous_address
+        .parse()
+        .expect("invalid rendezvous address");
+
+    if args.role == RendezvousRole::Rendezvous {
+        // doesn't need bevy, just a publicly reachable UDP port
+        let mut server = RendezvousServer::bind(rendezvous_address).expect("can't bind rendezvous");
+        println!("Rendezvous listening on {}", rendezvous_address);
+        server.run().expect("rendezvous failed");
+        return;
+    }
+
+    App::new()
+        // minimal plugins necessary for timers + headless loop
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin)
+        .add_plugin(NetworkingPlugin::default())
+        .insert_resource(args)
+        .add_startup_system(startup.system())
+        .add_system(send_packets.system())
+        .add_system(handle_packets.system())
+        .run();
+}
+
+fn startup(mut net: ResMut<NetworkResource>, args: Res<Args>) {
+    let rendezvous_address: SocketAddr = args.rendezvous_address.parse().unwrap();
+    if args.role == RendezvousRole::Host {
+        // any port will do, the client learns it from the rendezvous
+        let listener = UdpListener::bind("0.0.0.0:0".parse().unwrap())
+            .expect("can't listen")
+            .with_rendezvous(rendezvous_address, args.session.clone());
+        info!("Hosting session {:?}", args.session);
+        net.listen_with(listener);
+    } else {
+        info!("Joining session {:?}", args.session);
+        net.connect(ConnectTarget::Rendezvous {
+            server: rendezvous_address,
+            session: args.session.clone(),
+        });
+    }
+}
+
+fn send_packets(mut net: ResMut<NetworkResource>, time: Res<Time>, args: Res<Args>) {
+    if args.role == RendezvousRole::Client && (time.seconds_since_startup() * 60.) as i64 % 60 == 0
+    {
+        info!("PING");
+        net.broadcast(Packet::from("PING"));
+    }
+}
+
+fn handle_packets(
+    mut net: ResMut<NetworkResource>,
+    time: Res<Time>,
+    mut reader: EventReader<NetworkEvent>,
+) {
+    for event in reader.iter() {
+        match event {
+            NetworkEvent::Packet(handle, packet) => {
+                let message = String::from_utf8_lossy(packet);
+                info!("Got packet on [{}]: {}", handle, message);
+                if message == "PING" {
+                    let message = format!("PONG @ {}", time.seconds_since_startup());
+                    if let Err(error) = net.send(*handle, Packet::from(message)) {
+                        info!("PONG send error: {}", error);
+                    }
+                }
+            }
+            event => info!("{event:?} received!"),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use bevy::{app::ScheduleRunnerSettings, log::LogPlugin, prelude::*};
+use bevy_networking_turbulence::{NetworkEvent, NetworkResource, NetworkingPlugin, PacketReplay};
+
+use std::time::Duration;
+
+// Record a capture with `NetworkResource::start_capture(PacketCapture::create("session.cap")?)`
+// in your game, then replay it offline:
+//
+//     $ env RUST_LOG=debug cargo run --example replay -- session.cap
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: replay <capture file>");
+    let replay = PacketReplay::open(&path).expect("can't read capture");
+    println!("Replaying {} records from {}", replay.records().len(), path);
+
+    App::new()
+        // minimal plugins necessary for timers + headless loop
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin)
+        // The NetworkingPlugin
+        .add_plugin(NetworkingPlugin::default())
+        .insert_resource(Some(replay))
+        .add_startup_system(startup.system())
+        .add_system(handle_packets.system())
+        .run();
+}
+
+fn startup(mut net: ResMut<NetworkResource>, mut replay: ResMut<Option<PacketReplay>>) {
+    if let Some(replay) = replay.take() {
+        net.replay(replay, 1.0);
+    }
+}
+
+fn handle_packets(mut reader: EventReader<NetworkEvent>) {
+    for event in reader.iter() {
+        match event {
+            NetworkEvent::Packet(handle, packet) => {
+                info!("Replayed packet on [{}]: {:02x?}", handle, &packet[..]);
+            }
+            event => info!("{event:?} received!"),
+        }
+    }
+}
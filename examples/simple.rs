@@ -44,7 +44,8 @@ fn startup(mut net: ResMut<NetworkResource>, args: Res<Args>) {
     #[cfg(not(target_arch = "wasm32"))]
     if args.is_server {
         info!("Starting server");
-        net.listen(server_address, None, None);
+        net.listen(server_address, None, None)
+            .expect("can't listen");
     }
     if !args.is_server {
         info!("Starting client");
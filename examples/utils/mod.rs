@@ -27,6 +27,20 @@ pub struct IdleTimeoutArgs {
     pub auto_heartbeat_ms: Option<usize>,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum RendezvousRole {
+    Rendezvous,
+    Host,
+    Client,
+}
+
+#[derive(Debug)]
+pub struct RendezvousArgs {
+    pub role: RendezvousRole,
+    pub rendezvous_address: String,
+    pub session: String,
+}
+
 fn exe_name() -> String {
     match std::env::current_exe() {
         Ok(pathbuf) => match pathbuf.file_name() {
@@ -92,6 +106,26 @@ pub fn parse_idle_timeout_args() -> IdleTimeoutArgs {
     }
 }
 
+#[allow(dead_code)]
+pub fn parse_rendezvous_args() -> RendezvousArgs {
+    let matches = ClapApp::new(exe_name())
+        .about("NAT traversal example, run a rendezvous, a host and a client")
+        .args(rendezvous_args().as_slice())
+        .get_matches();
+    let role = if matches.is_present("rendezvous") {
+        RendezvousRole::Rendezvous
+    } else if matches.is_present("host") {
+        RendezvousRole::Host
+    } else {
+        RendezvousRole::Client
+    };
+    RendezvousArgs {
+        role,
+        rendezvous_address: value_t_or_exit!(matches, "rendezvous-address", String),
+        session: value_t_or_exit!(matches, "session", String),
+    }
+}
+
 fn server_or_client_args<'a>() -> Vec<Arg<'a, 'a>> {
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
@@ -172,3 +206,38 @@ fn pings_pongs_args<'a>() -> Vec<Arg<'a, 'a>> {
         .takes_value(true)
     ]
 }
+
+#[allow(dead_code)]
+fn rendezvous_args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("rendezvous")
+        .help("Introduce hosts and clients")
+        .long("rendezvous")
+        .required_unless_one(&["host", "client"])
+        .conflicts_with_all(&["host", "client"])
+        .takes_value(false)
+        ,
+        Arg::with_name("host")
+        .help("Host a session through the rendezvous")
+        .long("host")
+        .conflicts_with("client")
+        .takes_value(false)
+        ,
+        Arg::with_name("client")
+        .help("Join a session through the rendezvous")
+        .long("client")
+        .takes_value(false)
+        ,
+        Arg::with_name("rendezvous-address")
+        .long("rendezvous-address")
+        .default_value("127.0.0.1:14192")
+        .help("Address of the rendezvous server")
+        .takes_value(true)
+        ,
+        Arg::with_name("session")
+        .long("session")
+        .default_value("demo")
+        .help("Session the host registers and the client joins")
+        .takes_value(true)
+    ]
+}
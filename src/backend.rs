@@ -0,0 +1,448 @@
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::tasks::Task;
+use bevy::{prelude::error, tasks::TaskPool};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use turbulence::{
+    buffer::BufferPacketPool,
+    message_channels::MessageChannels,
+    packet_multiplexer::{IncomingMultiplexedPackets, MuxPacketPool, PacketMultiplexer},
+};
+
+use super::{
+    capture::{CaptureDirection, CaptureSlot, ConnectionCapture},
+    channels::{SimpleBufferPool, TaskPoolRuntime},
+    codec::EncodedChannels,
+    compression::PacketCompressor,
+    conditioner::{ConditionedLink, ConnectionConditioner},
+    transport::{
+        next_outgoing, packet_channel, Connection, ConnectionChannelsBuilder, MultiplexedPacket,
+        PacketStats, TransportKind,
+    },
+    ListenerId, NetworkError, Packet,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{query::QueryResponder, NetworkResource};
+
+/// Sending half of a link, shared with the channels task
+pub trait PacketSink: Send + Sync + 'static {
+    /// Sends a datagram without blocking. Dropping it when the socket is busy is fine.
+    fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>>;
+}
+
+/// Receiving half of a link
+pub trait PacketSource: Send + Sync + 'static {
+    /// Next received datagram without blocking, None if there is nothing to read.
+    /// `Err(NetworkError::Disconnected)` once the link is closed for good.
+    fn try_recv(&mut self) -> Option<Result<Packet, NetworkError>>;
+}
+
+impl PacketSink for Sender<Packet> {
+    fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Sender::send(self, Packet::copy_from_slice(packet)).map_err(|_| {
+            Box::new(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Link receiver dropped",
+            )) as Box<dyn Error + Sync + Send>
+        })
+    }
+}
+
+impl PacketSource for Receiver<Packet> {
+    fn try_recv(&mut self) -> Option<Result<Packet, NetworkError>> {
+        match Receiver::try_recv(self) {
+            Ok(packet) => Some(Ok(packet)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(NetworkError::Disconnected)),
+        }
+    }
+}
+
+/// Datagram link to a single peer, opened by a `Transport` or accepted by a `Listener`.
+///
+/// The plugin wraps it in a connection with the usual handles, heartbeats, compression,
+/// packet capture, link conditioning and turbulence channels.
+pub struct TransportLink {
+    pub sink: Arc<dyn PacketSink>,
+    pub source: Box<dyn PacketSource>,
+    pub remote_address: Option<SocketAddr>,
+    pub kind: TransportKind,
+}
+
+/// Custom client socket backend, see `NetworkResource::connect_with`
+pub trait Transport: Send + Sync + 'static {
+    /// Opens a link to a server. It doesn't have to be reachable yet -
+    /// servers which never answer are reported with `NetworkEvent::ConnectFailed`.
+    fn connect(&self, address: SocketAddr) -> Result<TransportLink, Box<dyn Error + Sync + Send>>;
+
+    /// Opens a link to a `"host:port"` without resolving it first, for transports which need
+    /// the name itself, eg. to verify a certificate. None lets the plugin resolve the host and
+    /// call `connect`, which doesn't work in the browser.
+    fn connect_host(
+        &self,
+        _host: &str,
+    ) -> Option<Result<TransportLink, Box<dyn Error + Sync + Send>>> {
+        None
+    }
+}
+
+/// Custom server socket backend, see `NetworkResource::listen_with`
+pub trait Listener: Send + Sync + 'static {
+    /// Address the listener is bound to
+    fn local_address(&self) -> SocketAddr;
+
+    /// Next new peer without blocking, polled by `receive_packets`
+    fn accept(&mut self) -> Option<TransportLink>;
+
+    /// The connection of the peer at `address` was dropped by `NetworkResource::disconnect`.
+    /// Its link is gone, later packets from the peer may be accepted as a new connection.
+    fn disconnected(&mut self, _address: SocketAddr) {}
+
+    /// Called by `listen_with`. Listeners receiving connectionless datagrams should pass them
+    /// to `responder` first, and send back its response instead of accepting the peer.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn answer_queries(&mut self, _responder: QueryResponder) {}
+
+    /// Address peers on the internet reach the listener at, if it knows one
+    fn public_address(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Connection over a `TransportLink`
+pub(crate) struct TransportConnection {
+    task_pool: TaskPool,
+
+    sink: Arc<dyn PacketSink>,
+    source: Box<dyn PacketSource>,
+    disconnected: bool,
+    remote_address: Option<SocketAddr>,
+    kind: TransportKind,
+    listener: Option<ListenerId>,
+    stats: Arc<RwLock<PacketStats>>,
+    compressor: Arc<PacketCompressor>,
+    capture: CaptureSlot,
+    link: ConditionedLink,
+
+    channels: Option<MessageChannels>,
+    encoded_channels: Option<EncodedChannels>,
+    channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    channels_task: Option<Task<()>>,
+}
+
+impl TransportConnection {
+    pub(crate) fn new(
+        task_pool: TaskPool,
+        link: TransportLink,
+        listener: Option<ListenerId>,
+        compressor: Arc<PacketCompressor>,
+    ) -> Self {
+        TransportConnection {
+            task_pool,
+            sink: link.sink,
+            source: link.source,
+            disconnected: false,
+            remote_address: link.remote_address,
+            kind: link.kind,
+            listener,
+            stats: Arc::new(RwLock::new(PacketStats::default())),
+            compressor,
+            capture: CaptureSlot::default(),
+            link: ConditionedLink::default(),
+            channels: None,
+            encoded_channels: None,
+            channels_rx: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            channels_task: None,
+        }
+    }
+
+    /// Sends raw packets the link conditioner let through
+    fn flush_outbound(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        while let Some(packet) = self.link.pop_outbound() {
+            self.sink.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection for TransportConnection {
+    fn remote_address(&self) -> Option<SocketAddr> {
+        self.remote_address
+    }
+
+    fn transport(&self) -> TransportKind {
+        self.kind
+    }
+
+    fn listener(&self) -> Option<ListenerId> {
+        self.listener
+    }
+
+    fn compressor(&self) -> Option<&PacketCompressor> {
+        Some(&self.compressor)
+    }
+
+    fn stats(&self) -> PacketStats {
+        self.stats.read().expect("stats lock poisoned").clone()
+    }
+
+    fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.capture
+            .record(CaptureDirection::Outbound, None, &payload);
+        let packet = self.compressor.compress(&payload);
+        self.stats
+            .write()
+            .expect("stats lock poisoned")
+            .add_tx(packet.len(), payload.len());
+        self.link.push_outbound(Packet::from(packet));
+        self.flush_outbound()
+    }
+
+    fn last_packet_timings(&self) -> (u128, u128) {
+        let (rx_dur, tx_dur) = self
+            .stats
+            .read()
+            .expect("stats lock poisoned")
+            .idle_durations();
+        (rx_dur.as_millis(), tx_dur.as_millis())
+    }
+
+    fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+        if let Err(err) = self.flush_outbound() {
+            error!("Transport Send Error: {}", err);
+        }
+        while !self.disconnected {
+            match self.source.try_recv() {
+                Some(Ok(packet)) => self.link.push_inbound(packet),
+                Some(Err(NetworkError::Disconnected)) => {
+                    // report the link going away once
+                    self.disconnected = true;
+                    return Some(Err(NetworkError::Disconnected));
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+        }
+
+        let packet = self.link.pop_inbound()?;
+        match self.compressor.decompress(&packet) {
+            Ok(payload) => {
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_rx(packet.len(), payload.len());
+                self.capture.record(
+                    CaptureDirection::Inbound,
+                    packet_channel(self.channels_rx.is_some(), &payload),
+                    &payload,
+                );
+                Some(Ok(Packet::from(payload)))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    fn build_channels(
+        &mut self,
+        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
+        runtime: TaskPoolRuntime,
+        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
+    ) {
+        let mut builder = ConnectionChannelsBuilder::new(runtime, pool);
+        builder_fn(&mut builder);
+
+        let mut multiplexer = PacketMultiplexer::new();
+        let (channels, encoded_channels) = builder.build(&mut multiplexer);
+        self.channels = Some(channels);
+        self.encoded_channels = Some(encoded_channels);
+        let (channels_rx, mut channels_tx) = multiplexer.start();
+        self.channels_rx = Some(channels_rx);
+
+        let sink = self.sink.clone();
+        let stats = self.stats.clone();
+        let compressor = self.compressor.clone();
+        let capture = self.capture.clone();
+        let link = self.link.clone();
+
+        let closure = async move {
+            loop {
+                if let Some(packet) = next_outgoing(&mut channels_tx, &link).await {
+                    let packet = match packet {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    capture.record(CaptureDirection::Outbound, packet.first().copied(), &packet);
+                    let compressed = compressor.compress(&packet);
+                    stats
+                        .write()
+                        .expect("stats lock poisoned")
+                        .add_tx(compressed.len(), packet.len());
+                    link.push_outbound(Packet::from(compressed));
+                }
+                while let Some(packet) = link.pop_outbound() {
+                    if let Err(err) = sink.send(&packet) {
+                        error!("Transport Send Error: {}", err);
+                    }
+                }
+            }
+            error!("Transport channel stream Disconnected");
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.channels_task = Some(self.task_pool.spawn(closure));
+        }
+        #[cfg(target_arch = "wasm32")]
+        self.task_pool.spawn(closure);
+    }
+
+    fn channels(&mut self) -> Option<&mut MessageChannels> {
+        self.channels.as_mut()
+    }
+
+    fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+        self.channels_rx.as_mut()
+    }
+
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        self.encoded_channels.as_mut()
+    }
+
+    fn set_capture(&mut self, capture: Option<ConnectionCapture>) {
+        self.capture.set(capture);
+    }
+
+    fn set_link_conditioner(&mut self, conditioner: Option<ConnectionConditioner>) {
+        self.link.set(conditioner);
+    }
+
+    fn flush_link(&mut self) {
+        self.link.release_outbound();
+        if let Err(err) = self.flush_outbound() {
+            error!("Transport Send Error: {}", err);
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+unsafe impl Send for TransportConnection {}
+
+#[cfg(target_arch = "wasm32")]
+unsafe impl Sync for TransportConnection {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<L> NetworkResource<L> {
+    /// Accepts connections from a custom `Listener`, like `listen` does with naia sockets
+    pub fn listen_with<T: Listener>(&mut self, mut listener: T) -> ListenerId {
+        listener.answer_queries(self.query_handler.clone());
+        let id = ListenerId(self.listener_sequence);
+        self.listener_sequence += 1;
+        self.listener_addresses.insert(id, listener.local_address());
+        self.custom_listeners.insert(id, Box::new(listener));
+        id
+    }
+
+    /// Public address of a custom listener, eg. learned by `UdpListener::with_rendezvous`
+    pub fn listener_public_address(&self, listener: ListenerId) -> Option<SocketAddr> {
+        self.custom_listeners.get(&listener)?.public_address()
+    }
+
+    /// Queues connections of peers accepted by custom listeners
+    pub(crate) fn poll_listeners(&mut self) {
+        let mut accepted = Vec::new();
+        for (id, listener) in self.custom_listeners.iter_mut() {
+            while let Some(link) = listener.accept() {
+                accepted.push((*id, link));
+            }
+        }
+        for (id, link) in accepted {
+            let connection = Box::new(TransportConnection::new(
+                self.task_pool.clone(),
+                link,
+                Some(id),
+                Arc::new(PacketCompressor::new(self.compression.clone())),
+            ));
+            self.add_pending_connection(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conditioner::{LinkConditioner, LinkConditions, LinkProfile},
+        ConnectionHandle,
+    };
+    use instant::Duration;
+
+    fn conditioned_connection(
+        latency_ms: u32,
+    ) -> (TransportConnection, Receiver<Packet>, Sender<Packet>) {
+        let (sink, sent) = crossbeam_channel::unbounded::<Packet>();
+        let (peer, source) = crossbeam_channel::unbounded::<Packet>();
+        let mut connection = TransportConnection::new(
+            TaskPool::new(),
+            TransportLink {
+                sink: Arc::new(sink),
+                source: Box::new(source),
+                remote_address: None,
+                kind: TransportKind::Custom("test"),
+            },
+            None,
+            Arc::new(PacketCompressor::new(None)),
+        );
+        connection.set_link_conditioner(Some(ConnectionConditioner {
+            handle: ConnectionHandle::new(0, 0),
+            conditioner: LinkConditioner::new(LinkConditions {
+                inbound: None,
+                outbound: Some(LinkProfile {
+                    latency_ms,
+                    ..Default::default()
+                }),
+            }),
+        }));
+        (connection, sent, peer)
+    }
+
+    #[test]
+    fn flush_link_releases_held_packets() {
+        let (mut connection, sent, _peer) = conditioned_connection(60_000);
+        connection.send(Packet::from(vec![1])).unwrap();
+        connection.send(Packet::from(vec![2])).unwrap();
+        assert!(sent.try_recv().is_err());
+
+        connection.flush_link();
+        assert_eq!(&sent.try_recv().unwrap()[..], &[1]);
+        assert_eq!(&sent.try_recv().unwrap()[..], &[2]);
+    }
+
+    #[test]
+    fn raw_send_after_channels_leaves_conditioner() {
+        let (mut connection, sent, _peer) = conditioned_connection(20);
+        connection.build_channels(
+            &|_builder| {},
+            TaskPoolRuntime::new(TaskPool::new()),
+            MuxPacketPool::new(BufferPacketPool::new(SimpleBufferPool(64))),
+        );
+
+        // heartbeats and control packets bypass the channels
+        connection.send(Packet::from(vec![1, 2, 3])).unwrap();
+        assert!(sent.try_recv().is_err());
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(connection.receive().is_none());
+        let packet = sent
+            .recv_timeout(Duration::from_secs(1))
+            .expect("held back packet was never sent");
+        assert_eq!(&packet[..], &[1, 2, 3]);
+    }
+}
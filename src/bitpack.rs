@@ -0,0 +1,177 @@
+use super::codec::CodecError;
+
+/// Number of bits needed to store values in `0..=range`
+fn bits_for_range(range: u64) -> u32 {
+    u64::BITS - range.leading_zeros()
+}
+
+/// Writes values packed at bit granularity, for hand-written `Codec`s.
+#[derive(Debug, Default, Clone)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the lowest `bits` bits of `value` (up to 32 at a time)
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        let mask = (1u64 << bits) - 1;
+        self.scratch |= (value as u64 & mask) << self.scratch_bits;
+        self.scratch_bits += bits;
+        while self.scratch_bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    /// Writes an integer known to be in `min..=max`, using only as many bits as the range needs.
+    /// Values outside of the range are clamped.
+    pub fn write_ranged(&mut self, value: i32, min: i32, max: i32) {
+        debug_assert!(min <= max);
+        let range = (max as i64 - min as i64) as u64;
+        let offset = (value.clamp(min, max) as i64 - min as i64) as u64;
+        self.write_bits(offset as u32, bits_for_range(range));
+    }
+
+    /// Writes a float in `min..=max` quantized to `bits` bits of precision.
+    /// Values outside of the range are clamped.
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, bits: u32) {
+        debug_assert!(min < max && bits > 0 && bits <= 32);
+        let steps = ((1u64 << bits) - 1) as f64;
+        let normalized = ((value.clamp(min, max) - min) / (max - min)) as f64;
+        self.write_bits((normalized * steps).round() as u32, bits);
+    }
+
+    /// Number of bits written so far
+    pub fn len_bits(&self) -> usize {
+        self.bytes.len() * 8 + self.scratch_bits as usize
+    }
+
+    /// Returns the packed bytes, padding the last byte with zeros
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Reads values written by `BitWriter`.
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, CodecError> {
+        debug_assert!(bits <= 32);
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err(CodecError("read past end of bit stream".to_string()));
+        }
+        let mut value = 0u64;
+        let mut read = 0;
+        while read < bits {
+            let byte = self.bytes[self.position / 8] as u64;
+            let bit_offset = (self.position % 8) as u32;
+            let take = (8 - bit_offset).min(bits - read);
+            let chunk = (byte >> bit_offset) & ((1 << take) - 1);
+            value |= chunk << read;
+            read += take;
+            self.position += take as usize;
+        }
+        Ok(value as u32)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, CodecError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_ranged(&mut self, min: i32, max: i32) -> Result<i32, CodecError> {
+        let range = (max as i64 - min as i64) as u64;
+        let offset = self.read_bits(bits_for_range(range))? as i64;
+        if offset as u64 > range {
+            return Err(CodecError("ranged value out of range".to_string()));
+        }
+        Ok((min as i64 + offset) as i32)
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, bits: u32) -> Result<f32, CodecError> {
+        let steps = ((1u64 << bits) - 1) as f64;
+        let quantized = self.read_bits(bits)? as f64;
+        Ok(min + ((quantized / steps) as f32) * (max - min))
+    }
+
+    /// Number of bits not read yet (including padding of the last byte)
+    pub fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = BitWriter::new();
+        writer.write_bool(true);
+        writer.write_bits(0x5, 3);
+        writer.write_bits(u32::MAX, 32);
+        writer.write_ranged(-3, -10, 10);
+        writer.write_ranged(i32::MAX, i32::MIN, i32::MAX);
+        writer.write_quantized(0.25, 0.0, 1.0, 10);
+        // 1 + 3 + 32 + 5 + 32 + 10
+        assert_eq!(writer.len_bits(), 83);
+        let bytes = writer.finish();
+        assert_eq!(bytes.len(), 11);
+
+        let mut reader = BitReader::new(&bytes);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_bits(3).unwrap(), 0x5);
+        assert_eq!(reader.read_bits(32).unwrap(), u32::MAX);
+        assert_eq!(reader.read_ranged(-10, 10).unwrap(), -3);
+        assert_eq!(reader.read_ranged(i32::MIN, i32::MAX).unwrap(), i32::MAX);
+        let quantized = reader.read_quantized(0.0, 1.0, 10).unwrap();
+        assert!((quantized - 0.25).abs() < 1.0 / 1023.0);
+        // padding of the last byte
+        assert_eq!(reader.remaining_bits(), 5);
+        assert!(reader.read_bits(6).is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let mut writer = BitWriter::new();
+        writer.write_ranged(50, 0, 10);
+        writer.write_quantized(-4.0, -1.0, 1.0, 8);
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_ranged(0, 10).unwrap(), 10);
+        assert_eq!(reader.read_quantized(-1.0, 1.0, 8).unwrap(), -1.0);
+    }
+
+    #[test]
+    fn ranged_values_beyond_max_are_rejected() {
+        // 4 bits for 0..=10, 15 doesn't fit the range
+        let mut reader = BitReader::new(&[0x0f]);
+        assert!(reader.read_ranged(0, 10).is_err());
+    }
+}
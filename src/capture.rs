@@ -0,0 +1,509 @@
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::tasks::Task;
+use bevy::{prelude::error, tasks::TaskPool};
+use instant::{Duration, Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use futures_lite::StreamExt;
+use turbulence::{
+    buffer::BufferPacketPool,
+    message_channels::MessageChannels,
+    packet_multiplexer::{IncomingMultiplexedPackets, MuxPacketPool, PacketMultiplexer},
+};
+
+use super::{
+    channels::{SimpleBufferPool, TaskPoolRuntime},
+    codec::EncodedChannels,
+    transport::{
+        Connection, ConnectionChannelsBuilder, MultiplexedPacket, PacketStats, TransportKind,
+    },
+    ConnectionHandle, NetworkError, NetworkResource, Packet, MAX_DATAGRAM,
+};
+
+/// Magic of the current format, its last byte is the format version
+const CAPTURE_MAGIC: &[u8; 8] = b"BNTCAP\0\x02";
+/// `channel` value of records that did not go through turbulence channels
+const RAW_CHANNEL: u16 = 0xffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Inbound,
+    Outbound,
+}
+
+/// Single datagram stored in a capture
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    /// time since the capture started
+    pub timestamp: Duration,
+    pub handle: ConnectionHandle,
+    pub direction: CaptureDirection,
+    /// turbulence multiplexer channel, None for raw packets
+    pub channel: Option<u8>,
+    /// packet payload (after decompression / before compression)
+    pub payload: Packet,
+}
+
+struct Recorder {
+    writer: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+/// Shared packet recorder. Clone it freely - all clones write to the same file.
+///
+/// File format: 8 bytes of magic (`BNTCAP\0` and version 2), followed by records of
+/// `[timestamp_us: u64][handle index: u32][handle generation: u32][direction: u8][channel: u16][len: u32][payload]`,
+/// all little-endian. Channel `0xffff` marks raw (non-channel) packets.
+#[derive(Clone)]
+pub struct PacketCapture(Arc<Mutex<Option<Recorder>>>);
+
+impl PacketCapture {
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        writer.write_all(CAPTURE_MAGIC)?;
+        Ok(PacketCapture(Arc::new(Mutex::new(Some(Recorder {
+            writer: Box::new(writer),
+            started: Instant::now(),
+        })))))
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn record(
+        &self,
+        handle: ConnectionHandle,
+        direction: CaptureDirection,
+        channel: Option<u8>,
+        payload: &[u8],
+    ) {
+        let mut recorder = self.0.lock().expect("capture lock poisoned");
+        let result = match recorder.as_mut() {
+            Some(recorder) => {
+                let timestamp = recorder.started.elapsed();
+                write_record(
+                    &mut recorder.writer,
+                    timestamp,
+                    handle,
+                    direction,
+                    channel,
+                    payload,
+                )
+            }
+            None => return,
+        };
+        if let Err(err) = result {
+            error!("Packet capture write failed, stopping capture: {}", err);
+            *recorder = None;
+        }
+    }
+
+    /// Flushes and closes the capture. Later records are discarded.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.0.lock().expect("capture lock poisoned").take() {
+            Some(mut recorder) => recorder.writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_record<W: Write + ?Sized>(
+    writer: &mut W,
+    timestamp: Duration,
+    handle: ConnectionHandle,
+    direction: CaptureDirection,
+    channel: Option<u8>,
+    payload: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&(timestamp.as_micros() as u64).to_le_bytes())?;
+    writer.write_all(&handle.index().to_le_bytes())?;
+    writer.write_all(&handle.generation().to_le_bytes())?;
+    writer.write_all(&[match direction {
+        CaptureDirection::Inbound => 0,
+        CaptureDirection::Outbound => 1,
+    }])?;
+    writer.write_all(&channel.map_or(RAW_CHANNEL, u16::from).to_le_bytes())?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Capture bound to a connection handle, handed over to `Connection::set_capture`
+#[derive(Clone)]
+pub struct ConnectionCapture {
+    pub handle: ConnectionHandle,
+    pub capture: PacketCapture,
+}
+
+impl ConnectionCapture {
+    pub fn record(&self, direction: CaptureDirection, channel: Option<u8>, payload: &[u8]) {
+        self.capture
+            .record(self.handle, direction, channel, payload);
+    }
+}
+
+/// Capture of a connection, shared with its channels task
+#[derive(Clone, Default)]
+pub struct CaptureSlot(Arc<Mutex<Option<ConnectionCapture>>>);
+
+impl CaptureSlot {
+    pub fn set(&self, capture: Option<ConnectionCapture>) {
+        *self.0.lock().expect("capture lock poisoned") = capture;
+    }
+
+    pub fn record(&self, direction: CaptureDirection, channel: Option<u8>, payload: &[u8]) {
+        if let Some(capture) = self.0.lock().expect("capture lock poisoned").as_ref() {
+            capture.record(direction, channel, payload);
+        }
+    }
+}
+
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Reads all records of a capture written by `PacketCapture`.
+pub fn read_capture<R: Read>(mut reader: R) -> io::Result<Vec<CaptureRecord>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic[..7] != CAPTURE_MAGIC[..7] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a packet capture",
+        ));
+    }
+    if magic[7] != CAPTURE_MAGIC[7] {
+        // version 1 stored bare `u32` handles
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported packet capture version {}, expected {}",
+                magic[7], CAPTURE_MAGIC[7]
+            ),
+        ));
+    }
+
+    let mut records = Vec::new();
+    let mut header = [0; 23];
+    while read_exact_or_eof(&mut reader, &mut header)? {
+        let mut u64_bytes = [0; 8];
+        u64_bytes.copy_from_slice(&header[0..8]);
+        let mut u32_bytes = [0; 4];
+        u32_bytes.copy_from_slice(&header[8..12]);
+        let index = u32::from_le_bytes(u32_bytes);
+        u32_bytes.copy_from_slice(&header[12..16]);
+        let handle = ConnectionHandle::new(index, u32::from_le_bytes(u32_bytes));
+        let direction = match header[16] {
+            0 => CaptureDirection::Inbound,
+            1 => CaptureDirection::Outbound,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid capture record direction",
+                ))
+            }
+        };
+        let channel = match u16::from_le_bytes([header[17], header[18]]) {
+            RAW_CHANNEL => None,
+            channel => Some(channel as u8),
+        };
+        u32_bytes.copy_from_slice(&header[19..23]);
+        let len = u32::from_le_bytes(u32_bytes) as usize;
+        // no datagram is larger, don't trust a corrupt length with the allocation
+        if len > MAX_DATAGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "capture record longer than a datagram",
+            ));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        records.push(CaptureRecord {
+            timestamp: Duration::from_micros(u64::from_le_bytes(u64_bytes)),
+            handle,
+            direction,
+            channel,
+            payload: payload.into(),
+        });
+    }
+    Ok(records)
+}
+
+/// Recorded traffic to be fed back into a `NetworkResource` with `NetworkResource::replay`.
+pub struct PacketReplay {
+    records: Vec<CaptureRecord>,
+}
+
+impl PacketReplay {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        PacketReplay { records }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(read_capture(BufReader::new(File::open(path)?))?))
+    }
+
+    pub fn records(&self) -> &[CaptureRecord] {
+        &self.records
+    }
+}
+
+/// Connection playing back inbound packets of a recorded connection, at recorded pace.
+/// Everything sent to it is discarded.
+pub struct ReplayConnection {
+    task_pool: TaskPool,
+    started: Instant,
+    speed: f64,
+    inbound: VecDeque<CaptureRecord>,
+    stats: PacketStats,
+
+    channels: Option<MessageChannels>,
+    encoded_channels: Option<EncodedChannels>,
+    channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    channels_task: Option<Task<()>>,
+}
+
+impl ReplayConnection {
+    pub fn new(task_pool: TaskPool, records: Vec<CaptureRecord>, speed: f64) -> Self {
+        ReplayConnection {
+            task_pool,
+            started: Instant::now(),
+            speed,
+            inbound: records
+                .into_iter()
+                .filter(|record| record.direction == CaptureDirection::Inbound)
+                .collect(),
+            stats: PacketStats::default(),
+            channels: None,
+            encoded_channels: None,
+            channels_rx: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            channels_task: None,
+        }
+    }
+}
+
+impl Connection for ReplayConnection {
+    fn remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn transport(&self) -> TransportKind {
+        TransportKind::Replay
+    }
+
+    fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.stats.add_tx(payload.len(), payload.len());
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+        let elapsed = self.started.elapsed().mul_f64(self.speed);
+        if self.inbound.front()?.timestamp > elapsed {
+            return None;
+        }
+        let record = self.inbound.pop_front()?;
+        self.stats
+            .add_rx(record.payload.len(), record.payload.len());
+        Some(Ok(record.payload))
+    }
+
+    fn build_channels(
+        &mut self,
+        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
+        runtime: TaskPoolRuntime,
+        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
+    ) {
+        let mut builder = ConnectionChannelsBuilder::new(runtime, pool);
+        builder_fn(&mut builder);
+
+        let mut multiplexer = PacketMultiplexer::new();
+        let (channels, encoded_channels) = builder.build(&mut multiplexer);
+        self.channels = Some(channels);
+        self.encoded_channels = Some(encoded_channels);
+        let (channels_rx, mut channels_tx) = multiplexer.start();
+        self.channels_rx = Some(channels_rx);
+
+        // drain and discard outgoing channel packets
+        let closure = async move { while channels_tx.next().await.is_some() {} };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.channels_task = Some(self.task_pool.spawn(closure));
+        }
+        #[cfg(target_arch = "wasm32")]
+        self.task_pool.spawn(closure);
+    }
+
+    fn channels(&mut self) -> Option<&mut MessageChannels> {
+        self.channels.as_mut()
+    }
+
+    fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+        self.channels_rx.as_mut()
+    }
+
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        self.encoded_channels.as_mut()
+    }
+
+    fn stats(&self) -> PacketStats {
+        self.stats.clone()
+    }
+
+    fn last_packet_timings(&self) -> (u128, u128) {
+        let (rx_dur, tx_dur) = self.stats.idle_durations();
+        (rx_dur.as_millis(), tx_dur.as_millis())
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// Records traffic of all current and future connections into `capture`.
+    pub fn start_capture(&mut self, capture: PacketCapture) {
+        for (handle, connection) in self.connections.iter_mut() {
+            connection.set_capture(Some(ConnectionCapture {
+                handle: *handle,
+                capture: capture.clone(),
+            }));
+        }
+        self.capture = Some(capture);
+    }
+
+    /// Stops recording and flushes the capture.
+    pub fn stop_capture(&mut self) -> io::Result<()> {
+        for (_handle, connection) in self.connections.iter_mut() {
+            connection.set_capture(None);
+        }
+        match self.capture.take() {
+            Some(capture) => capture.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds recorded inbound traffic back in - every recorded connection shows up as a new
+    /// connection (with a new handle) replaying its packets at `speed` times the recorded pace.
+    pub fn replay(&mut self, replay: PacketReplay, speed: f64) {
+        let mut connections: HashMap<ConnectionHandle, Vec<CaptureRecord>> = HashMap::new();
+        for record in replay.records {
+            connections.entry(record.handle).or_default().push(record);
+        }
+        for (_handle, records) in connections {
+            let connection = Box::new(ReplayConnection::new(
+                self.task_pool.clone(),
+                records,
+                speed,
+            ));
+            self.add_pending_connection(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(
+        records: &[(u64, ConnectionHandle, CaptureDirection, Option<u8>, &[u8])],
+    ) -> Vec<u8> {
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        for (timestamp, handle, direction, channel, payload) in records {
+            write_record(
+                &mut bytes,
+                Duration::from_micros(*timestamp),
+                *handle,
+                *direction,
+                *channel,
+                payload,
+            )
+            .unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = capture(&[
+            (
+                10,
+                ConnectionHandle::new(1, 2),
+                CaptureDirection::Inbound,
+                Some(3),
+                &[4, 5],
+            ),
+            (
+                20,
+                ConnectionHandle::new(6, 0),
+                CaptureDirection::Outbound,
+                None,
+                &[],
+            ),
+        ]);
+
+        let records = read_capture(&bytes[..]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, Duration::from_micros(10));
+        assert_eq!(records[0].handle, ConnectionHandle::new(1, 2));
+        assert_eq!(records[0].direction, CaptureDirection::Inbound);
+        assert_eq!(records[0].channel, Some(3));
+        assert_eq!(&records[0].payload[..], &[4, 5]);
+        assert_eq!(records[1].handle, ConnectionHandle::new(6, 0));
+        assert_eq!(records[1].direction, CaptureDirection::Outbound);
+        assert_eq!(records[1].channel, None);
+        assert!(records[1].payload.is_empty());
+    }
+
+    #[test]
+    fn truncated_record() {
+        let bytes = capture(&[(
+            10,
+            ConnectionHandle::new(1, 2),
+            CaptureDirection::Inbound,
+            None,
+            &[4, 5],
+        )]);
+        assert!(read_capture(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_capture(&b"not a capture"[..]).is_err());
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = capture(&[]);
+        assert!(read_capture(&bytes[..]).unwrap().is_empty());
+        bytes[7] = 1;
+        let err = read_capture(&bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version 1"));
+    }
+
+    #[test]
+    fn oversized_record() {
+        let mut bytes = capture(&[(
+            10,
+            ConnectionHandle::new(1, 2),
+            CaptureDirection::Inbound,
+            None,
+            &[],
+        )]);
+        // length field of the only record
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_capture(&bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
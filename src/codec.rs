@@ -0,0 +1,559 @@
+use bevy::prelude::error;
+use futures::{channel::mpsc, select, FutureExt, SinkExt, StreamExt};
+use serde::{
+    de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor},
+    ser::{self, Serializer},
+    Deserialize, Serialize,
+};
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    error::Error,
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+};
+use turbulence::{
+    channel_builder::ChannelBuilder,
+    message_channels::{MessageChannelMode, MessageChannelSettings},
+    packet_multiplexer::{MuxPacketPool, PacketMultiplexer},
+    reliable_channel::ReliableChannel,
+    runtime::Runtime,
+    unreliable_channel::UnreliableChannel,
+};
+
+use super::{
+    channels::TaskPoolRuntime,
+    transport::{ChannelsPacketPool, MultiplexedPacket},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "codec error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Serialization format used for a message type registered as `Encoded<M, C>`.
+///
+/// Implement it for your own marker type to hand-pack messages, i.e. with `BitWriter`/`BitReader`.
+pub trait Codec<M>: 'static {
+    fn encode(message: &M) -> Result<Vec<u8>, CodecError>;
+    fn decode(bytes: &[u8]) -> Result<M, CodecError>;
+}
+
+/// bincode - the format turbulence uses for plain message types
+pub struct Bincode;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for Bincode {
+    fn encode(message: &M) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<M, CodecError> {
+        bincode::deserialize(bytes).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+#[cfg(feature = "codec-msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "codec-msgpack")]
+impl<M: Serialize + DeserializeOwned> Codec<M> for MessagePack {
+    fn encode(message: &M) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<M, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+#[cfg(feature = "codec-postcard")]
+pub struct Postcard;
+
+#[cfg(feature = "codec-postcard")]
+impl<M: Serialize + DeserializeOwned> Codec<M> for Postcard {
+    fn encode(message: &M) -> Result<Vec<u8>, CodecError> {
+        postcard::to_allocvec(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<M, CodecError> {
+        postcard::from_bytes(bytes).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+/// Message `M` serialized in codec `C` format inside a bincode message, eg. as a field.
+///
+/// To carry messages in codec format alone, without bincode framing around them,
+/// register them with `ConnectionChannelsBuilder::register_encoded` instead.
+pub struct Encoded<M, C> {
+    message: M,
+    _codec: PhantomData<fn() -> C>,
+}
+
+impl<M, C> Encoded<M, C> {
+    pub fn new(message: M) -> Self {
+        Encoded {
+            message,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> M {
+        self.message
+    }
+}
+
+impl<M, C> Deref for Encoded<M, C> {
+    type Target = M;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+impl<M: Clone, C> Clone for Encoded<M, C> {
+    fn clone(&self) -> Self {
+        Encoded::new(self.message.clone())
+    }
+}
+
+impl<M: fmt::Debug, C> fmt::Debug for Encoded<M, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Encoded").field(&self.message).finish()
+    }
+}
+
+impl<M, C: Codec<M>> Serialize for Encoded<M, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = C::encode(&self.message).map_err(ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+impl<'de, M, C: Codec<M>> Deserialize<'de> for Encoded<M, C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EncodedVisitor<M, C>(PhantomData<fn() -> (M, C)>);
+
+        impl<'de, M, C: Codec<M>> Visitor<'de> for EncodedVisitor<M, C> {
+            type Value = Encoded<M, C>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("encoded message bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                C::decode(bytes).map(Encoded::new).map_err(E::custom)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element::<u8>()? {
+                    bytes.push(byte);
+                }
+                self.visit_bytes(&bytes)
+            }
+        }
+
+        deserializer.deserialize_bytes(EncodedVisitor(PhantomData))
+    }
+}
+
+type ChannelResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Channel registered with `ConnectionChannelsBuilder::register_encoded`
+pub(crate) struct EncodedChannelSettings {
+    type_name: &'static str,
+    settings: MessageChannelSettings,
+}
+
+impl EncodedChannelSettings {
+    pub(crate) fn new<M, C>(settings: MessageChannelSettings) -> Self {
+        EncodedChannelSettings {
+            type_name: type_name::<Encoded<M, C>>(),
+            settings,
+        }
+    }
+}
+
+struct EncodedChannel {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    incoming: mpsc::Receiver<Vec<u8>>,
+}
+
+/// Channels carrying message types registered with `ConnectionChannelsBuilder::register_encoded`,
+/// as the codec wrote them. Unreliable channels send them as turbulence unreliable messages,
+/// reliable ones as `u16` length prefixed messages of the reliable stream.
+#[derive(Default)]
+pub struct EncodedChannels {
+    channels: HashMap<TypeId, EncodedChannel>,
+}
+
+impl EncodedChannels {
+    pub(crate) fn build(
+        registered: HashMap<TypeId, EncodedChannelSettings>,
+        runtime: TaskPoolRuntime,
+        pool: ChannelsPacketPool,
+        multiplexer: &mut PacketMultiplexer<MultiplexedPacket>,
+    ) -> Self {
+        let mut builder = ChannelBuilder::new(runtime.clone(), pool);
+        let mut channels = HashMap::new();
+        for (type_id, registered) in registered {
+            let settings = registered.settings;
+            let (outgoing, outgoing_rx) = mpsc::channel(settings.message_buffer_size);
+            let (incoming_tx, incoming) = mpsc::channel(settings.message_buffer_size);
+            let task = match settings.channel_mode {
+                MessageChannelMode::Unreliable => {
+                    let (channel, _statistics) = builder
+                        .open_unreliable_channel(
+                            multiplexer,
+                            settings.channel,
+                            settings.packet_buffer_size,
+                        )
+                        .expect("duplicate packet channel");
+                    run_unreliable(channel, outgoing_rx, incoming_tx).boxed()
+                }
+                MessageChannelMode::Reliable {
+                    reliability_settings,
+                    max_message_len,
+                }
+                | MessageChannelMode::Compressed {
+                    reliability_settings,
+                    max_chunk_len: max_message_len,
+                } => {
+                    let (channel, _statistics) = builder
+                        .open_reliable_channel(
+                            multiplexer,
+                            settings.channel,
+                            settings.packet_buffer_size,
+                            reliability_settings,
+                        )
+                        .expect("duplicate packet channel");
+                    let max_message_len = max_message_len.min(u16::MAX as usize);
+                    run_reliable(channel, max_message_len, outgoing_rx, incoming_tx).boxed()
+                }
+            };
+            let type_name = registered.type_name;
+            runtime.spawn(async move {
+                if let Err(err) = task.await {
+                    error!("Encoded channel of {} failed: {}", type_name, err);
+                }
+            });
+            channels.insert(type_id, EncodedChannel { outgoing, incoming });
+        }
+        EncodedChannels { channels }
+    }
+
+    fn channel<M: 'static, C: 'static>(&mut self) -> Result<&mut EncodedChannel, CodecError> {
+        self.channels
+            .get_mut(&TypeId::of::<Encoded<M, C>>())
+            .ok_or_else(|| {
+                CodecError(format!(
+                    "{} is not registered",
+                    type_name::<Encoded<M, C>>()
+                ))
+            })
+    }
+
+    /// Queues a message, it is sent as soon as the channel task gets to it.
+    pub fn send<M: 'static, C: Codec<M>>(&mut self, message: &M) -> Result<(), CodecError> {
+        let bytes = C::encode(message)?;
+        self.channel::<M, C>()?
+            .outgoing
+            .try_send(bytes)
+            .map_err(|err| CodecError(err.to_string()))
+    }
+
+    /// Next received message, if any
+    pub fn recv<M: 'static, C: Codec<M>>(&mut self) -> Result<Option<M>, CodecError> {
+        match self.channel::<M, C>()?.incoming.next().now_or_never() {
+            Some(Some(bytes)) => C::decode(&bytes).map(Some),
+            Some(None) => Err(CodecError("channel disconnected".to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+enum Next {
+    Incoming(usize),
+    Outgoing(Vec<u8>),
+}
+
+async fn run_unreliable(
+    mut channel: UnreliableChannel<MuxPacketPool<ChannelsPacketPool>>,
+    mut outgoing: mpsc::Receiver<Vec<u8>>,
+    mut incoming: mpsc::Sender<Vec<u8>>,
+) -> ChannelResult {
+    let mut buffer = vec![0; u16::MAX as usize];
+    loop {
+        let next = select! {
+            len = channel.recv(&mut buffer).fuse() => Next::Incoming(len?),
+            message = outgoing.next() => Next::Outgoing(message.ok_or("channel disconnected")?),
+        };
+        match next {
+            Next::Incoming(len) => incoming.send(buffer[..len].to_vec()).await?,
+            Next::Outgoing(message) => {
+                channel.send(&message).await?;
+                // coalesce everything queued into as few packets as possible
+                while let Some(Some(message)) = outgoing.next().now_or_never() {
+                    channel.send(&message).await?;
+                }
+                channel.flush().await?;
+            }
+        }
+    }
+}
+
+/// Stream of `[len: u16][message]`, like turbulence reliable channels
+async fn run_reliable(
+    mut channel: ReliableChannel,
+    max_message_len: usize,
+    mut outgoing: mpsc::Receiver<Vec<u8>>,
+    mut incoming: mpsc::Sender<Vec<u8>>,
+) -> ChannelResult {
+    let mut received = Vec::new();
+    let mut buffer = vec![0; 1024];
+    loop {
+        let next = select! {
+            len = channel.read(&mut buffer).fuse() => Next::Incoming(len?),
+            message = outgoing.next() => Next::Outgoing(message.ok_or("channel disconnected")?),
+        };
+        match next {
+            Next::Incoming(len) => {
+                received.extend_from_slice(&buffer[..len]);
+                while received.len() >= 2 {
+                    let len = u16::from_le_bytes([received[0], received[1]]) as usize;
+                    if len > max_message_len {
+                        return Err(format!(
+                            "message of {} bytes exceeds {}",
+                            len, max_message_len
+                        )
+                        .into());
+                    }
+                    if received.len() < 2 + len {
+                        break;
+                    }
+                    let message = received[2..2 + len].to_vec();
+                    received.drain(..2 + len);
+                    incoming.send(message).await?;
+                }
+            }
+            Next::Outgoing(mut message) => {
+                loop {
+                    if message.len() > max_message_len {
+                        error!(
+                            "Dropping encoded message of {} bytes, exceeds {}",
+                            message.len(),
+                            max_message_len
+                        );
+                    } else {
+                        write_all(&mut channel, &(message.len() as u16).to_le_bytes()).await?;
+                        write_all(&mut channel, &message).await?;
+                    }
+                    match outgoing.next().now_or_never() {
+                        Some(Some(next)) => message = next,
+                        _ => break,
+                    }
+                }
+                channel.flush().await?;
+            }
+        }
+    }
+}
+
+async fn write_all(channel: &mut ReliableChannel, mut data: &[u8]) -> ChannelResult {
+    while !data.is_empty() {
+        let written = channel.write(data).await?;
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::TaskPool;
+    use futures_timer::Delay;
+    use instant::{Duration, Instant};
+    use turbulence::{
+        buffer::BufferPacketPool,
+        message_channels::MessageChannels,
+        packet::{Packet as PoolPacket, PacketPool, MAX_PACKET_LEN},
+        packet_multiplexer::{IncomingMultiplexedPackets, OutgoingMultiplexedPackets},
+        reliable_channel::Settings as ReliableChannelSettings,
+    };
+
+    use crate::{channels::SimpleBufferPool, transport::ConnectionChannelsBuilder};
+
+    /// Bytes as they are
+    struct Raw;
+
+    impl Codec<Vec<u8>> for Raw {
+        fn encode(message: &Vec<u8>) -> Result<Vec<u8>, CodecError> {
+            Ok(message.clone())
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+            Ok(bytes.to_vec())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Position {
+        x: i16,
+        y: i16,
+    }
+
+    const UNRELIABLE: MessageChannelSettings = MessageChannelSettings {
+        channel: 0,
+        channel_mode: MessageChannelMode::Unreliable,
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
+    const RELIABLE: MessageChannelSettings = MessageChannelSettings {
+        channel: 1,
+        channel_mode: MessageChannelMode::Reliable {
+            reliability_settings: ReliableChannelSettings {
+                bandwidth: 4096,
+                recv_window_size: 1024,
+                send_window_size: 1024,
+                burst_bandwidth: 1024,
+                init_send: 512,
+                wakeup_time: Duration::from_millis(100),
+                initial_rtt: Duration::from_millis(200),
+                max_rtt: Duration::from_secs(2),
+                rtt_update_factor: 0.1,
+                rtt_resend_factor: 1.5,
+            },
+            max_message_len: 1024,
+        },
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
+    struct Peer {
+        pool: ChannelsPacketPool,
+        _channels: MessageChannels,
+        encoded: EncodedChannels,
+        incoming: IncomingMultiplexedPackets<MultiplexedPacket>,
+        outgoing: OutgoingMultiplexedPackets<MultiplexedPacket>,
+    }
+
+    impl Peer {
+        fn new(task_pool: &TaskPool) -> Self {
+            let pool = MuxPacketPool::new(BufferPacketPool::new(SimpleBufferPool(MAX_PACKET_LEN)));
+            let mut builder = ConnectionChannelsBuilder::new(
+                TaskPoolRuntime::new(task_pool.clone()),
+                pool.clone(),
+            );
+            builder
+                .register_encoded::<Vec<u8>, Raw>(UNRELIABLE)
+                .unwrap();
+            builder
+                .register_encoded::<Position, Bincode>(RELIABLE)
+                .unwrap();
+            let mut multiplexer = PacketMultiplexer::new();
+            let (channels, encoded) = builder.build(&mut multiplexer);
+            let (incoming, outgoing) = multiplexer.start();
+            Peer {
+                pool,
+                _channels: channels,
+                encoded,
+                incoming,
+                outgoing,
+            }
+        }
+
+        fn sent(&mut self) -> Vec<u8> {
+            futures_lite::future::block_on(futures_lite::future::or(
+                async { self.outgoing.next().await.map(|packet| packet.to_vec()) },
+                async {
+                    Delay::new(Duration::from_secs(5)).await;
+                    None
+                },
+            ))
+            .expect("nothing sent")
+        }
+
+        fn receive(&mut self, packet: &[u8]) {
+            let mut pool_packet = self.pool.acquire();
+            pool_packet.resize(packet.len(), 0);
+            pool_packet[..].copy_from_slice(packet);
+            self.incoming.try_send(pool_packet).unwrap();
+        }
+
+        fn recv<M: 'static, C: Codec<M>>(&mut self) -> M {
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_secs(5) {
+                if let Some(message) = self.encoded.recv::<M, C>().unwrap() {
+                    return message;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("nothing received");
+        }
+    }
+
+    #[test]
+    fn unreliable_without_bincode_framing() {
+        let task_pool = TaskPool::new();
+        let mut sender = Peer::new(&task_pool);
+        let mut receiver = Peer::new(&task_pool);
+
+        sender.encoded.send::<_, Raw>(&vec![1, 2, 3]).unwrap();
+        let packet = sender.sent();
+        // channel, u16 message length, codec bytes
+        assert_eq!(packet, [0, 3, 0, 1, 2, 3]);
+
+        receiver.receive(&packet);
+        assert_eq!(receiver.recv::<Vec<u8>, Raw>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reliable_without_bincode_framing() {
+        let task_pool = TaskPool::new();
+        let mut sender = Peer::new(&task_pool);
+        let mut receiver = Peer::new(&task_pool);
+
+        let position = Position { x: 1, y: -1 };
+        sender.encoded.send::<_, Bincode>(&position).unwrap();
+        let packet = sender.sent();
+        let encoded = bincode::serialize(&position).unwrap();
+        // channel, reliable header, u16 message length, codec bytes
+        let mut expected = vec![1, 2 + encoded.len() as u8, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&(encoded.len() as u16).to_le_bytes());
+        expected.extend_from_slice(&encoded);
+        assert_eq!(packet, expected);
+
+        receiver.receive(&packet);
+        assert_eq!(receiver.recv::<Position, Bincode>(), position);
+    }
+
+    #[test]
+    fn unregistered_type() {
+        let mut channels = EncodedChannels::default();
+        assert!(channels.send::<_, Raw>(&vec![1]).is_err());
+        assert!(channels.recv::<Vec<u8>, Raw>().is_err());
+    }
+
+    #[test]
+    fn encoded_field_round_trip() {
+        #[derive(Serialize, Deserialize)]
+        struct Update {
+            position: Encoded<Position, Bincode>,
+        }
+
+        let update = Update {
+            position: Encoded::new(Position { x: 3, y: 4 }),
+        };
+        let bytes = bincode::serialize(&update).unwrap();
+        let decoded: Update = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(*decoded.position, Position { x: 3, y: 4 });
+    }
+}
@@ -0,0 +1,413 @@
+#[cfg(feature = "compression-zstd")]
+use std::sync::{Arc, Mutex};
+use std::{
+    io,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+};
+
+use super::{control::ControlPacket, NetworkError};
+
+/// Per-packet compression applied below turbulence channels and raw packets.
+///
+/// Peers advertise which methods they are able to decompress in a handshake of control
+/// packets when the connection is set up, and packets are only compressed once the peer
+/// confirmed it got our advertisement. Peers with different methods (or without
+/// compression at all) still talk to each other, they just get uncompressed packets.
+#[derive(Clone, Debug)]
+pub enum Compression {
+    /// send packets uncompressed, but accept compressed ones
+    Uncompressed,
+    #[cfg(feature = "compression-lz4")]
+    Lz4,
+    /// Zstandard, optionally with a dictionary trained on your traffic (`zstd --train`).
+    /// Packets are only zstd compressed if the peer uses the same dictionary.
+    #[cfg(feature = "compression-zstd")]
+    Zstd {
+        level: i32,
+        dictionary: Option<Arc<Vec<u8>>>,
+    },
+}
+
+/// Framed packets start with `[FRAME_MARKER, FRAME_METHOD | method]`,
+/// which never starts a control packet (`0xff 0xff`)
+const FRAME_MARKER: u8 = 0xff;
+const FRAME_METHOD: u8 = 0xf0;
+/// uncompressed payload which would look like a framed packet otherwise
+const METHOD_NONE: u8 = 0;
+#[cfg(feature = "compression-lz4")]
+const METHOD_LZ4: u8 = 1;
+#[cfg(feature = "compression-zstd")]
+const METHOD_ZSTD: u8 = 2;
+const METHOD_MAX: u8 = 2;
+
+/// Largest payload we are willing to decompress - maximum UDP datagram size
+#[cfg(any(feature = "compression-lz4", feature = "compression-zstd"))]
+const MAX_DECOMPRESSED_LEN: usize = 65536;
+
+/// Magic number of dictionaries trained by `zstd --train`, followed by the dictionary id
+#[cfg(feature = "compression-zstd")]
+const ZSTD_DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+#[cfg(any(feature = "compression-lz4", feature = "compression-zstd"))]
+fn method_bit(method: u8) -> u8 {
+    1 << (method - 1)
+}
+
+fn invalid_data<E: ToString>(error: E) -> NetworkError {
+    NetworkError::IoError(Box::new(io::Error::new(
+        io::ErrorKind::InvalidData,
+        error.to_string(),
+    )))
+}
+
+/// Identifies a zstd dictionary in the handshake, 0 without one
+#[cfg(feature = "compression-zstd")]
+fn dictionary_id(dictionary: &[u8]) -> u32 {
+    if dictionary.is_empty() {
+        return 0;
+    }
+    match dictionary.get(..8) {
+        Some(header) if header[..4] == ZSTD_DICTIONARY_MAGIC => {
+            u32::from_le_bytes([header[4], header[5], header[6], header[7]])
+        }
+        // raw content dictionary, FNV-1a with the low bit set so it's never 0
+        _ => {
+            let hash = dictionary.iter().fold(0x811c_9dc5, |hash: u32, byte| {
+                (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+            });
+            hash | 1
+        }
+    }
+}
+
+#[cfg(feature = "compression-zstd")]
+struct ZstdContext {
+    dictionary_id: u32,
+    compressor: Mutex<zstd::bulk::Compressor<'static>>,
+    decompressor: Mutex<zstd::bulk::Decompressor<'static>>,
+}
+
+/// Compression state of a single connection
+pub struct PacketCompressor {
+    config: Option<Compression>,
+    /// peer advertised its methods, so it frames packets it sends to us
+    peer_advertised: AtomicBool,
+    /// peer got our advertisement, so we can frame packets we send to it
+    peer_acknowledged: AtomicBool,
+    /// methods the peer told us it accepts
+    peer_accepts: AtomicU8,
+    peer_dictionary: AtomicU32,
+    #[cfg(feature = "compression-zstd")]
+    zstd: Option<ZstdContext>,
+}
+
+impl PacketCompressor {
+    pub fn new(config: Option<Compression>) -> Self {
+        #[cfg(feature = "compression-zstd")]
+        let zstd = match config {
+            Some(Compression::Zstd {
+                level,
+                ref dictionary,
+            }) => {
+                let dictionary = dictionary.as_ref().map_or(&[][..], |dict| &dict[..]);
+                Some(ZstdContext {
+                    dictionary_id: dictionary_id(dictionary),
+                    compressor: Mutex::new(
+                        zstd::bulk::Compressor::with_dictionary(level, dictionary)
+                            .expect("can't create zstd compressor"),
+                    ),
+                    decompressor: Mutex::new(
+                        zstd::bulk::Decompressor::with_dictionary(dictionary)
+                            .expect("can't create zstd decompressor"),
+                    ),
+                })
+            }
+            _ => None,
+        };
+
+        PacketCompressor {
+            config,
+            peer_advertised: AtomicBool::new(false),
+            peer_acknowledged: AtomicBool::new(false),
+            peer_accepts: AtomicU8::new(0),
+            peer_dictionary: AtomicU32::new(0),
+            #[cfg(feature = "compression-zstd")]
+            zstd,
+        }
+    }
+
+    #[cfg(any(feature = "compression-lz4", feature = "compression-zstd"))]
+    fn peer_accepts(&self, method: u8) -> bool {
+        self.peer_accepts.load(Ordering::Relaxed) & method_bit(method) != 0
+    }
+
+    /// methods we are able to decompress
+    fn accepts(&self) -> u8 {
+        #[allow(unused_mut)]
+        let mut accepts = 0;
+        #[cfg(feature = "compression-lz4")]
+        {
+            accepts |= method_bit(METHOD_LZ4);
+        }
+        #[cfg(feature = "compression-zstd")]
+        if self.zstd.is_some() {
+            accepts |= method_bit(METHOD_ZSTD);
+        }
+        accepts
+    }
+
+    fn dictionary(&self) -> u32 {
+        #[cfg(feature = "compression-zstd")]
+        if let Some(ref zstd) = self.zstd {
+            return zstd.dictionary_id;
+        }
+        0
+    }
+
+    /// Handshake packet telling the peer what we accept, None if compression is disabled
+    pub(crate) fn advertisement(&self) -> Option<ControlPacket> {
+        self.config.as_ref()?;
+        Some(ControlPacket::Compression {
+            accepts: self.accepts(),
+            dictionary: self.dictionary(),
+            acknowledged: self.peer_advertised.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Handles the peer's advertisement, returns the answer to send back if any
+    pub(crate) fn negotiate(
+        &self,
+        accepts: u8,
+        dictionary: u32,
+        acknowledged: bool,
+    ) -> Option<ControlPacket> {
+        self.config.as_ref()?;
+        self.peer_accepts.store(accepts, Ordering::Relaxed);
+        self.peer_dictionary.store(dictionary, Ordering::Relaxed);
+        if acknowledged {
+            self.peer_acknowledged.store(true, Ordering::Relaxed);
+        }
+        let advertised_before = self.peer_advertised.swap(true, Ordering::Relaxed);
+        // answer until both sides know the other one got its advertisement
+        if !acknowledged || !advertised_before {
+            self.advertisement()
+        } else {
+            None
+        }
+    }
+
+    /// Frames (and possibly compresses) an outgoing payload.
+    pub fn compress(&self, payload: &[u8]) -> Vec<u8> {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return payload.to_vec(),
+        };
+        // until the peer knows we frame packets, they go out as they are
+        if !self.peer_acknowledged.load(Ordering::Relaxed) {
+            return payload.to_vec();
+        }
+
+        let compressed: Option<(u8, Vec<u8>)> = match config {
+            Compression::Uncompressed => None,
+            #[cfg(feature = "compression-lz4")]
+            Compression::Lz4 if self.peer_accepts(METHOD_LZ4) => {
+                Some((METHOD_LZ4, lz4_flex::compress_prepend_size(payload)))
+            }
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd { .. }
+                if self.peer_accepts(METHOD_ZSTD)
+                    && self.peer_dictionary.load(Ordering::Relaxed) == self.dictionary() =>
+            {
+                self.zstd
+                    .as_ref()
+                    .and_then(|zstd| zstd.compressor.lock().unwrap().compress(payload).ok())
+                    .map(|compressed| (METHOD_ZSTD, compressed))
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        };
+
+        match compressed {
+            // only worth it if it actually got smaller
+            Some((method, compressed)) if compressed.len() + 2 < payload.len() => {
+                frame(method, &compressed)
+            }
+            _ if is_framed(payload) => frame(METHOD_NONE, payload),
+            _ => payload.to_vec(),
+        }
+    }
+
+    /// Strips framing from (and decompresses) an incoming packet.
+    pub fn decompress(&self, packet: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        if self.config.is_none()
+            || !self.peer_advertised.load(Ordering::Relaxed)
+            || !is_framed(packet)
+        {
+            return Ok(packet.to_vec());
+        }
+
+        let payload = &packet[2..];
+        match packet[1] & !FRAME_METHOD {
+            METHOD_NONE => Ok(payload.to_vec()),
+            #[cfg(feature = "compression-lz4")]
+            METHOD_LZ4 => {
+                let len = payload
+                    .get(..4)
+                    .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+                    .ok_or_else(|| invalid_data("lz4 compressed packet without size"))?;
+                if len > MAX_DECOMPRESSED_LEN {
+                    return Err(invalid_data(format!(
+                        "lz4 compressed packet claims {} bytes, more than {}",
+                        len, MAX_DECOMPRESSED_LEN
+                    )));
+                }
+                lz4_flex::decompress_size_prepended(payload).map_err(invalid_data)
+            }
+            #[cfg(feature = "compression-zstd")]
+            METHOD_ZSTD => match self.zstd {
+                Some(ref zstd) => zstd
+                    .decompressor
+                    .lock()
+                    .unwrap()
+                    .decompress(payload, MAX_DECOMPRESSED_LEN)
+                    .map_err(invalid_data),
+                None => Err(invalid_data(
+                    "zstd compressed packet, but zstd is not enabled",
+                )),
+            },
+            method => Err(invalid_data(format!(
+                "unsupported packet compression method {}",
+                method
+            ))),
+        }
+    }
+}
+
+fn is_framed(packet: &[u8]) -> bool {
+    matches!(packet, [FRAME_MARKER, method, ..]
+        if method & FRAME_METHOD == FRAME_METHOD && method & !FRAME_METHOD <= METHOD_MAX)
+}
+
+fn frame(method: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 2);
+    packet.push(FRAME_MARKER);
+    packet.push(FRAME_METHOD | method);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the handshake of two connection ends, dropping the initial advertisement of `a`
+    fn handshake(a: &PacketCompressor, b: &PacketCompressor) {
+        let mut to_a = b.advertisement();
+        while let Some(ControlPacket::Compression {
+            accepts,
+            dictionary,
+            acknowledged,
+        }) = to_a.take()
+        {
+            if let Some(ControlPacket::Compression {
+                accepts,
+                dictionary,
+                acknowledged,
+            }) = a.negotiate(accepts, dictionary, acknowledged)
+            {
+                to_a = b.negotiate(accepts, dictionary, acknowledged);
+            }
+        }
+    }
+
+    fn payload() -> Vec<u8> {
+        b"position update position update position update position update".to_vec()
+    }
+
+    #[test]
+    fn peer_without_compression() {
+        let compressor = PacketCompressor::new(Some(Compression::Uncompressed));
+        let plain = PacketCompressor::new(None);
+        assert_eq!(plain.negotiate(0, 0, false), None);
+
+        // never acknowledged, so nothing is framed
+        let packet = [FRAME_MARKER, FRAME_METHOD | METHOD_NONE, 1, 2];
+        assert_eq!(compressor.compress(&packet), packet);
+        assert_eq!(compressor.decompress(&packet).unwrap(), packet);
+    }
+
+    #[test]
+    fn framed_lookalikes_are_escaped() {
+        let a = PacketCompressor::new(Some(Compression::Uncompressed));
+        let b = PacketCompressor::new(Some(Compression::Uncompressed));
+        handshake(&a, &b);
+
+        let packet = [FRAME_MARKER, FRAME_METHOD | METHOD_MAX, 1, 2];
+        let sent = a.compress(&packet);
+        assert_eq!(sent.len(), packet.len() + 2);
+        assert_eq!(b.decompress(&sent).unwrap(), packet);
+
+        // control packets and ordinary payloads go out as they are
+        let control = ControlPacket::Resume.encode(1);
+        assert_eq!(a.compress(&control), &control[..]);
+        assert_eq!(a.compress(&payload()), payload());
+    }
+
+    #[cfg(feature = "compression-lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        let a = PacketCompressor::new(Some(Compression::Lz4));
+        let b = PacketCompressor::new(Some(Compression::Uncompressed));
+        handshake(&a, &b);
+
+        let sent = a.compress(&payload());
+        assert!(sent.len() < payload().len());
+        assert_eq!(b.decompress(&sent).unwrap(), payload());
+        // b is configured not to compress
+        assert_eq!(b.compress(&payload()), payload());
+    }
+
+    #[cfg(feature = "compression-lz4")]
+    #[test]
+    fn lz4_size_prefix_is_bounded() {
+        let a = PacketCompressor::new(Some(Compression::Lz4));
+        let b = PacketCompressor::new(Some(Compression::Lz4));
+        handshake(&a, &b);
+
+        let mut packet = frame(METHOD_LZ4, &(u32::MAX).to_le_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        assert!(b.decompress(&packet).is_err());
+    }
+
+    #[cfg(feature = "compression-zstd")]
+    #[test]
+    fn zstd_needs_matching_dictionary() {
+        let zstd = |dictionary: &[u8]| {
+            PacketCompressor::new(Some(Compression::Zstd {
+                level: 3,
+                dictionary: Some(Arc::new(dictionary.to_vec())),
+            }))
+        };
+        let a = zstd(b"position update");
+        let b = zstd(b"position update");
+        handshake(&a, &b);
+        let sent = a.compress(&payload());
+        assert!(sent.len() < payload().len());
+        assert_eq!(b.decompress(&sent).unwrap(), payload());
+
+        let c = zstd(b"chat message");
+        handshake(&a, &c);
+        assert_eq!(a.compress(&payload()), payload());
+    }
+
+    #[cfg(feature = "compression-zstd")]
+    #[test]
+    fn trained_dictionary_id() {
+        let mut dictionary = ZSTD_DICTIONARY_MAGIC.to_vec();
+        dictionary.extend_from_slice(&1234u32.to_le_bytes());
+        dictionary.extend_from_slice(b"entropy tables and content");
+        assert_eq!(dictionary_id(&dictionary), 1234);
+        assert_eq!(dictionary_id(&[]), 0);
+        assert_ne!(dictionary_id(b"raw content"), 0);
+    }
+}
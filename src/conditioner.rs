@@ -0,0 +1,646 @@
+use instant::{Duration, Instant};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{hash_map::RandomState, BinaryHeap, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use naia_client_socket::LinkConditionerConfig;
+
+use super::{ConnectionHandle, NetworkResource, Packet};
+
+/// Packets are dropped when a bandwidth limited link is backed up by more than this
+const MAX_BANDWIDTH_BACKLOG: Duration = Duration::from_secs(1);
+
+/// Two-state Markov model of bursty packet loss.
+///
+/// Before each packet the link may switch between the good and the bad state,
+/// then the packet is lost with the loss probability of the current state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GilbertElliott {
+    /// probability of switching from the good to the bad state
+    pub p_good_to_bad: f32,
+    /// probability of switching from the bad back to the good state
+    pub p_bad_to_good: f32,
+    pub loss_good: f32,
+    pub loss_bad: f32,
+}
+
+impl GilbertElliott {
+    /// Classic Gilbert model - no loss in the good state, everything lost in the bad state.
+    /// Mean burst length is `1 / p_bad_to_good` packets.
+    pub fn new(p_good_to_bad: f32, p_bad_to_good: f32) -> Self {
+        GilbertElliott {
+            p_good_to_bad,
+            p_bad_to_good,
+            loss_good: 0.0,
+            loss_bad: 1.0,
+        }
+    }
+}
+
+/// Impairments applied to packets travelling in one direction.
+/// The default profile is a perfect link.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkProfile {
+    /// delay added to every packet
+    pub latency_ms: u32,
+    /// random extra delay in `0..=jitter_ms`
+    pub jitter_ms: u32,
+    /// probability (0.0 - 1.0) of dropping a packet
+    pub loss: f32,
+    /// bursty loss, applied in addition to `loss`
+    pub burst_loss: Option<GilbertElliott>,
+    /// probability of delivering a packet twice
+    pub duplicate: f32,
+    /// probability of holding a packet back by `reorder_delay_ms`, letting later packets overtake it
+    pub reorder: f32,
+    pub reorder_delay_ms: u32,
+    /// link capacity in bytes per second - packets queue up behind each other,
+    /// and get dropped once the queue is a second long
+    pub bandwidth: Option<u32>,
+}
+
+impl LinkProfile {
+    pub fn good() -> Self {
+        LinkProfile {
+            latency_ms: 40,
+            jitter_ms: 6,
+            loss: 0.0002,
+            ..Default::default()
+        }
+    }
+
+    pub fn average() -> Self {
+        LinkProfile {
+            latency_ms: 170,
+            jitter_ms: 45,
+            loss: 0.02,
+            ..Default::default()
+        }
+    }
+
+    pub fn poor() -> Self {
+        LinkProfile {
+            latency_ms: 300,
+            jitter_ms: 84,
+            loss: 0.04,
+            burst_loss: Some(GilbertElliott::new(0.01, 0.3)),
+            duplicate: 0.01,
+            reorder: 0.02,
+            reorder_delay_ms: 50,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<LinkConditionerConfig> for LinkProfile {
+    fn from(config: LinkConditionerConfig) -> Self {
+        LinkProfile {
+            latency_ms: config.incoming_latency,
+            jitter_ms: config.incoming_jitter,
+            loss: config.incoming_loss,
+            ..Default::default()
+        }
+    }
+}
+
+/// Profiles for both directions of a connection, as seen from this end. None leaves the direction untouched.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkConditions {
+    pub inbound: Option<LinkProfile>,
+    pub outbound: Option<LinkProfile>,
+}
+
+impl LinkConditions {
+    /// Same profile in both directions
+    pub fn symmetric(profile: LinkProfile) -> Self {
+        LinkConditions {
+            inbound: Some(profile.clone()),
+            outbound: Some(profile),
+        }
+    }
+}
+
+/// `naia` conditioner settings only ever affected incoming packets
+impl From<LinkConditionerConfig> for LinkConditions {
+    fn from(config: LinkConditionerConfig) -> Self {
+        LinkConditions {
+            inbound: Some(config.into()),
+            outbound: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConditionerSettings {
+    default: LinkConditions,
+    connections: HashMap<ConnectionHandle, LinkConditions>,
+}
+
+/// Shared, runtime adjustable link conditioner settings. Clones share the settings,
+/// changes apply to the next packet of every connection.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConditioner(Arc<RwLock<ConditionerSettings>>);
+
+impl LinkConditioner {
+    pub fn new(default: LinkConditions) -> Self {
+        LinkConditioner(Arc::new(RwLock::new(ConditionerSettings {
+            default,
+            connections: HashMap::new(),
+        })))
+    }
+
+    /// Conditions of connections without an override
+    pub fn default_conditions(&self) -> LinkConditions {
+        self.0
+            .read()
+            .expect("conditioner lock poisoned")
+            .default
+            .clone()
+    }
+
+    pub fn set_default_conditions(&self, conditions: LinkConditions) {
+        self.0.write().expect("conditioner lock poisoned").default = conditions;
+    }
+
+    /// Effective conditions of a connection - its override, or the default
+    pub fn connection_conditions(&self, handle: ConnectionHandle) -> LinkConditions {
+        let settings = self.0.read().expect("conditioner lock poisoned");
+        settings
+            .connections
+            .get(&handle)
+            .unwrap_or(&settings.default)
+            .clone()
+    }
+
+    pub fn set_connection_conditions(&self, handle: ConnectionHandle, conditions: LinkConditions) {
+        self.0
+            .write()
+            .expect("conditioner lock poisoned")
+            .connections
+            .insert(handle, conditions);
+    }
+
+    /// Removes the override, making the connection use the default conditions again
+    pub fn clear_connection_conditions(&self, handle: ConnectionHandle) {
+        self.0
+            .write()
+            .expect("conditioner lock poisoned")
+            .connections
+            .remove(&handle);
+    }
+
+    fn profile(&self, handle: ConnectionHandle, inbound: bool) -> Option<LinkProfile> {
+        let settings = self.0.read().expect("conditioner lock poisoned");
+        let conditions = settings
+            .connections
+            .get(&handle)
+            .unwrap_or(&settings.default);
+        if inbound {
+            conditions.inbound.clone()
+        } else {
+            conditions.outbound.clone()
+        }
+    }
+}
+
+/// Link conditioner of a single connection
+#[derive(Clone)]
+pub struct ConnectionConditioner {
+    pub handle: ConnectionHandle,
+    pub conditioner: LinkConditioner,
+}
+
+/// xorshift64* - good enough to roll dice for simulated packet loss
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        Rng(hasher.finish() | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// uniform in 0.0..1.0
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next_f32() < probability
+    }
+
+    fn up_to(&mut self, max: u32) -> u32 {
+        if max == 0 {
+            0
+        } else {
+            (self.next_u64() % (max as u64 + 1)) as u32
+        }
+    }
+}
+
+struct Delayed {
+    deliver_at: Instant,
+    sequence: u64,
+    packet: Packet,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.sequence).cmp(&(other.deliver_at, other.sequence))
+    }
+}
+
+struct LinkQueue {
+    rng: Rng,
+    burst: bool,
+    /// when a bandwidth limited link is done with the packets queued so far
+    busy_until: Instant,
+    sequence: u64,
+    queue: BinaryHeap<Reverse<Delayed>>,
+}
+
+impl LinkQueue {
+    fn new() -> Self {
+        LinkQueue {
+            rng: Rng::new(),
+            burst: false,
+            busy_until: Instant::now(),
+            sequence: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    fn schedule(&mut self, deliver_at: Instant, packet: Packet) {
+        self.sequence += 1;
+        self.queue.push(Reverse(Delayed {
+            deliver_at,
+            sequence: self.sequence,
+            packet,
+        }));
+    }
+
+    fn push(&mut self, profile: Option<LinkProfile>, packet: Packet) {
+        self.push_at(Instant::now(), profile, packet);
+    }
+
+    fn push_at(&mut self, now: Instant, profile: Option<LinkProfile>, packet: Packet) {
+        let profile = match profile {
+            Some(profile) => profile,
+            None => return self.schedule(now, packet),
+        };
+
+        let mut lost = self.rng.chance(profile.loss);
+        if let Some(ref burst) = profile.burst_loss {
+            let switch = if self.burst {
+                burst.p_bad_to_good
+            } else {
+                burst.p_good_to_bad
+            };
+            if self.rng.chance(switch) {
+                self.burst = !self.burst;
+            }
+            let loss = if self.burst {
+                burst.loss_bad
+            } else {
+                burst.loss_good
+            };
+            lost |= self.rng.chance(loss);
+        }
+        if lost {
+            return;
+        }
+
+        let mut sent_at = now;
+        if let Some(bandwidth) = profile.bandwidth {
+            let start = self.busy_until.max(now);
+            if start.duration_since(now) > MAX_BANDWIDTH_BACKLOG {
+                return;
+            }
+            let transmission = packet.len() as f64 / bandwidth.max(1) as f64;
+            self.busy_until = start + Duration::from_secs_f64(transmission);
+            sent_at = self.busy_until;
+        }
+
+        let copies = if self.rng.chance(profile.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay_ms = profile.latency_ms + self.rng.up_to(profile.jitter_ms);
+            if self.rng.chance(profile.reorder) {
+                delay_ms += profile.reorder_delay_ms;
+            }
+            self.schedule(
+                sent_at + Duration::from_millis(delay_ms as u64),
+                packet.clone(),
+            );
+        }
+    }
+
+    fn pop(&mut self) -> Option<Packet> {
+        self.pop_at(Instant::now())
+    }
+
+    fn pop_at(&mut self, now: Instant) -> Option<Packet> {
+        match self.queue.peek() {
+            Some(Reverse(delayed)) if delayed.deliver_at <= now => {
+                self.queue.pop().map(|Reverse(delayed)| delayed.packet)
+            }
+            _ => None,
+        }
+    }
+
+    /// Makes every held back packet due now, keeping their order
+    fn release(&mut self) {
+        let now = Instant::now();
+        let held = std::mem::take(&mut self.queue).into_sorted_vec();
+        // sorted by `Reverse`, so the latest packet comes first
+        for Reverse(delayed) in held.into_iter().rev() {
+            self.schedule(now, delayed.packet);
+        }
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(delayed)| delayed.deliver_at)
+    }
+}
+
+struct LinkState {
+    conditioner: Option<ConnectionConditioner>,
+    inbound: LinkQueue,
+    outbound: LinkQueue,
+}
+
+/// Per connection packet queues, shared between the connection and its channels task.
+/// Without a conditioner packets pass straight through.
+#[derive(Clone)]
+pub struct ConditionedLink(Arc<Mutex<LinkState>>);
+
+impl Default for ConditionedLink {
+    fn default() -> Self {
+        ConditionedLink(Arc::new(Mutex::new(LinkState {
+            conditioner: None,
+            inbound: LinkQueue::new(),
+            outbound: LinkQueue::new(),
+        })))
+    }
+}
+
+impl ConditionedLink {
+    pub fn set(&self, conditioner: Option<ConnectionConditioner>) {
+        self.0.lock().expect("link lock poisoned").conditioner = conditioner;
+    }
+
+    fn profile(state: &LinkState, inbound: bool) -> Option<LinkProfile> {
+        state
+            .conditioner
+            .as_ref()
+            .and_then(|c| c.conditioner.profile(c.handle, inbound))
+    }
+
+    pub fn push_inbound(&self, packet: Packet) {
+        let mut state = self.0.lock().expect("link lock poisoned");
+        let profile = Self::profile(&state, true);
+        state.inbound.push(profile, packet);
+    }
+
+    pub fn pop_inbound(&self) -> Option<Packet> {
+        self.0.lock().expect("link lock poisoned").inbound.pop()
+    }
+
+    pub fn push_outbound(&self, packet: Packet) {
+        let mut state = self.0.lock().expect("link lock poisoned");
+        let profile = Self::profile(&state, false);
+        state.outbound.push(profile, packet);
+    }
+
+    pub fn pop_outbound(&self) -> Option<Packet> {
+        self.0.lock().expect("link lock poisoned").outbound.pop()
+    }
+
+    /// Lets all held back outbound packets through on the next `pop_outbound`
+    pub fn release_outbound(&self) {
+        self.0
+            .lock()
+            .expect("link lock poisoned")
+            .outbound
+            .release();
+    }
+
+    /// When the next held back outbound packet is due
+    pub fn next_outbound_due(&self) -> Option<Instant> {
+        self.0
+            .lock()
+            .expect("link lock poisoned")
+            .outbound
+            .next_due()
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// Handle to the link conditioner settings, to adjust network conditions at runtime
+    /// (eg. from a debug UI). Clones of it stay in effect.
+    pub fn link_conditioner(&self) -> &LinkConditioner {
+        &self.link_conditioner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queue with a fixed seed, and a clock starting now
+    fn seeded(seed: u64) -> (LinkQueue, Instant) {
+        let queue = LinkQueue {
+            rng: Rng(seed | 1),
+            ..LinkQueue::new()
+        };
+        let now = queue.busy_until;
+        (queue, now)
+    }
+
+    fn packet(byte: u8, len: usize) -> Packet {
+        Packet::from(vec![byte; len])
+    }
+
+    /// Pushes `count` numbered packets at `now` and pops everything due by `until`
+    fn run(
+        queue: &mut LinkQueue,
+        now: Instant,
+        profile: &LinkProfile,
+        count: u8,
+        until: Instant,
+    ) -> Vec<u8> {
+        for byte in 0..count {
+            queue.push_at(now, Some(profile.clone()), packet(byte, 10));
+        }
+        std::iter::from_fn(|| queue.pop_at(until))
+            .map(|packet| packet[0])
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_fate() {
+        let profile = LinkProfile::poor();
+        let (mut a, now) = seeded(7);
+        let (mut b, _) = seeded(7);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            run(&mut a, now, &profile, 200, later),
+            run(&mut b, now, &profile, 200, later)
+        );
+    }
+
+    #[test]
+    fn packets_wait_for_their_latency() {
+        let profile = LinkProfile {
+            latency_ms: 100,
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(1);
+        queue.push_at(now, Some(profile), packet(0, 10));
+        assert!(queue.pop_at(now + Duration::from_millis(99)).is_none());
+        assert!(queue.pop_at(now + Duration::from_millis(100)).is_some());
+
+        // without a profile they pass straight through
+        queue.push_at(now, None, packet(1, 10));
+        assert!(queue.pop_at(now).is_some());
+    }
+
+    #[test]
+    fn loss_drops_about_its_share() {
+        let profile = LinkProfile {
+            loss: 0.25,
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(3);
+        let mut delivered = 0;
+        for _ in 0..10 {
+            delivered += run(&mut queue, now, &profile, 100, now).len();
+        }
+        assert!((700..800).contains(&delivered), "{} delivered", delivered);
+
+        let (mut queue, now) = seeded(3);
+        let everything = LinkProfile {
+            loss: 1.0,
+            ..Default::default()
+        };
+        assert!(run(&mut queue, now, &everything, 100, now).is_empty());
+    }
+
+    #[test]
+    fn duplicates_arrive_twice() {
+        let profile = LinkProfile {
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(5);
+        assert_eq!(run(&mut queue, now, &profile, 3, now), [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn reordered_packets_get_overtaken() {
+        let profile = LinkProfile {
+            reorder: 0.5,
+            reorder_delay_ms: 50,
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(9);
+        let on_time = run(&mut queue, now, &profile, 100, now);
+        let late = run(
+            &mut queue,
+            now,
+            &profile,
+            0,
+            now + Duration::from_millis(50),
+        );
+        assert!(!on_time.is_empty() && !late.is_empty());
+        assert_eq!(on_time.len() + late.len(), 100);
+        // each group keeps its own order
+        assert!(on_time.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(late.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn bandwidth_queues_and_drops_the_backlog() {
+        let profile = LinkProfile {
+            bandwidth: Some(1000),
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(11);
+        // 100 bytes take a tenth of a second each
+        for byte in 0..15 {
+            queue.push_at(now, Some(profile.clone()), packet(byte, 100));
+        }
+        let due = |queue: &mut LinkQueue, ms| {
+            std::iter::from_fn(|| queue.pop_at(now + Duration::from_millis(ms))).count()
+        };
+        assert_eq!(due(&mut queue, 99), 0);
+        assert_eq!(due(&mut queue, 100), 1);
+        assert_eq!(due(&mut queue, 500), 4);
+        // a second of backlog is all the link holds
+        assert_eq!(due(&mut queue, 5000), 6);
+    }
+
+    #[test]
+    fn bursts_come_and_go() {
+        let profile = LinkProfile {
+            burst_loss: Some(GilbertElliott::new(0.05, 0.25)),
+            ..Default::default()
+        };
+        let (mut queue, now) = seeded(13);
+        let mut lost_runs = Vec::new();
+        let mut run_len = 0;
+        for byte in 0..2000u32 {
+            queue.push_at(now, Some(profile.clone()), packet(byte as u8, 1));
+            if queue.pop_at(now).is_some() {
+                if run_len > 0 {
+                    lost_runs.push(run_len);
+                }
+                run_len = 0;
+            } else {
+                assert!(queue.burst, "lost outside a burst");
+                run_len += 1;
+            }
+        }
+        // losses come in runs, of 1 / p_bad_to_good = 4 packets on average
+        assert!(lost_runs.len() > 20);
+        let mean = lost_runs.iter().sum::<u32>() as f32 / lost_runs.len() as f32;
+        assert!((3.0..5.0).contains(&mean), "mean burst of {}", mean);
+
+        // nothing gets lost once the link settles in the good state
+        let calm = LinkProfile {
+            burst_loss: Some(GilbertElliott::new(0.0, 1.0)),
+            ..Default::default()
+        };
+        queue.push_at(now, Some(calm.clone()), packet(0, 1));
+        queue.pop_at(now);
+        assert!(!queue.burst);
+        assert_eq!(run(&mut queue, now, &calm, 100, now).len(), 100);
+    }
+}
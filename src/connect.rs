@@ -0,0 +1,436 @@
+use bevy::prelude::debug;
+#[cfg(not(target_arch = "wasm32"))]
+use crossbeam_channel::{Receiver, TryRecvError};
+use instant::{Duration, Instant};
+use std::{collections::HashMap, error::Error, fmt, io, net::SocketAddr, sync::Arc};
+
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
+use naia_client_socket::ClientSocket;
+
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
+use super::transport::ClientConnection;
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    feature = "std-udp",
+    not(feature = "naia-server-socket")
+))]
+use super::udp::UdpTransport;
+use super::{
+    backend::{Transport, TransportConnection, TransportLink},
+    compression::PacketCompressor,
+    control::{next_control_sequence, ControlPacket},
+    transport::Connection,
+    ConnectionHandle, NetworkResource,
+};
+#[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+use super::{rendezvous, udp};
+
+/// Default of `NetworkSettings::connect_timeout_ms`
+pub(crate) const DEFAULT_CONNECT_TIMEOUT_MS: usize = 10_000;
+
+/// Server to connect to, see `NetworkResource::connect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectTarget {
+    Address(SocketAddr),
+    /// `"host:port"`, resolved on a thread of its own, or by the transport of `connect_with`
+    /// if it takes hostnames, see `Transport::connect_host`
+    Host(String),
+    /// Host of `session` registered with a rendezvous server, see `UdpListener::with_rendezvous`.
+    /// Both sides punch through their NATs, then connect over std UDP - `connect_with` ignores its transport.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+    Rendezvous {
+        server: SocketAddr,
+        session: String,
+    },
+}
+
+impl From<SocketAddr> for ConnectTarget {
+    fn from(address: SocketAddr) -> Self {
+        ConnectTarget::Address(address)
+    }
+}
+
+impl From<&str> for ConnectTarget {
+    fn from(target: &str) -> Self {
+        match target.parse() {
+            Ok(address) => ConnectTarget::Address(address),
+            Err(_) => ConnectTarget::Host(target.to_owned()),
+        }
+    }
+}
+
+impl From<String> for ConnectTarget {
+    fn from(target: String) -> Self {
+        match target.parse() {
+            Ok(address) => ConnectTarget::Address(address),
+            Err(_) => ConnectTarget::Host(target),
+        }
+    }
+}
+
+/// Reason of a `NetworkEvent::ConnectFailed`
+#[derive(Debug)]
+pub enum ConnectError {
+    /// hostname lookup failed
+    Resolve(String, io::Error),
+    /// custom `Transport` couldn't open the link
+    Transport(Box<dyn Error + Sync + Send>),
+    /// server didn't answer within `NetworkSettings::connect_timeout_ms`
+    Timeout,
+    /// rendezvous server couldn't introduce us to the host of the session
+    Rendezvous(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Resolve(host, err) => write!(f, "can't resolve {}: {}", host, err),
+            ConnectError::Transport(err) => write!(f, "transport error: {}", err),
+            ConnectError::Timeout => write!(f, "connect timed out"),
+            ConnectError::Rendezvous(err) => write!(f, "rendezvous failed: {}", err),
+        }
+    }
+}
+
+impl Error for ConnectError {}
+
+/// Blocking lookup running on its own thread. `getaddrinfo` and the rendezvous exchange
+/// block for seconds when the network is slow, and the few IO task pool threads also drive
+/// the channels of every connection.
+#[cfg(not(target_arch = "wasm32"))]
+struct Blocking<T>(Receiver<io::Result<T>>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + 'static> Blocking<T> {
+    fn spawn<F>(name: String, f: F) -> Self
+    where
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let result_tx = tx.clone();
+        if let Err(err) = std::thread::Builder::new().name(name).spawn(move || {
+            let _ = result_tx.send(f());
+        }) {
+            let _ = tx.send(Err(err));
+        }
+        Blocking(rx)
+    }
+
+    /// The result, once the lookup is done
+    fn poll(&self) -> Option<io::Result<T>> {
+        match self.0.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(io::Error::other("lookup thread panicked")))
+            }
+        }
+    }
+}
+
+/// Hostname lookup of a connect, and the transport to connect with once it's done
+#[cfg(not(target_arch = "wasm32"))]
+struct Resolving {
+    host: String,
+    lookup: Blocking<SocketAddr>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+/// Outgoing connections the server hasn't answered yet
+#[derive(Default)]
+pub(crate) struct ConnectState {
+    started: HashMap<ConnectionHandle, Instant>,
+    #[cfg(not(target_arch = "wasm32"))]
+    resolving: HashMap<ConnectionHandle, Resolving>,
+    /// introductions by a rendezvous server, with the socket to connect from
+    #[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+    punching: HashMap<ConnectionHandle, Blocking<(std::net::UdpSocket, SocketAddr)>>,
+    failed: Vec<(ConnectionHandle, ConnectError)>,
+}
+
+impl ConnectState {
+    pub(crate) fn is_connecting(&self, handle: ConnectionHandle) -> bool {
+        self.started.contains_key(&handle)
+    }
+
+    /// Any packet from the server, normally its `Accept`, proves the connection works.
+    /// Returns true for the first one, when the connection gets announced.
+    pub(crate) fn answered(&mut self, handle: ConnectionHandle) -> bool {
+        self.started.remove(&handle).is_some()
+    }
+
+    pub(crate) fn cancel(&mut self, handle: ConnectionHandle) {
+        self.started.remove(&handle);
+        #[cfg(not(target_arch = "wasm32"))]
+        self.resolving.remove(&handle);
+        #[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+        self.punching.remove(&handle);
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// Starts connecting to a server and returns the handle of the connection right away.
+    ///
+    /// `target` is a `SocketAddr`, or a `"host:port"` string resolved asynchronously on a thread of its own.
+    /// In the browser hostnames only work with `connect_with` and a transport taking them, like `WebSocketTransport`.
+    /// `NetworkEvent::Connected` is sent once the server accepts the connection, or
+    /// `NetworkEvent::ConnectFailed` instead if the host can't be resolved or the server
+    /// doesn't answer within `NetworkSettings::connect_timeout_ms`.
+    pub fn connect<T: Into<ConnectTarget>>(&mut self, target: T) -> ConnectionHandle {
+        self.start_connect(target.into(), None)
+    }
+
+    /// Like `connect`, but through a custom `Transport` instead of naia sockets
+    pub fn connect_with<T: Into<ConnectTarget>>(
+        &mut self,
+        transport: Arc<dyn Transport>,
+        target: T,
+    ) -> ConnectionHandle {
+        self.start_connect(target.into(), Some(transport))
+    }
+
+    fn start_connect(
+        &mut self,
+        target: ConnectTarget,
+        transport: Option<Arc<dyn Transport>>,
+    ) -> ConnectionHandle {
+        let handle = self.next_connection_handle();
+        self.connects.started.insert(handle, Instant::now());
+        if let (ConnectTarget::Host(host), Some(transport)) = (&target, &transport) {
+            if let Some(link) = transport.connect_host(host) {
+                debug!("Connecting [{}] to {}", handle, host);
+                let connection = link.map(|link| self.link_connection(link));
+                self.open_connection(handle, connection);
+                return handle;
+            }
+        }
+        match target {
+            ConnectTarget::Address(address) => self.start_connection(handle, address, transport),
+            #[cfg(not(target_arch = "wasm32"))]
+            ConnectTarget::Host(host) => {
+                let name = host.clone();
+                let lookup = Blocking::spawn(format!("resolve {}", host), move || resolve(&name));
+                self.connects.resolving.insert(
+                    handle,
+                    Resolving {
+                        host,
+                        lookup,
+                        transport,
+                    },
+                );
+            }
+            #[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+            ConnectTarget::Rendezvous { server, session } => {
+                let introduction = Blocking::spawn(format!("rendezvous {}", server), move || {
+                    rendezvous::introduce(server, &session)
+                });
+                self.connects.punching.insert(handle, introduction);
+            }
+            #[cfg(target_arch = "wasm32")]
+            ConnectTarget::Host(host) => {
+                let err = io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "hostnames can't be resolved in the browser, connect through a transport taking them",
+                );
+                self.connects
+                    .failed
+                    .push((handle, ConnectError::Resolve(host, err)));
+            }
+        }
+        handle
+    }
+
+    fn start_connection(
+        &mut self,
+        handle: ConnectionHandle,
+        address: SocketAddr,
+        transport: Option<Arc<dyn Transport>>,
+    ) {
+        debug!("Connecting [{}] to {}", handle, address);
+        let connection = match transport {
+            Some(transport) => transport
+                .connect(address)
+                .map(|link| self.link_connection(link)),
+            None => self.default_connection(address),
+        };
+        self.open_connection(handle, connection);
+    }
+
+    /// Queues the connection, or reports why it couldn't be opened
+    fn open_connection(
+        &mut self,
+        handle: ConnectionHandle,
+        connection: Result<Box<dyn Connection>, Box<dyn Error + Sync + Send>>,
+    ) {
+        match connection {
+            Ok(connection) => self.queue_connection(handle, connection),
+            Err(err) => self
+                .connects
+                .failed
+                .push((handle, ConnectError::Transport(err))),
+        }
+    }
+
+    fn link_connection(&self, link: TransportLink) -> Box<dyn Connection> {
+        Box::new(TransportConnection::new(
+            self.task_pool.clone(),
+            link,
+            None,
+            Arc::new(PacketCompressor::new(self.compression.clone())),
+        ))
+    }
+
+    /// naia client socket - WebRTC in the browser, UDP natively
+    #[cfg(any(
+        target_arch = "wasm32",
+        not(feature = "std-udp"),
+        feature = "naia-server-socket"
+    ))]
+    fn default_connection(
+        &self,
+        address: SocketAddr,
+    ) -> Result<Box<dyn Connection>, Box<dyn Error + Sync + Send>> {
+        let mut client_socket = ClientSocket::connect(address);
+        let sender = client_socket.get_sender();
+
+        Ok(Box::new(ClientConnection::new(
+            self.task_pool.clone(),
+            client_socket,
+            sender,
+            address,
+            Arc::new(PacketCompressor::new(self.compression.clone())),
+        )))
+    }
+
+    /// std UDP socket, when built without naia's server socket
+    #[cfg(all(
+        not(target_arch = "wasm32"),
+        feature = "std-udp",
+        not(feature = "naia-server-socket")
+    ))]
+    fn default_connection(
+        &self,
+        address: SocketAddr,
+    ) -> Result<Box<dyn Connection>, Box<dyn Error + Sync + Send>> {
+        Ok(self.link_connection(UdpTransport.connect(address)?))
+    }
+
+    /// Starts connections whose host got resolved, returns connects that failed meanwhile
+    pub(crate) fn poll_connects(&mut self) -> Vec<(ConnectionHandle, ConnectError)> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let mut resolved = Vec::new();
+            self.connects
+                .resolving
+                .retain(|handle, resolving| match resolving.lookup.poll() {
+                    Some(result) => {
+                        resolved.push((
+                            *handle,
+                            resolving.host.clone(),
+                            resolving.transport.take(),
+                            result,
+                        ));
+                        false
+                    }
+                    None => true,
+                });
+            for (handle, host, transport, result) in resolved {
+                match result {
+                    Ok(address) => self.start_connection(handle, address, transport),
+                    Err(err) => self
+                        .connects
+                        .failed
+                        .push((handle, ConnectError::Resolve(host, err))),
+                }
+            }
+        }
+        #[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+        {
+            let mut introduced = Vec::new();
+            self.connects
+                .punching
+                .retain(|handle, introduction| match introduction.poll() {
+                    Some(result) => {
+                        introduced.push((*handle, result));
+                        false
+                    }
+                    None => true,
+                });
+            for (handle, result) in introduced {
+                match result.and_then(|(socket, host)| udp::connected_link(socket, host)) {
+                    Ok(link) => {
+                        debug!(
+                            "Connecting [{}] to punched host {:?}",
+                            handle, link.remote_address
+                        );
+                        let connection = self.link_connection(link);
+                        self.queue_connection(handle, connection);
+                    }
+                    Err(err) => self
+                        .connects
+                        .failed
+                        .push((handle, ConnectError::Rendezvous(err))),
+                }
+            }
+        }
+
+        let failed: Vec<_> = self.connects.failed.drain(..).collect();
+        for (handle, _) in failed.iter() {
+            self.connects.started.remove(handle);
+            self.release_handle(*handle);
+        }
+        failed
+    }
+
+    /// Asks unanswered servers again, returns connects that ran out of `timeout_ms`
+    pub(crate) fn expire_connects(&mut self, timeout_ms: Option<usize>) -> Vec<ConnectionHandle> {
+        let now = Instant::now();
+        let timeout = timeout_ms.map(|timeout_ms| Duration::from_millis(timeout_ms as u64));
+        let expired: Vec<ConnectionHandle> = self
+            .connects
+            .started
+            .iter()
+            .filter(|(_, started)| match timeout {
+                Some(timeout) => now > **started + timeout,
+                None => false,
+            })
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in expired.iter() {
+            self.connects.cancel(*handle);
+        }
+
+        let NetworkResource {
+            connections,
+            connects,
+            control_sequence,
+            ..
+        } = self;
+        for handle in connects.started.keys() {
+            if let Some(connection) = connections.get_mut(handle) {
+                let sequence = next_control_sequence(control_sequence);
+                if let Err(err) = connection.send(ControlPacket::Connect.encode(sequence)) {
+                    debug!("Can't ask [{}] to accept connection: {}", handle, err);
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn resolve(host: &str) -> io::Result<SocketAddr> {
+    use std::net::ToSocketAddrs;
+
+    host.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))
+}
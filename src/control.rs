@@ -0,0 +1,304 @@
+use bevy::prelude::debug;
+use std::{error::Error, time::Duration};
+
+use super::{ConnectionHandle, NetworkResource, Packet};
+
+/// First bytes of packets the plugin handles itself - control, server query and rendezvous.
+/// Unlikely to start a user or turbulence packet.
+pub(crate) const PLUGIN_PACKET_PREFIX: [u8; 2] = [0xff, 0xff];
+
+/// Magic of a plugin packet: `PLUGIN_PACKET_PREFIX` followed by `tag`
+pub(crate) const fn plugin_magic(tag: &[u8; 6]) -> [u8; 8] {
+    let mut magic = [
+        PLUGIN_PACKET_PREFIX[0],
+        PLUGIN_PACKET_PREFIX[1],
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut i = 0;
+    while i < tag.len() {
+        magic[PLUGIN_PACKET_PREFIX.len() + i] = tag[i];
+        i += 1;
+    }
+    magic
+}
+
+const CONTROL_MAGIC: [u8; 8] = plugin_magic(b"BNTCTL");
+/// Control packets are unreliable, so notices are sent a few times. Handling them is idempotent.
+const CONTROL_REPEAT: usize = 3;
+
+const OP_SUSPEND: u8 = 1;
+const OP_RESUME: u8 = 2;
+const OP_DISCONNECT: u8 = 3;
+const OP_CONNECT: u8 = 4;
+const OP_ACCEPT: u8 = 5;
+const OP_COMPRESSION: u8 = 6;
+
+/// Connection management notices exchanged as raw packets, bypassing channels.
+/// Like heartbeats, they are consumed by `receive_packets`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ControlPacket {
+    /// peer won't be sending for up to `timeout_ms`
+    Suspend {
+        timeout_ms: u32,
+    },
+    Resume,
+    /// peer closed the connection
+    Disconnect,
+    /// client asks the server to confirm the connection, repeated until answered
+    Connect,
+    /// server answer to `Connect`
+    Accept,
+    /// compression methods and zstd dictionary the peer accepts, see `PacketCompressor`
+    Compression {
+        accepts: u8,
+        dictionary: u32,
+        /// peer got our advertisement
+        acknowledged: bool,
+    },
+}
+
+impl ControlPacket {
+    /// `sequence` orders notices of the sender, repeats of a notice share it
+    pub(crate) fn encode(&self, sequence: u32) -> Packet {
+        let mut packet = CONTROL_MAGIC.to_vec();
+        packet.extend_from_slice(&sequence.to_le_bytes());
+        match self {
+            ControlPacket::Suspend { timeout_ms } => {
+                packet.push(OP_SUSPEND);
+                packet.extend_from_slice(&timeout_ms.to_le_bytes());
+            }
+            ControlPacket::Resume => packet.push(OP_RESUME),
+            ControlPacket::Disconnect => packet.push(OP_DISCONNECT),
+            ControlPacket::Connect => packet.push(OP_CONNECT),
+            ControlPacket::Accept => packet.push(OP_ACCEPT),
+            ControlPacket::Compression {
+                accepts,
+                dictionary,
+                acknowledged,
+            } => {
+                packet.push(OP_COMPRESSION);
+                packet.push(*accepts);
+                packet.extend_from_slice(&dictionary.to_le_bytes());
+                packet.push(*acknowledged as u8);
+            }
+        }
+        Packet::from(packet)
+    }
+
+    /// Control packet and its sequence
+    pub(crate) fn decode(packet: &[u8]) -> Option<(u32, Self)> {
+        let body = packet.strip_prefix(&CONTROL_MAGIC[..])?;
+        if body.len() < 4 {
+            return None;
+        }
+        let (sequence, body) = body.split_at(4);
+        let sequence = u32::from_le_bytes([sequence[0], sequence[1], sequence[2], sequence[3]]);
+        let control = match body.split_first()? {
+            (&OP_SUSPEND, timeout) if timeout.len() == 4 => Some(ControlPacket::Suspend {
+                timeout_ms: u32::from_le_bytes([timeout[0], timeout[1], timeout[2], timeout[3]]),
+            }),
+            (&OP_RESUME, []) => Some(ControlPacket::Resume),
+            (&OP_DISCONNECT, []) => Some(ControlPacket::Disconnect),
+            (&OP_CONNECT, []) => Some(ControlPacket::Connect),
+            (&OP_ACCEPT, []) => Some(ControlPacket::Accept),
+            (&OP_COMPRESSION, [accepts, d0, d1, d2, d3, acknowledged]) => {
+                Some(ControlPacket::Compression {
+                    accepts: *accepts,
+                    dictionary: u32::from_le_bytes([*d0, *d1, *d2, *d3]),
+                    acknowledged: *acknowledged != 0,
+                })
+            }
+            _ => None,
+        }?;
+        Some((sequence, control))
+    }
+}
+
+/// Sequence for the next control notice
+pub(crate) fn next_control_sequence(sequence: &mut u32) -> u32 {
+    *sequence = sequence.wrapping_add(1);
+    *sequence
+}
+
+/// Suspensions of a connection. Each end lifts only the suspension it made.
+#[derive(Debug, Default)]
+pub(crate) struct Suspension {
+    /// timeout we announced with `suspend`
+    local: Option<u32>,
+    /// timeout the peer announced
+    remote: Option<u32>,
+    /// sequence of the last suspend or resume notice of the peer
+    remote_sequence: Option<u32>,
+}
+
+impl Suspension {
+    /// Longest idle window of the suspensions in effect
+    pub(crate) fn timeout_ms(&self) -> Option<u32> {
+        self.local.max(self.remote)
+    }
+
+    /// Applies a peer `Suspend` (with a timeout) or `Resume` (None) notice.
+    /// Returns whether the peer suspension changed - repeated and reordered notices don't change it.
+    pub(crate) fn remote(&mut self, sequence: u32, timeout_ms: Option<u32>) -> bool {
+        if let Some(last) = self.remote_sequence {
+            // not newer than the last notice applied, with wrap-around
+            if sequence.wrapping_sub(last) as i32 <= 0 {
+                return false;
+            }
+        }
+        self.remote_sequence = Some(sequence);
+        let changed = self.remote.is_some() != timeout_ms.is_some();
+        self.remote = timeout_ms;
+        changed
+    }
+}
+
+impl<L> NetworkResource<L> {
+    fn send_control(
+        &mut self,
+        handle: ConnectionHandle,
+        control: ControlPacket,
+    ) -> Result<(), Box<dyn Error + Sync + Send + 'static>> {
+        let packet = control.encode(next_control_sequence(&mut self.control_sequence));
+        for _ in 0..CONTROL_REPEAT {
+            self.send(handle, packet.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Marks a connection as suspended, eg. while blocking on a loading screen, and notifies the peer.
+    ///
+    /// Until `resume` is called, both ends wait up to `timeout` for packets before idle-disconnecting
+    /// (or the regular idle timeout, if that is longer).
+    pub fn suspend(
+        &mut self,
+        handle: ConnectionHandle,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error + Sync + Send + 'static>> {
+        let timeout_ms = timeout.as_millis().min(u32::MAX as u128) as u32;
+        self.send_control(handle, ControlPacket::Suspend { timeout_ms })?;
+        self.suspensions.entry(handle).or_default().local = Some(timeout_ms);
+        Ok(())
+    }
+
+    /// Lifts our suspension. Normal idle timeout checks are restored once the peer
+    /// has no suspension of its own either.
+    pub fn resume(
+        &mut self,
+        handle: ConnectionHandle,
+    ) -> Result<(), Box<dyn Error + Sync + Send + 'static>> {
+        if let Some(suspension) = self.suspensions.get_mut(&handle) {
+            suspension.local = None;
+        }
+        self.send_control(handle, ControlPacket::Resume)
+    }
+
+    /// Starts the compression handshake of a new connection
+    pub(crate) fn advertise_compression(&mut self, handle: ConnectionHandle) {
+        let advertisement = match self
+            .connections
+            .get(&handle)
+            .and_then(|connection| connection.compressor())
+            .and_then(|compressor| compressor.advertisement())
+        {
+            Some(advertisement) => advertisement,
+            None => return,
+        };
+        if let Err(err) = self.send_control(handle, advertisement) {
+            debug!("Can't advertise compression to [{}]: {}", handle, err);
+        }
+    }
+
+    /// Tells the peer we are closing the connection. Sent only once - a duplicate arriving after
+    /// the server dropped the connection would look like a new connection. If it gets lost,
+    /// the peer idle-disconnects as usual.
+    pub(crate) fn notify_disconnect(&mut self, handle: ConnectionHandle) {
+        let sequence = next_control_sequence(&mut self.control_sequence);
+        if let Some(connection) = self.connections.get_mut(&handle) {
+            if let Err(err) = connection.send(ControlPacket::Disconnect.encode(sequence)) {
+                debug!("Can't notify [{}] about disconnect: {}", handle, err);
+            }
+            // the connection is about to go, don't leave the notice to the link conditioner
+            connection.flush_link();
+        }
+    }
+
+    /// Whether the connection was suspended by either end
+    pub fn is_suspended(&self, handle: ConnectionHandle) -> bool {
+        self.suspensions
+            .get(&handle)
+            .and_then(Suspension::timeout_ms)
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for control in [
+            ControlPacket::Suspend { timeout_ms: 5000 },
+            ControlPacket::Resume,
+            ControlPacket::Disconnect,
+            ControlPacket::Connect,
+            ControlPacket::Accept,
+            ControlPacket::Compression {
+                accepts: 3,
+                dictionary: 0xdead_beef,
+                acknowledged: true,
+            },
+        ] {
+            let packet = control.encode(7);
+            assert!(packet.starts_with(&PLUGIN_PACKET_PREFIX));
+            assert_eq!(ControlPacket::decode(&packet), Some((7, control)));
+        }
+        assert_eq!(ControlPacket::decode(&CONTROL_MAGIC), None);
+        assert_eq!(ControlPacket::decode(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn stale_notices_are_ignored() {
+        let mut suspension = Suspension::default();
+        assert!(suspension.remote(1, Some(5000)));
+        // repeats of the notice
+        assert!(!suspension.remote(1, Some(5000)));
+        assert!(suspension.remote(2, None));
+        // suspend overtaken by the resume
+        assert!(!suspension.remote(1, Some(5000)));
+        assert_eq!(suspension.timeout_ms(), None);
+
+        // just before sequence 0, long ago
+        assert!(!suspension.remote(u32::MAX, Some(100)));
+        let mut suspension = Suspension {
+            remote_sequence: Some(u32::MAX),
+            ..Default::default()
+        };
+        // sequence wrapped around
+        assert!(suspension.remote(0, Some(100)));
+    }
+
+    #[test]
+    fn ends_lift_their_own_suspension() {
+        let mut suspension = Suspension {
+            local: Some(1000),
+            ..Default::default()
+        };
+        assert!(suspension.remote(1, Some(3000)));
+        assert_eq!(suspension.timeout_ms(), Some(3000));
+
+        // peer resuming leaves our suspension in place
+        assert!(suspension.remote(2, None));
+        assert_eq!(suspension.timeout_ms(), Some(1000));
+
+        assert!(suspension.remote(3, Some(3000)));
+        suspension.local = None;
+        assert_eq!(suspension.timeout_ms(), Some(3000));
+    }
+}
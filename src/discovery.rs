@@ -0,0 +1,390 @@
+use bevy::{
+    app::{App, Events, Plugin},
+    prelude::*,
+};
+use instant::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+/// Port servers announce on and clients listen on, unless configured otherwise
+pub const DEFAULT_DISCOVERY_PORT: u16 = 14190;
+/// Site-local multicast group, for networks which drop broadcasts
+pub const DEFAULT_DISCOVERY_MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 255, 55, 190);
+
+const DISCOVERY_MAGIC: &[u8; 4] = b"BNTD";
+/// Announcements larger than this are not sent, so they fit a single unfragmented datagram
+const MAX_ANNOUNCEMENT_LEN: usize = 1200;
+const MAX_DATAGRAM_LEN: usize = DISCOVERY_MAGIC.len() + MAX_ANNOUNCEMENT_LEN;
+/// Most datagrams read per frame
+const RECV_BATCH: usize = 64;
+
+/// LAN server discovery, independent of `NetworkingPlugin`.
+///
+/// Servers call `Discovery::announce`, clients read the `DiscoveredServers` resource
+/// or `DiscoveryEvent`s. Every process sharing the discovery port sees every announcement.
+pub struct DiscoveryPlugin {
+    pub port: u16,
+    /// Also announce to and listen on this multicast group
+    pub multicast: Option<Ipv4Addr>,
+    pub announce_interval_ms: u64,
+    /// Servers which haven't announced for this long are dropped
+    pub expiry_ms: u64,
+}
+
+impl Default for DiscoveryPlugin {
+    fn default() -> Self {
+        DiscoveryPlugin {
+            port: DEFAULT_DISCOVERY_PORT,
+            multicast: Some(DEFAULT_DISCOVERY_MULTICAST),
+            announce_interval_ms: 1000,
+            expiry_ms: 5000,
+        }
+    }
+}
+
+impl Plugin for DiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        let socket = match bind_discovery_socket(self.port, self.multicast) {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                error!("Discovery can't bind port {}: {}", self.port, err);
+                None
+            }
+        };
+        app.insert_resource(Discovery {
+            socket,
+            port: self.port,
+            multicast: self.multicast,
+            announcement: None,
+            announce_interval: Duration::from_millis(self.announce_interval_ms),
+            last_announce: None,
+            // one byte extra to tell oversized datagrams from ones that just fit
+            buffer: vec![0; MAX_DATAGRAM_LEN + 1],
+        })
+        .insert_resource(DiscoveredServers {
+            servers: HashMap::new(),
+            expiry: Duration::from_millis(self.expiry_ms),
+        })
+        .add_event::<DiscoveryEvent>()
+        .add_system(announce_server.system())
+        .add_system(discover_servers.system());
+    }
+}
+
+fn bind_discovery_socket(port: u16, multicast: Option<Ipv4Addr>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // servers and clients on the same machine share the port
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    let socket = UdpSocket::from(socket);
+    socket.set_broadcast(true)?;
+    if let Some(group) = multicast {
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// What a server tells the LAN about itself
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerAnnouncement {
+    pub name: String,
+    pub players: u32,
+    /// Port the server listens on, clients connect to the announcing host at this port
+    pub port: u16,
+    /// Application data, eg. map name or game mode
+    pub payload: Vec<u8>,
+}
+
+/// Discovery socket, and the announcement of this process if it is a server
+pub struct Discovery {
+    socket: Option<UdpSocket>,
+    port: u16,
+    multicast: Option<Ipv4Addr>,
+    announcement: Option<Vec<u8>>,
+    announce_interval: Duration,
+    last_announce: Option<Instant>,
+    buffer: Vec<u8>,
+}
+
+impl Discovery {
+    /// Starts announcing periodically, replacing the previous announcement.
+    /// Call it again whenever the player count or payload changes.
+    pub fn announce(&mut self, announcement: &ServerAnnouncement) {
+        let mut datagram = DISCOVERY_MAGIC.to_vec();
+        bincode::serialize_into(&mut datagram, announcement)
+            .expect("announcement serializes into a Vec");
+        if datagram.len() > MAX_DATAGRAM_LEN {
+            error!(
+                "Discovery announcement of {} bytes exceeds {} bytes, not announcing",
+                datagram.len() - DISCOVERY_MAGIC.len(),
+                MAX_ANNOUNCEMENT_LEN
+            );
+            self.announcement = None;
+            return;
+        }
+        // changes go out right away
+        if self.announcement.as_ref() != Some(&datagram) {
+            self.last_announce = None;
+        }
+        self.announcement = Some(datagram);
+    }
+
+    pub fn stop_announcing(&mut self) {
+        self.announcement = None;
+    }
+
+    pub fn is_announcing(&self) -> bool {
+        self.announcement.is_some()
+    }
+}
+
+/// Announcement in a discovery datagram, None if it isn't a well-formed one
+fn parse_announcement(datagram: &[u8]) -> Option<ServerAnnouncement> {
+    if datagram.len() > MAX_DATAGRAM_LEN {
+        return None;
+    }
+    let encoded = datagram.strip_prefix(DISCOVERY_MAGIC)?;
+    let announcement: ServerAnnouncement = bincode::deserialize(encoded).ok()?;
+    // trailing garbage
+    if bincode::serialized_size(&announcement).ok()? != encoded.len() as u64 {
+        return None;
+    }
+    Some(announcement)
+}
+
+/// Server seen on the LAN
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// Address to connect to: the announcing host and the announced port
+    pub address: SocketAddr,
+    pub announcement: ServerAnnouncement,
+    pub last_seen: Instant,
+}
+
+/// Servers which announced recently, keyed by `DiscoveredServer::address`
+#[derive(Debug)]
+pub struct DiscoveredServers {
+    servers: HashMap<SocketAddr, DiscoveredServer>,
+    expiry: Duration,
+}
+
+impl DiscoveredServers {
+    pub fn get(&self, address: SocketAddr) -> Option<&DiscoveredServer> {
+        self.servers.get(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DiscoveredServer> {
+        self.servers.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    /// Records an announcement of `sender`, returning `Found` or `Updated` if it is news
+    fn observe(
+        &mut self,
+        sender: SocketAddr,
+        announcement: ServerAnnouncement,
+        now: Instant,
+    ) -> Option<DiscoveryEvent> {
+        let server = DiscoveredServer {
+            address: SocketAddr::new(sender.ip(), announcement.port),
+            announcement,
+            last_seen: now,
+        };
+        match self.servers.insert(server.address, server.clone()) {
+            None => Some(DiscoveryEvent::Found(server)),
+            Some(previous) if previous.announcement != server.announcement => {
+                Some(DiscoveryEvent::Updated(server))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Drops servers which stopped announcing, returning their addresses
+    fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let expiry = self.expiry;
+        let mut lost = Vec::new();
+        self.servers.retain(|address, server| {
+            let alive = now.duration_since(server.last_seen) < expiry;
+            if !alive {
+                lost.push(*address);
+            }
+            alive
+        });
+        lost
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    Found(DiscoveredServer),
+    /// announcement of a known server changed
+    Updated(DiscoveredServer),
+    /// server stopped announcing, see `DiscoveryPlugin::expiry_ms`
+    Lost(SocketAddr),
+}
+
+pub fn announce_server(mut discovery: ResMut<Discovery>) {
+    let discovery = &mut *discovery;
+    let (socket, announcement) = match (&discovery.socket, &discovery.announcement) {
+        (Some(socket), Some(announcement)) => (socket, announcement),
+        _ => return,
+    };
+    let now = Instant::now();
+    if matches!(discovery.last_announce, Some(last) if now - last < discovery.announce_interval) {
+        return;
+    }
+    discovery.last_announce = Some(now);
+
+    let mut targets = vec![Ipv4Addr::BROADCAST];
+    targets.extend(discovery.multicast);
+    for target in targets {
+        match socket.send_to(announcement, (target, discovery.port)) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => debug!("Discovery announce to {} failed: {}", target, err),
+        }
+    }
+}
+
+pub fn discover_servers(
+    mut discovery: ResMut<Discovery>,
+    mut servers: ResMut<DiscoveredServers>,
+    mut events: ResMut<Events<DiscoveryEvent>>,
+) {
+    let discovery = &mut *discovery;
+    let now = Instant::now();
+    if let Some(socket) = &discovery.socket {
+        for _ in 0..RECV_BATCH {
+            let (len, sender) = match socket.recv_from(&mut discovery.buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    debug!("Discovery receive error: {}", err);
+                    continue;
+                }
+            };
+            let datagram = &discovery.buffer[..len];
+            // other traffic on the port
+            if !datagram.starts_with(DISCOVERY_MAGIC) {
+                continue;
+            }
+            let announcement = match parse_announcement(datagram) {
+                Some(announcement) => announcement,
+                None => {
+                    debug!("Malformed discovery announcement from {}", sender);
+                    continue;
+                }
+            };
+            if let Some(event) = servers.observe(sender, announcement, now) {
+                events.send(event);
+            }
+        }
+    }
+
+    for address in servers.expire(now) {
+        events.send(DiscoveryEvent::Lost(address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(players: u32) -> ServerAnnouncement {
+        ServerAnnouncement {
+            name: "lan party".to_string(),
+            players,
+            port: 7000,
+            payload: b"de_dust".to_vec(),
+        }
+    }
+
+    fn datagram(announcement: &ServerAnnouncement) -> Vec<u8> {
+        let mut datagram = DISCOVERY_MAGIC.to_vec();
+        bincode::serialize_into(&mut datagram, announcement).unwrap();
+        datagram
+    }
+
+    fn servers() -> DiscoveredServers {
+        DiscoveredServers {
+            servers: HashMap::new(),
+            expiry: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn announcements_parse() {
+        let announcement = announcement(3);
+        assert_eq!(
+            parse_announcement(&datagram(&announcement)),
+            Some(announcement)
+        );
+    }
+
+    #[test]
+    fn malformed_datagrams_are_ignored() {
+        let valid = datagram(&announcement(3));
+        let mut wrong_magic = valid.clone();
+        wrong_magic[0] = b'X';
+        let mut trailing = valid.clone();
+        trailing.push(0);
+        for datagram in [
+            &[][..],
+            &DISCOVERY_MAGIC[..],
+            &wrong_magic,
+            &valid[..valid.len() - 1],
+            &trailing,
+        ] {
+            assert_eq!(parse_announcement(datagram), None);
+        }
+
+        let oversized = ServerAnnouncement {
+            payload: vec![0; MAX_ANNOUNCEMENT_LEN],
+            ..announcement(3)
+        };
+        assert_eq!(parse_announcement(&datagram(&oversized)), None);
+    }
+
+    #[test]
+    fn servers_are_found_updated_and_lost() {
+        let mut servers = servers();
+        let now = Instant::now();
+        let sender: SocketAddr = "192.168.1.20:14190".parse().unwrap();
+        let address: SocketAddr = "192.168.1.20:7000".parse().unwrap();
+
+        assert!(matches!(
+            servers.observe(sender, announcement(1), now),
+            Some(DiscoveryEvent::Found(server)) if server.address == address
+        ));
+        // repeats are no news
+        assert!(servers.observe(sender, announcement(1), now).is_none());
+        assert!(matches!(
+            servers.observe(sender, announcement(2), now),
+            Some(DiscoveryEvent::Updated(server)) if server.announcement.players == 2
+        ));
+        assert_eq!(servers.len(), 1);
+
+        // announcements keep it alive
+        let later = now + Duration::from_secs(4);
+        servers.observe(sender, announcement(2), later);
+        assert!(servers.expire(now + Duration::from_secs(6)).is_empty());
+        assert_eq!(servers.expire(later + Duration::from_secs(5)), [address]);
+        assert!(servers.is_empty());
+    }
+}
@@ -0,0 +1,515 @@
+use std::{collections::HashMap, fmt, fmt::Write, ops::Range};
+
+use turbulence::message_channels::{MessageChannelMode, MessageChannelSettings};
+
+/// Formats bytes as a classic `offset  hex  |ascii|` dump, 16 bytes per line.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 4 + 16);
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for column in 0..16 {
+            if column == 8 {
+                out.push(' ');
+            }
+            match chunk.get(column) {
+                Some(byte) => {
+                    let _ = write!(out, " {:02x}", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    Reliable,
+    Unreliable,
+    /// reliable stream of (possibly compressed) chunks of messages
+    Compressed,
+}
+
+/// Decoded structure of a single packet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// empty packet
+    Heartbeat,
+    /// packet of a connection without channels
+    Raw,
+    /// multiplexer channel without known settings
+    UnknownChannel,
+    /// messages of an unreliable channel, as byte ranges of the packet
+    Unreliable {
+        messages: Vec<Range<usize>>,
+    },
+    /// reliable channel data - stream position of the carried data and message boundaries
+    /// found in it (assuming it starts at a message boundary). For compressed channels
+    /// these are the chunks, which may contain several messages.
+    Reliable {
+        position: u32,
+        data: Range<usize>,
+        messages: Vec<Range<usize>>,
+    },
+    /// reliable channel acknowledgment of `len` bytes at stream `position`,
+    /// with the end of the receive window of the acknowledging side
+    ReliableAck {
+        position: u32,
+        len: u16,
+        window_end: u32,
+    },
+    Malformed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DissectedPacket {
+    pub len: usize,
+    /// turbulence multiplexer channel
+    pub channel: Option<u8>,
+    pub frame: Frame,
+}
+
+impl fmt::Display for DissectedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes", self.len)?;
+        if let Some(channel) = self.channel {
+            write!(f, " channel {}", channel)?;
+        }
+        match &self.frame {
+            Frame::Heartbeat => write!(f, " heartbeat"),
+            Frame::Raw => write!(f, " raw"),
+            Frame::UnknownChannel => write!(f, " unknown channel"),
+            Frame::Unreliable { messages } => {
+                write!(f, " unreliable, {} message(s)", messages.len())?;
+                for message in messages {
+                    write!(f, " [{}..{}]", message.start, message.end)?;
+                }
+                Ok(())
+            }
+            Frame::Reliable {
+                position,
+                data,
+                messages,
+            } => {
+                write!(
+                    f,
+                    " reliable pos={} data={}..{}, {} message(s)",
+                    position,
+                    data.start,
+                    data.end,
+                    messages.len()
+                )?;
+                for message in messages {
+                    write!(f, " [{}..{}]", message.start, message.end)?;
+                }
+                Ok(())
+            }
+            Frame::ReliableAck {
+                position,
+                len,
+                window_end,
+            } => write!(
+                f,
+                " reliable ack pos={} len={} window_end={}",
+                position, len, window_end
+            ),
+            Frame::Malformed(reason) => write!(f, " malformed: {}", reason),
+        }
+    }
+}
+
+/// `[len: i16][position: u32]` in front of reliable stream data
+const RELIABLE_HEADER_LEN: usize = 6;
+/// `[-len: i16][position: u32][window_end: u32]`
+const RELIABLE_ACK_LEN: usize = 10;
+/// `[compressed: u8][len - 1: u16]` in front of compressed channel chunks
+const CHUNK_HEADER_LEN: usize = 3;
+
+/// Splits `u16` length prefixed messages in `packet[range]`, returns the message ranges
+/// (without prefixes) and whether the whole range was consumed.
+fn split_messages(packet: &[u8], range: Range<usize>) -> (Vec<Range<usize>>, bool) {
+    let mut messages = Vec::new();
+    let mut offset = range.start;
+    while offset + 2 <= range.end {
+        let len = u16::from_le_bytes([packet[offset], packet[offset + 1]]) as usize;
+        let start = offset + 2;
+        if start + len > range.end {
+            return (messages, false);
+        }
+        messages.push(start..start + len);
+        offset = start + len;
+    }
+    (messages, offset == range.end)
+}
+
+/// Splits compressed channel chunks in `packet[range]`, returns the chunk ranges
+/// (without headers)
+fn split_chunks(packet: &[u8], range: Range<usize>) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut offset = range.start;
+    while offset + CHUNK_HEADER_LEN <= range.end {
+        let len = u16::from_le_bytes([packet[offset + 1], packet[offset + 2]]) as usize + 1;
+        let start = offset + CHUNK_HEADER_LEN;
+        if start + len > range.end {
+            break;
+        }
+        chunks.push(start..start + len);
+        offset = start + len;
+    }
+    chunks
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Reliable channel packet following the multiplexer channel byte
+fn dissect_reliable(packet: &[u8], kind: ChannelKind) -> Frame {
+    let body = &packet[1..];
+    if body.len() < 2 {
+        return Frame::Malformed("truncated reliable header".to_string());
+    }
+    let len = i16::from_le_bytes([body[0], body[1]]);
+    if len < 0 {
+        if body.len() != RELIABLE_ACK_LEN {
+            return Frame::Malformed(format!("reliable ack of {} bytes", body.len()));
+        }
+        return Frame::ReliableAck {
+            position: read_u32(&body[2..6]),
+            len: len.unsigned_abs(),
+            window_end: read_u32(&body[6..10]),
+        };
+    }
+    if body.len() < RELIABLE_HEADER_LEN {
+        return Frame::Malformed("truncated reliable header".to_string());
+    }
+    if len as usize != body.len() - RELIABLE_HEADER_LEN {
+        return Frame::Malformed(format!(
+            "reliable data length {}, but {} bytes follow",
+            len,
+            body.len() - RELIABLE_HEADER_LEN
+        ));
+    }
+    let data = 1 + RELIABLE_HEADER_LEN..packet.len();
+    // a message may continue in the next packet, so leftovers are expected
+    let messages = match kind {
+        ChannelKind::Compressed => split_chunks(packet, data.clone()),
+        _ => split_messages(packet, data.clone()).0,
+    };
+    Frame::Reliable {
+        position: read_u32(&body[2..6]),
+        data,
+        messages,
+    }
+}
+
+/// Decodes packets exchanged over turbulence channels, following the turbulence 0.3 wire
+/// layout: multiplexer channel byte, then for unreliable channels a sequence of `u16` length
+/// prefixed messages. Reliable channels send either `[len: i16][position: u32]` followed by
+/// a segment of the message stream, or 10 byte acknowledgments
+/// `[-len: i16][position: u32][window_end: u32]`, told apart by the negative length.
+/// The stream of reliable channels is made of length prefixed messages, the one of
+/// compressed channels of `[compressed: u8][len - 1: u16]` prefixed chunks.
+///
+/// Decoding is best-effort, meant for debugging and logging only.
+#[derive(Debug, Clone, Default)]
+pub struct PacketDissector {
+    channels: HashMap<u8, ChannelKind>,
+}
+
+impl PacketDissector {
+    /// Create a dissector knowing the channels of your `ConnectionChannelsBuilder`.
+    pub fn new(settings: &[MessageChannelSettings]) -> Self {
+        let mut dissector = PacketDissector::default();
+        for settings in settings {
+            dissector.add_channel(settings);
+        }
+        dissector
+    }
+
+    pub fn add_channel(&mut self, settings: &MessageChannelSettings) {
+        let kind = match settings.channel_mode {
+            MessageChannelMode::Unreliable => ChannelKind::Unreliable,
+            MessageChannelMode::Reliable { .. } => ChannelKind::Reliable,
+            MessageChannelMode::Compressed { .. } => ChannelKind::Compressed,
+        };
+        self.channels.insert(settings.channel, kind);
+    }
+
+    /// Decodes a packet of a connection with channels
+    pub fn dissect(&self, packet: &[u8]) -> DissectedPacket {
+        let channel = match packet.first() {
+            Some(channel) => *channel,
+            None => {
+                return DissectedPacket {
+                    len: 0,
+                    channel: None,
+                    frame: Frame::Heartbeat,
+                }
+            }
+        };
+        let frame = match self.channels.get(&channel) {
+            None => Frame::UnknownChannel,
+            Some(ChannelKind::Unreliable) => match split_messages(packet, 1..packet.len()) {
+                (messages, true) => Frame::Unreliable { messages },
+                (_, false) => Frame::Malformed("truncated unreliable message".to_string()),
+            },
+            Some(kind) => dissect_reliable(packet, *kind),
+        };
+        DissectedPacket {
+            len: packet.len(),
+            channel: Some(channel),
+            frame,
+        }
+    }
+}
+
+/// How packet payloads are printed in debug logs
+#[derive(Debug, Clone, Default)]
+pub enum LogFormat {
+    /// payload as (lossy) UTF-8 text - fine for text based raw packets
+    Utf8Lossy,
+    /// hex and ASCII dump of the payload
+    #[default]
+    Hexdump,
+    /// decoded turbulence channel structure, followed by a hexdump
+    Decoded(PacketDissector),
+}
+
+impl LogFormat {
+    /// Formats `packet` for logging. `has_channels` tells whether the packet belongs
+    /// to a connection with turbulence channels.
+    pub fn format(&self, packet: &[u8], has_channels: bool) -> String {
+        match self {
+            LogFormat::Utf8Lossy => String::from_utf8_lossy(packet).into_owned(),
+            LogFormat::Hexdump => format!("\n{}", hexdump(packet)),
+            LogFormat::Decoded(dissector) => {
+                let dissected = if has_channels {
+                    dissector.dissect(packet)
+                } else {
+                    DissectedPacket {
+                        len: packet.len(),
+                        channel: None,
+                        frame: Frame::Raw,
+                    }
+                };
+                format!("{}\n{}", dissected, hexdump(packet))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels::{SimpleBufferPool, TaskPoolRuntime},
+        transport::{ConnectionChannelsBuilder, MultiplexedPacket},
+    };
+    use bevy::tasks::TaskPool;
+    use futures_lite::{future, StreamExt};
+    use futures_timer::Delay;
+    use std::time::Duration;
+    use turbulence::{
+        buffer::BufferPacketPool,
+        message_channels::MessageChannels,
+        packet::{Packet as PoolPacket, PacketPool, MAX_PACKET_LEN},
+        packet_multiplexer::{
+            IncomingMultiplexedPackets, MuxPacketPool, OutgoingMultiplexedPackets,
+            PacketMultiplexer,
+        },
+        reliable_channel::Settings as ReliableChannelSettings,
+    };
+
+    const RELIABILITY: ReliableChannelSettings = ReliableChannelSettings {
+        bandwidth: 4096,
+        recv_window_size: 1024,
+        send_window_size: 1024,
+        burst_bandwidth: 1024,
+        init_send: 512,
+        wakeup_time: Duration::from_millis(100),
+        initial_rtt: Duration::from_millis(200),
+        max_rtt: Duration::from_secs(2),
+        rtt_update_factor: 0.1,
+        rtt_resend_factor: 1.5,
+    };
+
+    const UNRELIABLE: MessageChannelSettings = MessageChannelSettings {
+        channel: 0,
+        channel_mode: MessageChannelMode::Unreliable,
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
+    const RELIABLE: MessageChannelSettings = MessageChannelSettings {
+        channel: 1,
+        channel_mode: MessageChannelMode::Reliable {
+            reliability_settings: RELIABILITY,
+            max_message_len: 1024,
+        },
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
+    const COMPRESSED: MessageChannelSettings = MessageChannelSettings {
+        channel: 2,
+        channel_mode: MessageChannelMode::Compressed {
+            reliability_settings: RELIABILITY,
+            max_chunk_len: 1024,
+        },
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    };
+
+    type Pool = MuxPacketPool<BufferPacketPool<SimpleBufferPool>>;
+
+    /// One end of a turbulence connection, its packets are passed around by hand
+    struct Peer {
+        pool: Pool,
+        channels: MessageChannels,
+        incoming: IncomingMultiplexedPackets<MultiplexedPacket>,
+        outgoing: OutgoingMultiplexedPackets<MultiplexedPacket>,
+    }
+
+    impl Peer {
+        fn new(task_pool: &TaskPool) -> Self {
+            let pool = MuxPacketPool::new(BufferPacketPool::new(SimpleBufferPool(MAX_PACKET_LEN)));
+            let mut builder = ConnectionChannelsBuilder::new(
+                TaskPoolRuntime::new(task_pool.clone()),
+                pool.clone(),
+            );
+            builder.register::<u32>(UNRELIABLE).unwrap();
+            builder.register::<String>(RELIABLE).unwrap();
+            builder.register::<Vec<u8>>(COMPRESSED).unwrap();
+            let mut multiplexer = PacketMultiplexer::new();
+            let (channels, _) = builder.build(&mut multiplexer);
+            let (incoming, outgoing) = multiplexer.start();
+            Peer {
+                pool,
+                channels,
+                incoming,
+                outgoing,
+            }
+        }
+
+        /// Next packet turbulence sends
+        fn sent(&mut self) -> Vec<u8> {
+            future::block_on(future::or(
+                async { self.outgoing.next().await.map(|packet| packet.to_vec()) },
+                async {
+                    Delay::new(Duration::from_secs(5)).await;
+                    None
+                },
+            ))
+            .expect("nothing sent")
+        }
+
+        fn receive(&mut self, packet: &[u8]) {
+            let mut pool_packet = self.pool.acquire();
+            pool_packet.resize(packet.len(), 0);
+            pool_packet[..].copy_from_slice(packet);
+            self.incoming.try_send(pool_packet).unwrap();
+        }
+    }
+
+    fn dissector() -> PacketDissector {
+        PacketDissector::new(&[UNRELIABLE, RELIABLE, COMPRESSED])
+    }
+
+    #[test]
+    fn unreliable_messages() {
+        let task_pool = TaskPool::new();
+        let mut peer = Peer::new(&task_pool);
+        // bincode varint encoding, a byte each
+        peer.channels.send(1u32);
+        peer.channels.send(2u32);
+        peer.channels.flush::<u32>();
+
+        let packet = peer.sent();
+        let dissected = dissector().dissect(&packet);
+        assert_eq!(dissected.channel, Some(0));
+        assert_eq!(
+            dissected.frame,
+            Frame::Unreliable {
+                messages: vec![3..4, 6..7]
+            }
+        );
+    }
+
+    #[test]
+    fn reliable_data_and_ack() {
+        let task_pool = TaskPool::new();
+        let mut sender = Peer::new(&task_pool);
+        let mut receiver = Peer::new(&task_pool);
+        sender.channels.send("hello".to_string());
+        sender.channels.flush::<String>();
+
+        let data = sender.sent();
+        // u16 message length, then the bincode string - varint length and bytes
+        assert_eq!(
+            dissector().dissect(&data).frame,
+            Frame::Reliable {
+                position: 0,
+                data: 7..data.len(),
+                messages: std::iter::once(9..data.len()).collect(),
+            }
+        );
+        assert_eq!(data.len(), 9 + 1 + 5);
+
+        receiver.receive(&data);
+        let ack = receiver.sent();
+        assert_eq!(ack.len(), 1 + RELIABLE_ACK_LEN);
+        assert_eq!(
+            dissector().dissect(&ack).frame,
+            Frame::ReliableAck {
+                position: 0,
+                len: (data.len() - 7) as u16,
+                // the message wasn't read yet, so the window didn't move
+                window_end: RELIABILITY.recv_window_size,
+            }
+        );
+    }
+
+    #[test]
+    fn compressed_chunks() {
+        let task_pool = TaskPool::new();
+        let mut peer = Peer::new(&task_pool);
+        peer.channels.send(vec![7u8; 100]);
+        peer.channels.flush::<Vec<u8>>();
+
+        let packet = peer.sent();
+        match dissector().dissect(&packet).frame {
+            Frame::Reliable {
+                position: 0,
+                data,
+                messages,
+            } => {
+                assert_eq!(data, 7..packet.len());
+                assert_eq!(messages, vec![10..packet.len()]);
+            }
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn malformed_reliable() {
+        let dissector = dissector();
+        // ack of the wrong size
+        let frame = dissector.dissect(&[1, 0xfb, 0xff, 0, 0, 0, 0]).frame;
+        assert!(matches!(frame, Frame::Malformed(_)));
+        // data length beyond the packet
+        let frame = dissector.dissect(&[1, 9, 0, 0, 0, 0, 0, 1]).frame;
+        assert!(matches!(frame, Frame::Malformed(_)));
+        assert_eq!(dissector.dissect(&[9, 1]).frame, Frame::UnknownChannel);
+        assert_eq!(dissector.dissect(&[]).frame, Frame::Heartbeat);
+    }
+}
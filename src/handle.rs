@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Identifies a connection of a `NetworkResource`.
+///
+/// Slots of closed connections get reused, with a bumped generation, so a handle kept around
+/// after its connection went away never refers to a newer connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ConnectionHandle {
+    index: u32,
+    generation: u32,
+}
+
+impl ConnectionHandle {
+    pub(crate) fn new(index: u32, generation: u32) -> Self {
+        ConnectionHandle { index, generation }
+    }
+
+    /// Slot of the connection, reused after it closes
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// How many connections used this slot before
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// Migration path for code which kept handles as `u32`: the first connection of the slot.
+/// Handles of later connections in the slot are stale to it, so it's rejected rather than
+/// reaching a newer connection.
+impl From<u32> for ConnectionHandle {
+    fn from(index: u32) -> Self {
+        ConnectionHandle::new(index, 0)
+    }
+}
+
+impl fmt::Display for ConnectionHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Hands out connection handles, shared with listener tasks
+#[derive(Debug, Default)]
+pub(crate) struct HandleAllocator {
+    /// current generation of every slot
+    generations: Vec<u32>,
+    free: Vec<u32>,
+}
+
+impl HandleAllocator {
+    pub(crate) fn allocate(&mut self) -> ConnectionHandle {
+        match self.free.pop() {
+            Some(index) => ConnectionHandle::new(index, self.generations[index as usize]),
+            None => {
+                self.generations.push(0);
+                ConnectionHandle::new(self.generations.len() as u32 - 1, 0)
+            }
+        }
+    }
+
+    /// Frees the slot of a handle, making it stale. Does nothing if it already is.
+    pub(crate) fn release(&mut self, handle: ConnectionHandle) {
+        if self.is_current(handle) {
+            let generation = &mut self.generations[handle.index as usize];
+            *generation = generation.wrapping_add(1);
+            self.free.push(handle.index);
+        }
+    }
+
+    pub(crate) fn is_current(&self, handle: ConnectionHandle) -> bool {
+        self.generations.get(handle.index as usize) == Some(&handle.generation)
+    }
+
+    /// Handle refers to a connection which went away
+    pub(crate) fn is_stale(&self, handle: ConnectionHandle) -> bool {
+        matches!(self.generations.get(handle.index as usize), Some(generation) if *generation != handle.generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_slots_come_back_with_a_new_generation() {
+        let mut handles = HandleAllocator::default();
+        let first = handles.allocate();
+        let second = handles.allocate();
+        assert_eq!((first.index(), first.generation()), (0, 0));
+        assert_eq!((second.index(), second.generation()), (1, 0));
+
+        handles.release(first);
+        assert!(!handles.is_current(first));
+        assert!(handles.is_stale(first));
+        assert!(handles.is_current(second));
+
+        let reused = handles.allocate();
+        assert_eq!((reused.index(), reused.generation()), (0, 1));
+        assert_ne!(reused, first);
+        // still stale, the slot belongs to the new handle
+        assert!(handles.is_stale(first));
+        assert!(handles.is_current(reused));
+    }
+
+    #[test]
+    fn releasing_twice_does_nothing() {
+        let mut handles = HandleAllocator::default();
+        let handle = handles.allocate();
+        handles.release(handle);
+        let reused = handles.allocate();
+        handles.release(handle);
+        assert!(handles.is_current(reused));
+        assert_eq!(handles.allocate().index(), 1);
+    }
+
+    #[test]
+    fn bare_indices_are_first_generation_handles() {
+        let mut handles = HandleAllocator::default();
+        let handle = handles.allocate();
+        assert_eq!(ConnectionHandle::from(0), handle);
+        handles.release(handle);
+        handles.allocate();
+        assert!(handles.is_stale(0.into()));
+    }
+
+    #[test]
+    fn unknown_handles_are_neither_current_nor_stale() {
+        let handles = HandleAllocator::default();
+        let unknown = ConnectionHandle::new(3, 0);
+        assert!(!handles.is_current(unknown));
+        assert!(!handles.is_stale(unknown));
+    }
+}
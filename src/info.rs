@@ -0,0 +1,53 @@
+use instant::Instant;
+use std::{collections::HashMap, net::SocketAddr};
+
+use super::{
+    transport::{Connection, TransportKind},
+    ConnectionHandle, ListenerId, NetworkResource,
+};
+
+/// Details of an established connection, see `NetworkResource::connection_info`
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Peer address - the server for outgoing connections, None for local and replayed ones
+    pub address: Option<SocketAddr>,
+    /// Listener the connection arrived on, None for outgoing connections
+    pub listener: Option<ListenerId>,
+    /// When `NetworkEvent::Connected` was sent
+    pub connected_at: Instant,
+    pub transport: TransportKind,
+    /// Free-form data attached by the application, eg. player name or session id
+    pub metadata: HashMap<String, String>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(connection: &dyn Connection) -> Self {
+        ConnectionInfo {
+            address: connection.remote_address(),
+            listener: connection.listener(),
+            connected_at: Instant::now(),
+            transport: connection.transport(),
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// Details of a connection, None if the handle isn't connected (yet), see `is_connected`
+    pub fn connection_info(&self, handle: ConnectionHandle) -> Option<&ConnectionInfo> {
+        if self.connects.is_connecting(handle) {
+            return None;
+        }
+        self.connection_info.get(&handle)
+    }
+
+    /// Application data attached to a connection, dropped on disconnect
+    pub fn connection_metadata_mut(
+        &mut self,
+        handle: ConnectionHandle,
+    ) -> Option<&mut HashMap<String, String>> {
+        self.connection_info
+            .get_mut(&handle)
+            .map(|info| &mut info.metadata)
+    }
+}
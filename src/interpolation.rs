@@ -0,0 +1,321 @@
+use bevy::{
+    ecs::component::{Component, TableStorage},
+    prelude::*,
+};
+use std::{collections::VecDeque, marker::PhantomData};
+
+/// State that can be blended between two received snapshots.
+pub trait Interpolate: Clone + Send + Sync + 'static {
+    /// Blend from `self` towards `other`.
+    /// `t` is in `0.0..=1.0` when interpolating and above `1.0` when extrapolating.
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for Transform {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot<T> {
+    /// Server time (in seconds) at which the state was captured
+    pub time: f64,
+    pub state: T,
+}
+
+/// Jitter buffer of timestamped states received for a single entity.
+///
+/// Push every state received from the server, and `SnapshotInterpolationPlugin`
+/// will render the entity `SnapshotInterpolation::delay` seconds behind the server.
+pub struct SnapshotBuffer<T: Interpolate> {
+    snapshots: VecDeque<Snapshot<T>>,
+    capacity: usize,
+}
+
+impl<T: Interpolate> Component for SnapshotBuffer<T> {
+    type Storage = TableStorage;
+}
+
+impl<T: Interpolate> Default for SnapshotBuffer<T> {
+    fn default() -> Self {
+        Self::with_capacity(32)
+    }
+}
+
+impl<T: Interpolate> SnapshotBuffer<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        SnapshotBuffer {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(2),
+        }
+    }
+
+    /// Stores a received state. Out-of-order and duplicate snapshots are sorted in
+    /// or dropped, so it is safe to feed it straight from an unreliable channel.
+    pub fn push(&mut self, time: f64, state: T) {
+        let index = self
+            .snapshots
+            .partition_point(|snapshot| snapshot.time < time);
+        if let Some(existing) = self.snapshots.get(index) {
+            if existing.time == time {
+                return;
+            }
+        }
+        if index == 0 && self.snapshots.len() == self.capacity {
+            // older than everything we are holding on to
+            return;
+        }
+        self.snapshots.insert(index, Snapshot { time, state });
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn latest(&self) -> Option<&Snapshot<T>> {
+        self.snapshots.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Computes the state at `render_time`.
+    ///
+    /// Between two snapshots the state is interpolated. Past the newest snapshot it is
+    /// extrapolated from the last two, but no further than `max_extrapolation` seconds,
+    /// after which the entity holds still until new data arrives.
+    pub fn sample(&self, render_time: f64, max_extrapolation: f64) -> Option<T> {
+        let index = self
+            .snapshots
+            .partition_point(|snapshot| snapshot.time <= render_time);
+        match (
+            index.checked_sub(1).and_then(|i| self.snapshots.get(i)),
+            self.snapshots.get(index),
+        ) {
+            (Some(from), Some(to)) => {
+                let t = (render_time - from.time) / (to.time - from.time);
+                Some(from.state.interpolate(&to.state, t as f32))
+            }
+            (Some(latest), None) => {
+                let previous = match index.checked_sub(2).and_then(|i| self.snapshots.get(i)) {
+                    Some(previous) => previous,
+                    None => return Some(latest.state.clone()),
+                };
+                let overshoot = (render_time - latest.time).min(max_extrapolation.max(0.0));
+                let t = 1.0 + overshoot / (latest.time - previous.time);
+                Some(previous.state.interpolate(&latest.state, t as f32))
+            }
+            // all snapshots are still in the future - show the oldest one
+            (None, Some(oldest)) => Some(oldest.state.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Drops snapshots that can no longer take part in interpolation at `render_time`.
+    fn discard_before(&mut self, render_time: f64) {
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+/// Settings and server clock estimate shared by all interpolated entities.
+#[derive(Debug, Clone)]
+pub struct SnapshotInterpolation {
+    /// How far behind the estimated server time entities are rendered, in seconds.
+    /// Should cover a couple of snapshot intervals plus expected jitter.
+    pub delay: f64,
+    /// Maximum time (in seconds) to extrapolate past the newest snapshot when packets are lost
+    pub max_extrapolation: f64,
+    /// How quickly the server clock estimate follows new observations (0.0 - 1.0)
+    pub clock_smoothing: f64,
+    clock_offset: Option<f64>,
+}
+
+impl Default for SnapshotInterpolation {
+    fn default() -> Self {
+        SnapshotInterpolation {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+            clock_smoothing: 0.05,
+            clock_offset: None,
+        }
+    }
+}
+
+impl SnapshotInterpolation {
+    /// Feed the server timestamp of every received state update, together with the
+    /// local time (`Time::seconds_since_startup()`) it arrived at.
+    pub fn observe_server_time(&mut self, server_time: f64, local_time: f64) {
+        let offset = server_time - local_time;
+        self.clock_offset = Some(match self.clock_offset {
+            // snap on first sample or when the server clock jumped (i.e. server restart)
+            Some(current) if (offset - current).abs() < 1.0 => {
+                current + (offset - current) * self.clock_smoothing
+            }
+            _ => offset,
+        });
+    }
+
+    /// Estimated current server time, if any server time was observed yet
+    pub fn server_time(&self, local_time: f64) -> Option<f64> {
+        self.clock_offset.map(|offset| local_time + offset)
+    }
+
+    /// Server time at which entities should be rendered now
+    pub fn render_time(&self, local_time: f64) -> Option<f64> {
+        self.server_time(local_time)
+            .map(|server_time| server_time - self.delay)
+    }
+}
+
+pub fn interpolate_snapshots<T: Interpolate + Component>(
+    time: Res<Time>,
+    settings: Res<SnapshotInterpolation>,
+    mut query: Query<(&mut SnapshotBuffer<T>, &mut T)>,
+) {
+    let render_time = match settings.render_time(time.seconds_since_startup()) {
+        Some(render_time) => render_time,
+        None => return,
+    };
+    for (mut buffer, mut state) in query.iter_mut() {
+        if let Some(sampled) = buffer.sample(render_time, settings.max_extrapolation) {
+            *state = sampled;
+        }
+        buffer.discard_before(render_time);
+    }
+}
+
+/// Renders entities with a `SnapshotBuffer<T>` `SnapshotInterpolation::delay` behind the server.
+/// Add it once for every interpolated component type.
+pub struct SnapshotInterpolationPlugin<T = Transform> {
+    state: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for SnapshotInterpolationPlugin<T> {
+    fn default() -> Self {
+        SnapshotInterpolationPlugin { state: PhantomData }
+    }
+}
+
+impl<T: Interpolate + Component> Plugin for SnapshotInterpolationPlugin<T> {
+    fn build(&self, app: &mut App) {
+        // shared by all interpolated types
+        if !app.world.contains_resource::<SnapshotInterpolation>() {
+            app.insert_resource(SnapshotInterpolation::default());
+        }
+        app.add_system(interpolate_snapshots::<T>.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Position(f32);
+
+    impl Interpolate for Position {
+        fn interpolate(&self, other: &Self, t: f32) -> Self {
+            Position(self.0 + (other.0 - self.0) * t)
+        }
+    }
+
+    fn times(buffer: &SnapshotBuffer<Position>) -> Vec<f64> {
+        buffer
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.time)
+            .collect()
+    }
+
+    #[test]
+    fn snapshots_stay_ordered() {
+        let mut buffer = SnapshotBuffer::with_capacity(3);
+        buffer.push(2.0, Position(2.0));
+        buffer.push(1.0, Position(1.0));
+        buffer.push(3.0, Position(3.0));
+        // duplicates keep the first state
+        buffer.push(2.0, Position(20.0));
+        assert_eq!(times(&buffer), [1.0, 2.0, 3.0]);
+        assert_eq!(buffer.snapshots[1].state, Position(2.0));
+
+        // full buffers make room at the old end, and turn away anything older
+        buffer.push(2.5, Position(2.5));
+        assert_eq!(times(&buffer), [2.0, 2.5, 3.0]);
+        buffer.push(0.5, Position(0.5));
+        assert_eq!(times(&buffer), [2.0, 2.5, 3.0]);
+        assert_eq!(buffer.latest().unwrap().time, 3.0);
+    }
+
+    #[test]
+    fn samples_between_snapshots_interpolate() {
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(1.0, 0.0), None);
+        buffer.push(1.0, Position(10.0));
+        buffer.push(2.0, Position(20.0));
+        assert_eq!(buffer.sample(1.0, 0.0), Some(Position(10.0)));
+        assert_eq!(buffer.sample(1.25, 0.0), Some(Position(12.5)));
+        // before the oldest snapshot
+        assert_eq!(buffer.sample(0.0, 0.0), Some(Position(10.0)));
+    }
+
+    #[test]
+    fn extrapolation_is_clamped() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(1.0, Position(10.0));
+        assert_eq!(buffer.sample(5.0, 1.0), Some(Position(10.0)));
+        buffer.push(2.0, Position(20.0));
+        assert_eq!(buffer.sample(2.5, 1.0), Some(Position(25.0)));
+        assert_eq!(buffer.sample(10.0, 1.0), Some(Position(30.0)));
+        assert_eq!(buffer.sample(10.0, 0.0), Some(Position(20.0)));
+    }
+
+    #[test]
+    fn consumed_snapshots_are_discarded() {
+        let mut buffer = SnapshotBuffer::default();
+        for time in 0..5 {
+            buffer.push(time as f64, Position(time as f32));
+        }
+        buffer.discard_before(2.5);
+        assert_eq!(times(&buffer), [2.0, 3.0, 4.0]);
+        // the last two stay for extrapolation
+        buffer.discard_before(10.0);
+        assert_eq!(times(&buffer), [3.0, 4.0]);
+    }
+
+    #[test]
+    fn clock_estimate_converges() {
+        let mut settings = SnapshotInterpolation::default();
+        assert_eq!(settings.server_time(0.0), None);
+        settings.observe_server_time(100.0, 0.0);
+        assert_eq!(settings.server_time(1.0), Some(101.0));
+        assert_eq!(settings.render_time(1.0), Some(101.0 - settings.delay));
+
+        // jittery samples around an offset of 100.2
+        for tick in 0..500 {
+            let jitter = if tick % 2 == 0 { 0.05 } else { -0.05 };
+            let local_time = tick as f64 * 0.05;
+            settings.observe_server_time(local_time + 100.2 + jitter, local_time);
+        }
+        let offset = settings.server_time(0.0).unwrap();
+        assert!((offset - 100.2).abs() < 0.01, "offset {}", offset);
+
+        // a server restart snaps to the new clock
+        settings.observe_server_time(5.0, 30.0);
+        assert_eq!(settings.server_time(30.0), Some(5.0));
+    }
+}
@@ -0,0 +1,61 @@
+use std::{fmt, marker::PhantomData, ops::Deref};
+
+use super::NetworkEvent;
+
+/// Identifies an independent network instance - its `NetworkResource<L>`, `NetworkSettings<L>`,
+/// events and channels - so one app can run several side by side, eg. a game server
+/// which is also a client of a lobby server:
+///
+/// ```ignore
+/// struct Lobby;
+///
+/// impl NetworkLabel for Lobby {
+///     type Event = LabelledNetworkEvent<Lobby>;
+/// }
+///
+/// app.add_plugin(NetworkingPlugin::default())
+///     .add_plugin(NetworkingPlugin::<Lobby>::labelled());
+/// // ...
+/// fn lobby_system(mut net: ResMut<NetworkResource<Lobby>>, mut reader: EventReader<LabelledNetworkEvent<Lobby>>) {}
+/// ```
+pub trait NetworkLabel: Send + Sync + 'static {
+    /// Type of events sent by this instance
+    type Event: From<NetworkEvent> + Send + Sync + 'static;
+}
+
+/// Label of the default network instance, its events are plain `NetworkEvent`s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DefaultNetwork;
+
+impl NetworkLabel for DefaultNetwork {
+    type Event = NetworkEvent;
+}
+
+/// `NetworkEvent` of a labelled network instance
+pub struct LabelledNetworkEvent<L> {
+    pub event: NetworkEvent,
+    label: PhantomData<fn() -> L>,
+}
+
+impl<L> From<NetworkEvent> for LabelledNetworkEvent<L> {
+    fn from(event: NetworkEvent) -> Self {
+        LabelledNetworkEvent {
+            event,
+            label: PhantomData,
+        }
+    }
+}
+
+impl<L> Deref for LabelledNetworkEvent<L> {
+    type Target = NetworkEvent;
+
+    fn deref(&self) -> &NetworkEvent {
+        &self.event
+    }
+}
+
+impl<L> fmt::Debug for LabelledNetworkEvent<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}::{:?}", std::any::type_name::<L>(), self.event)
+    }
+}
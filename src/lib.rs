@@ -1,29 +1,33 @@
 #[cfg(not(target_arch = "wasm32"))]
 use bevy::tasks::Task;
 use bevy::{
-    app::{App, CoreStage, Events, Plugin},
-    core::FixedTimestep,
+    app::{App, AppExit, CoreStage, Events, Plugin},
     prelude::*,
     tasks::{IoTaskPool, TaskPool},
 };
 #[cfg(not(target_arch = "wasm32"))]
-use crossbeam_channel::{unbounded, SendError as CrossbeamSendError, Sender};
+use crossbeam_channel::Sender;
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
+use crossbeam_channel::{unbounded, SendError as CrossbeamSendError};
+#[cfg(not(target_arch = "wasm32"))]
+use instant::Duration;
+use instant::Instant;
 #[cfg(not(target_arch = "wasm32"))]
 use std::sync::RwLock;
 use std::{
     collections::HashMap,
     error::Error,
     fmt::Debug,
+    marker::PhantomData,
     net::SocketAddr,
-    sync::{atomic, Arc, Mutex},
+    sync::{Arc, Mutex},
 };
 
-use naia_client_socket::ClientSocket;
-#[cfg(not(target_arch = "wasm32"))]
-use naia_server_socket::ServerSocket;
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
+use naia_server_socket::{Packet as ServerPacket, ServerSocket};
 
 pub use naia_client_socket::LinkConditionerConfig;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 pub use naia_server_socket::find_my_ip_address;
 
 use turbulence::{
@@ -37,22 +41,137 @@ pub use turbulence::{
     reliable_channel::Settings as ReliableChannelSettings,
 };
 
+mod backend;
+mod bitpack;
+mod capture;
 mod channels;
+mod codec;
+mod compression;
+mod conditioner;
+mod connect;
+mod control;
+#[cfg(all(not(target_arch = "wasm32"), feature = "discovery"))]
+mod discovery;
+mod dissect;
+mod handle;
+mod info;
+mod interpolation;
+mod label;
+mod local;
+#[cfg(not(target_arch = "wasm32"))]
+mod query;
+#[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+mod rendezvous;
+mod rpc;
+mod scheduler;
+mod settings;
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    any(feature = "use-webrtc", feature = "webrtc-client")
+))]
+mod signalling;
+mod transfer;
 mod transport;
+#[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+mod udp;
+#[cfg(all(not(target_arch = "wasm32"), feature = "webrtc-client"))]
+mod webrtc;
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
+use self::compression::PacketCompressor;
 use self::{
     channels::{SimpleBufferPool, TaskPoolRuntime},
+    control::{next_control_sequence, ControlPacket, Suspension},
+    handle::HandleAllocator,
     transport::MultiplexedPacket,
 };
-pub use transport::{Connection, ConnectionChannelsBuilder, Packet};
+pub use backend::{Listener, PacketSink, PacketSource, Transport, TransportLink};
+pub use bitpack::{BitReader, BitWriter};
+pub use capture::{
+    read_capture, CaptureDirection, CaptureRecord, ConnectionCapture, PacketCapture, PacketReplay,
+    ReplayConnection,
+};
+#[cfg(feature = "codec-msgpack")]
+pub use codec::MessagePack;
+#[cfg(feature = "codec-postcard")]
+pub use codec::Postcard;
+pub use codec::{Bincode, Codec, CodecError, Encoded, EncodedChannels};
+pub use compression::Compression;
+pub use conditioner::{
+    ConnectionConditioner, GilbertElliott, LinkConditioner, LinkConditions, LinkProfile,
+};
+pub use connect::{ConnectError, ConnectTarget};
+#[cfg(all(not(target_arch = "wasm32"), feature = "discovery"))]
+pub use discovery::{
+    announce_server, discover_servers, DiscoveredServer, DiscoveredServers, Discovery,
+    DiscoveryEvent, DiscoveryPlugin, ServerAnnouncement, DEFAULT_DISCOVERY_MULTICAST,
+    DEFAULT_DISCOVERY_PORT,
+};
+pub use dissect::{hexdump, ChannelKind, DissectedPacket, Frame, LogFormat, PacketDissector};
+pub use handle::ConnectionHandle;
+pub use info::ConnectionInfo;
+pub use interpolation::{
+    interpolate_snapshots, Interpolate, Snapshot, SnapshotBuffer, SnapshotInterpolation,
+    SnapshotInterpolationPlugin,
+};
+pub use label::{DefaultNetwork, LabelledNetworkEvent, NetworkLabel};
+pub use local::LocalConnection;
+#[cfg(not(target_arch = "wasm32"))]
+pub use query::{QueryHandler, QueryResponder, ServerInfo};
+#[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+pub use rendezvous::RendezvousServer;
+pub use rpc::{process_rpc, RpcCall, RpcError, RpcId, RpcMessage, RpcRequest};
+pub use scheduler::{send_scheduled_messages, MessagePriority, Overflow};
+pub use settings::{ConnectionSettings, NetworkSettings};
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    any(feature = "use-webrtc", feature = "webrtc-client")
+))]
+pub use signalling::{probe_webrtc_session, WebRtcSessionAnswer};
+pub use transfer::{
+    process_transfers, TransferDirection, TransferEvent, TransferId, TransferMessage,
+};
+pub use transport::{Connection, ConnectionChannelsBuilder, Packet, PacketStats, TransportKind};
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    feature = "std-udp",
+    not(feature = "naia-server-socket")
+))]
+pub use udp::find_my_ip_address;
+#[cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+pub use udp::{UdpListener, UdpTransport};
+#[cfg(all(not(target_arch = "wasm32"), feature = "webrtc-client"))]
+pub use webrtc::WebRtcTransport;
+#[cfg(all(not(target_arch = "wasm32"), feature = "websocket"))]
+pub use websocket::WebSocketListener;
+#[cfg(feature = "websocket")]
+pub use websocket::WebSocketTransport;
 
-pub type ConnectionHandle = u32;
+/// Identifies a listening socket, returned by `NetworkResource::listen`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(u32);
+
+impl std::fmt::Display for ListenerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "L{}", self.0)
+    }
+}
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65_507;
+
+/// How long stopped listeners keep running to flush disconnect notices to their peers
+#[cfg(not(target_arch = "wasm32"))]
+const LISTENER_CLOSE_GRACE: Duration = Duration::from_millis(250);
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
 struct SendHeartbeatsStage;
 
-#[derive(Default)]
-pub struct NetworkingPlugin {
-    pub link_conditioner: Option<LinkConditionerConfig>,
+/// Adds a network instance. Add one `NetworkingPlugin<L>` per `NetworkLabel` to run several side by side.
+pub struct NetworkingPlugin<L = DefaultNetwork> {
+    /// Initial default link conditions, adjustable later through `NetworkResource::link_conditioner`.
+    pub link_conditioner: Option<LinkConditions>,
     pub message_flushing_strategy: MessageFlushingStrategy,
     /// Disconnect if no packets received in this number of milliseconds
     pub idle_timeout_ms: Option<usize>,
@@ -60,16 +179,47 @@ pub struct NetworkingPlugin {
     /// these are sent silently, and discarded, so you won't see them in your bevy systems.
     /// if auto_heartbeat_ms elapses, and we haven't sent anything else in that time, we send one.
     pub auto_heartbeat_ms: Option<usize>,
-    /// FixedTimestep for the `heartbeats_and_timeouts` system which checks for idle connections
+    /// Timestep for the `heartbeats_and_timeouts` system which checks for idle connections
     /// and sends heartbeats. Does not need to be every frame.
     ///
-    /// The `heartbeats_and_timeouts` system is only added if `idle_timeout_ms` or `auto_heartbeat_ms` are specified.
+    /// These three are initial values of the `NetworkSettings` resource, which can be changed at runtime.
     ///
     /// Default if None: 0.5 secs
     pub heartbeats_and_timeouts_timestep_in_seconds: Option<f64>,
+    /// Send `NetworkEvent::ConnectFailed` if the server doesn't answer `connect` within
+    /// this number of milliseconds. Default: 10 secs
+    pub connect_timeout_ms: Option<usize>,
+    /// Compress packets of every connection. Packets to peers without compression are sent uncompressed.
+    pub compression: Option<Compression>,
+    /// How packet payloads are printed in debug logs
+    pub log_format: LogFormat,
+    pub label: PhantomData<L>,
+}
+
+impl<L> NetworkingPlugin<L> {
+    /// Default settings for a labelled network instance
+    pub fn labelled() -> Self {
+        NetworkingPlugin {
+            link_conditioner: None,
+            message_flushing_strategy: MessageFlushingStrategy::default(),
+            idle_timeout_ms: None,
+            auto_heartbeat_ms: None,
+            heartbeats_and_timeouts_timestep_in_seconds: None,
+            connect_timeout_ms: Some(connect::DEFAULT_CONNECT_TIMEOUT_MS),
+            compression: None,
+            log_format: LogFormat::default(),
+            label: PhantomData,
+        }
+    }
 }
 
-impl Plugin for NetworkingPlugin {
+impl Default for NetworkingPlugin {
+    fn default() -> Self {
+        Self::labelled()
+    }
+}
+
+impl<L: NetworkLabel> Plugin for NetworkingPlugin<L> {
     fn build(&self, app: &mut App) {
         let task_pool = app
             .world
@@ -78,54 +228,99 @@ impl Plugin for NetworkingPlugin {
             .0
             .clone();
 
-        app.insert_resource(NetworkResource::new(
+        let mut net = NetworkResource::<L>::new(
             task_pool,
             self.link_conditioner.clone(),
             self.message_flushing_strategy,
-            self.idle_timeout_ms,
-            self.auto_heartbeat_ms,
-        ))
-        .add_event::<NetworkEvent>()
-        .add_system(receive_packets.system());
-        if self.idle_timeout_ms.is_some() || self.auto_heartbeat_ms.is_some() {
-            // heartbeats and timeouts checking/sending only runs infrequently:
+            self.compression.clone(),
+        );
+        let mut settings = NetworkSettings::<L>::default();
+        settings.idle_timeout_ms = self.idle_timeout_ms;
+        settings.auto_heartbeat_ms = self.auto_heartbeat_ms;
+        settings.connect_timeout_ms = self.connect_timeout_ms;
+        if let Some(timestep) = self.heartbeats_and_timeouts_timestep_in_seconds {
+            settings.heartbeats_and_timeouts_timestep_in_seconds = timestep;
+        }
+        net.set_log_format(self.log_format.clone());
+
+        app.insert_resource(net)
+            .insert_resource(settings)
+            .add_event::<L::Event>()
+            .add_system(receive_packets::<L>.system())
+            .add_system(process_rpc::<L>.system())
+            .add_system(process_transfers::<L>.system())
+            .add_system_to_stage(CoreStage::PostUpdate, send_scheduled_messages::<L>.system())
+            .add_system_to_stage(CoreStage::Last, shutdown_on_exit::<L>.system());
+
+        if app
+            .schedule
+            .get_stage::<SystemStage>(&SendHeartbeatsStage)
+            .is_none()
+        {
+            // heartbeats and timeouts checking/sending only runs infrequently,
+            // see `NetworkSettings::heartbeats_and_timeouts_timestep_in_seconds`
             app.add_stage_after(
                 CoreStage::Update,
                 SendHeartbeatsStage,
-                SystemStage::parallel()
-                    .with_run_criteria(FixedTimestep::step(
-                        self.heartbeats_and_timeouts_timestep_in_seconds
-                            .unwrap_or(0.5),
-                    ))
-                    .with_system(heartbeats_and_timeouts.system()),
+                SystemStage::parallel(),
             );
         }
+        app.add_system_to_stage(SendHeartbeatsStage, heartbeats_and_timeouts::<L>.system());
     }
 }
 
+/// connections set up by tasks, waiting to be added by `receive_packets`
+type PendingConnections = Arc<Mutex<Vec<(ConnectionHandle, Box<dyn Connection>)>>>;
+
 #[cfg(not(target_arch = "wasm32"))]
-type ServerChannels = HashMap<SocketAddr, Sender<Result<Packet, NetworkError>>>;
+type ServerChannels = HashMap<(ListenerId, SocketAddr), Sender<Result<Packet, NetworkError>>>;
 
-pub struct NetworkResource {
+pub struct NetworkResource<L = DefaultNetwork> {
     task_pool: TaskPool,
 
-    pending_connections: Arc<Mutex<Vec<Box<dyn Connection>>>>,
-    connection_sequence: atomic::AtomicU32,
+    pending_connections: PendingConnections,
+    handles: Arc<Mutex<HandleAllocator>>,
     pub connections: HashMap<ConnectionHandle, Box<dyn Connection>>,
+    connection_info: HashMap<ConnectionHandle, ConnectionInfo>,
 
     #[cfg(not(target_arch = "wasm32"))]
     server_channels: Arc<RwLock<ServerChannels>>,
     #[cfg(not(target_arch = "wasm32"))]
-    listeners: Vec<Task<()>>,
+    listeners: HashMap<ListenerId, Task<()>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    custom_listeners: HashMap<ListenerId, Box<dyn Listener>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    listener_addresses: HashMap<ListenerId, SocketAddr>,
+    /// stopped listeners, kept alive until the deadline so queued packets go out
+    #[cfg(not(target_arch = "wasm32"))]
+    closing_listeners: Vec<(Instant, ListenerId, Task<()>)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    listener_sequence: u32,
+    #[cfg(not(target_arch = "wasm32"))]
+    query_handler: query::QueryResponder,
+    #[cfg(not(target_arch = "wasm32"))]
+    queries: query::QueryState,
 
     runtime: TaskPoolRuntime,
     packet_pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
     channels_builder_fn: Option<Box<dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync>>,
     message_flushing_strategy: MessageFlushingStrategy,
-    idle_timeout_ms: Option<usize>,
-    auto_heartbeat_ms: Option<usize>,
 
-    link_conditioner: Option<LinkConditionerConfig>,
+    link_conditioner: LinkConditioner,
+    compression: Option<Compression>,
+    capture: Option<PacketCapture>,
+    log_format: LogFormat,
+    /// extended idle timeouts of connections suspended by either end
+    suspensions: HashMap<ConnectionHandle, Suspension>,
+    /// sequence of the last control notice sent
+    control_sequence: u32,
+    connects: connect::ConnectState,
+
+    rpc: rpc::RpcState,
+    transfers: transfer::TransferState,
+    scheduler: scheduler::SendScheduler,
+
+    label: PhantomData<fn() -> L>,
 }
 
 #[derive(Debug)]
@@ -134,17 +329,54 @@ pub enum NetworkEvent {
     Disconnected(ConnectionHandle),
     Packet(ConnectionHandle, Packet),
     Error(ConnectionHandle, NetworkError),
+    Transfer(ConnectionHandle, TransferEvent),
+    /// peer suspended the connection, eg. for a loading screen
+    Suspended(ConnectionHandle),
+    /// peer lifted its suspension
+    Resumed(ConnectionHandle),
+    /// `connect` couldn't reach the server, the handle is gone
+    ConnectFailed(ConnectionHandle, ConnectError),
+    /// answer to `query_server`
+    #[cfg(not(target_arch = "wasm32"))]
+    ServerInfo(ServerInfo),
+    /// `query_server` got no answer
+    #[cfg(not(target_arch = "wasm32"))]
+    QueryFailed(SocketAddr),
 }
 
 #[derive(Debug)]
 pub enum NetworkError {
     TurbulenceChannelError(IncomingTrySendError<MultiplexedPacket>),
     IoError(Box<dyn Error + Sync + Send>),
+    /// `listen` couldn't bind the address
+    BindError(SocketAddr, std::io::Error),
+    /// handle of a connection which went away
+    StaleHandle(ConnectionHandle),
+    /// handle doesn't refer to a connection (yet)
+    NoSuchConnection(ConnectionHandle),
     /// if we haven't seen a packet for the specified timeout
     MissedHeartbeat,
     Disconnected,
 }
 
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetworkError::TurbulenceChannelError(err) => write!(f, "channel error: {:?}", err),
+            NetworkError::IoError(err) => write!(f, "I/O error: {}", err),
+            NetworkError::BindError(address, err) => {
+                write!(f, "can't listen on {}: {}", address, err)
+            }
+            NetworkError::StaleHandle(handle) => write!(f, "connection [{}] is gone", handle),
+            NetworkError::NoSuchConnection(handle) => write!(f, "no connection [{}]", handle),
+            NetworkError::MissedHeartbeat => write!(f, "missed heartbeat"),
+            NetworkError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl Error for NetworkError {}
+
 /// Turbulence will coalesce multiple small messages into a single packet when flush is called.
 /// the default is `OnEverySend` - flushing after each message, which bypasses the coalescing.
 /// You probably want to call flush once per tick instead, in your own system.
@@ -179,18 +411,17 @@ impl Default for MessageFlushingStrategy {
 }
 
 #[cfg(target_arch = "wasm32")]
-unsafe impl Send for NetworkResource {}
+unsafe impl<L> Send for NetworkResource<L> {}
 
 #[cfg(target_arch = "wasm32")]
-unsafe impl Sync for NetworkResource {}
+unsafe impl<L> Sync for NetworkResource<L> {}
 
-impl NetworkResource {
+impl<L> NetworkResource<L> {
     pub fn new(
         task_pool: TaskPool,
-        link_conditioner: Option<LinkConditionerConfig>,
+        link_conditioner: Option<LinkConditions>,
         message_flushing_strategy: MessageFlushingStrategy,
-        idle_timeout_ms: Option<usize>,
-        auto_heartbeat_ms: Option<usize>,
+        compression: Option<Compression>,
     ) -> Self {
         let runtime = TaskPoolRuntime::new(task_pool.clone());
         let packet_pool =
@@ -199,73 +430,117 @@ impl NetworkResource {
         NetworkResource {
             task_pool,
             connections: HashMap::new(),
-            connection_sequence: atomic::AtomicU32::new(0),
+            connection_info: HashMap::new(),
+            handles: Arc::new(Mutex::new(HandleAllocator::default())),
             pending_connections: Arc::new(Mutex::new(Vec::new())),
             #[cfg(not(target_arch = "wasm32"))]
             server_channels: Arc::new(RwLock::new(HashMap::new())),
             #[cfg(not(target_arch = "wasm32"))]
-            listeners: Vec::new(),
+            listeners: HashMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            custom_listeners: HashMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            listener_addresses: HashMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            closing_listeners: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            listener_sequence: 0,
+            #[cfg(not(target_arch = "wasm32"))]
+            query_handler: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            queries: Default::default(),
             runtime,
             packet_pool,
             channels_builder_fn: None,
             message_flushing_strategy,
-            idle_timeout_ms,
-            auto_heartbeat_ms,
 
-            link_conditioner,
+            link_conditioner: LinkConditioner::new(link_conditioner.unwrap_or_default()),
+            compression,
+            capture: None,
+            log_format: LogFormat::default(),
+            suspensions: HashMap::new(),
+            control_sequence: 0,
+            connects: Default::default(),
+
+            rpc: Default::default(),
+            transfers: Default::default(),
+            scheduler: Default::default(),
+
+            label: PhantomData,
         }
     }
 
     /// The 3 listening addresses aren't strictly necessary, you can put the same IP address with a different port for the socket address; Unless you have some configuration issues with public and private addresses that need to be connected to.
     /// They also aren't necessary if you're using UDP, so you can put anything if that's the case.
-    #[cfg(not(target_arch = "wasm32"))]
+    ///
+    /// Port 0 picks a free ephemeral port, see `listener_address` for the bound address.
+    /// Call it several times to listen on multiple addresses (eg. IPv4 and IPv6),
+    /// `Connection::listener` tells which listener a connection arrived on.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
     pub fn listen(
         &mut self,
         socket_address: SocketAddr,
         webrtc_listen_address: Option<SocketAddr>,
         public_webrtc_address: Option<SocketAddr>,
-    ) {
-        let mut server_socket = {
-            let webrtc_listen_address = webrtc_listen_address.unwrap_or_else(|| {
-                let mut listen_addr = socket_address;
-                listen_addr.set_port(socket_address.port() + 1);
-                listen_addr
-            });
-            let public_webrtc_address = public_webrtc_address.unwrap_or(webrtc_listen_address);
-            let socket = futures_lite::future::block_on(ServerSocket::listen(
+    ) -> Result<ListenerId, NetworkError> {
+        let (socket_address, webrtc_listen_address) =
+            reserve_listen_addresses(socket_address, webrtc_listen_address)?;
+        let public_webrtc_address = public_webrtc_address.unwrap_or(webrtc_listen_address);
+        // naia panics when binding fails, which can still happen if someone grabbed the port meanwhile
+        let mut server_socket = std::panic::catch_unwind(|| {
+            futures_lite::future::block_on(ServerSocket::listen(
                 socket_address,
                 webrtc_listen_address,
                 public_webrtc_address,
-            ));
+            ))
+        })
+        .map_err(|_| {
+            NetworkError::BindError(
+                socket_address,
+                std::io::Error::new(std::io::ErrorKind::AddrInUse, "naia failed to listen"),
+            )
+        })?;
 
-            if let Some(ref conditioner) = self.link_conditioner {
-                socket.with_link_conditioner(conditioner)
-            } else {
-                socket
-            }
-        };
+        let listener = ListenerId(self.listener_sequence);
+        self.listener_sequence += 1;
+        info!("Listener {} bound to {}", listener, socket_address);
 
         let server_channels = self.server_channels.clone();
         let pending_connections = self.pending_connections.clone();
+        let handles = self.handles.clone();
         let task_pool = self.task_pool.clone();
+        let compression = self.compression.clone();
+        let log_format = self.log_format.clone();
+        let query_handler = self.query_handler.clone();
 
-        self.listeners.push(self.task_pool.spawn(async move {
+        let task = self.task_pool.spawn(async move {
             loop {
                 match server_socket.receive().await {
                     Ok(packet) => {
                         let address = packet.address();
-                        let message = String::from_utf8_lossy(packet.payload());
                         debug!(
                             "Server recv <- {}:{}: {}",
                             address,
                             packet.payload().len(),
-                            message
+                            log_format.format(packet.payload(), false)
                         );
 
+                        // connectionless queries are answered right here
+                        if let Some(response) = query_handler.respond(address, packet.payload()) {
+                            if let Err(err) = server_socket
+                                .get_sender()
+                                .send(ServerPacket::new(address, response))
+                                .await
+                            {
+                                debug!("Can't answer query of {}: {}", address, err);
+                            }
+                            continue;
+                        }
+
                         let needs_new_channel = match server_channels
                             .read()
                             .expect("server channels lock is poisoned")
-                            .get(&address)
+                            .get(&(listener, address))
                             .map(|channel| {
                                 channel.send(Ok(Packet::copy_from_slice(packet.payload())))
                             }) {
@@ -295,15 +570,21 @@ impl NetworkResource {
                         match packet_tx.send(Ok(Packet::copy_from_slice(packet.payload()))) {
                             Ok(()) => {
                                 // It makes sense to store the channel only if it's healthy.
-                                pending_connections.lock().unwrap().push(Box::new(
-                                    transport::ServerConnection::new(
+                                let handle =
+                                    handles.lock().expect("handles lock poisoned").allocate();
+                                pending_connections.lock().unwrap().push((
+                                    handle,
+                                    Box::new(transport::ServerConnection::new(
                                         task_pool.clone(),
                                         packet_rx,
                                         server_socket.get_sender(),
+                                        server_socket.get_sender(),
                                         address,
-                                    ),
+                                        listener,
+                                        Arc::new(PacketCompressor::new(compression.clone())),
+                                    )),
                                 ));
-                                server_channels.insert(address, packet_tx);
+                                server_channels.insert((listener, address), packet_tx);
                             }
                             Err(error) => {
                                 // This branch is unlikely to get called the second time (after
@@ -318,43 +599,205 @@ impl NetworkResource {
                     }
                 }
             }
-        }));
+        });
+        self.listeners.insert(listener, task);
+        self.listener_addresses.insert(listener, socket_address);
+        Ok(listener)
     }
 
-    pub fn connect(&mut self, socket_address: SocketAddr) {
-        let mut client_socket = {
-            let socket = ClientSocket::connect(socket_address);
+    /// Address a listener is bound to
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn listener_address(&self, listener: ListenerId) -> Option<SocketAddr> {
+        self.listener_addresses.get(&listener).copied()
+    }
 
-            if let Some(ref conditioner) = self.link_conditioner {
-                socket.with_link_conditioner(conditioner)
-            } else {
-                socket
+    /// Active listeners and their bound addresses
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn listeners(&self) -> impl Iterator<Item = (ListenerId, SocketAddr)> + '_ {
+        self.listener_addresses
+            .iter()
+            .map(|(listener, address)| (*listener, *address))
+    }
+
+    /// Stops accepting connections on `listener` and disconnects peers which arrived on it.
+    /// Returns false if there is no such listener.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn stop_listening(&mut self, listener: ListenerId) -> bool {
+        let task = self.listeners.remove(&listener);
+        if task.is_none() && self.custom_listeners.remove(&listener).is_none() {
+            return false;
+        }
+        self.listener_addresses.remove(&listener);
+        let handles: Vec<ConnectionHandle> = self
+            .connections
+            .iter()
+            .filter(|(_handle, connection)| connection.listener() == Some(listener))
+            .map(|(handle, _connection)| *handle)
+            .collect();
+        for handle in handles {
+            self.notify_disconnect(handle);
+            self.disconnect(handle);
+        }
+        if let Some(task) = task {
+            self.closing_listeners
+                .push((Instant::now() + LISTENER_CLOSE_GRACE, listener, task));
+        }
+        true
+    }
+
+    /// Closes everything: tells all peers we are going away, drops connections and stops listening.
+    ///
+    /// The resource stays usable - you can `listen` or `connect` again afterwards.
+    /// Called automatically on `AppExit`.
+    pub fn shutdown(&mut self) {
+        let handles: Vec<ConnectionHandle> = self.connections.keys().copied().collect();
+        for handle in handles {
+            self.notify_disconnect(handle);
+            self.disconnect(handle);
+        }
+        let pending: Vec<_> = self.pending_connections.lock().unwrap().drain(..).collect();
+        for (handle, _connection) in pending {
+            self.release_handle(handle);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let deadline = Instant::now() + LISTENER_CLOSE_GRACE;
+            for (listener, task) in self.listeners.drain() {
+                self.closing_listeners.push((deadline, listener, task));
             }
-        };
-        let sender = client_socket.get_sender();
+            self.custom_listeners.clear();
+            self.listener_addresses.clear();
+            self.server_channels
+                .write()
+                .expect("server channels lock poisoned")
+                .clear();
+        }
+    }
 
+    /// Blocks until stopped listeners had their grace period to send queued packets, then drops them
+    #[cfg(not(target_arch = "wasm32"))]
+    fn finish_closing_listeners(&mut self) {
+        let deadline = self
+            .closing_listeners
+            .iter()
+            .map(|(deadline, _listener, _task)| *deadline)
+            .max();
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            }
+        }
+        self.closing_listeners.clear();
+    }
+
+    /// Queues a connection to be set up and announced by `receive_packets`, returning its handle.
+    fn add_pending_connection(&mut self, connection: Box<dyn Connection>) -> ConnectionHandle {
+        let handle = self.next_connection_handle();
+        self.queue_connection(handle, connection);
+        handle
+    }
+
+    fn next_connection_handle(&self) -> ConnectionHandle {
+        self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .allocate()
+    }
+
+    /// Makes the handle stale, its slot can be reused by a new connection
+    fn release_handle(&mut self, handle: ConnectionHandle) {
+        self.handles
+            .lock()
+            .expect("handles lock poisoned")
+            .release(handle);
+    }
+
+    /// Error for a handle which doesn't refer to a connection
+    fn handle_error(&self, handle: ConnectionHandle) -> NetworkError {
+        if self
+            .handles
+            .lock()
+            .expect("handles lock poisoned")
+            .is_stale(handle)
+        {
+            NetworkError::StaleHandle(handle)
+        } else {
+            NetworkError::NoSuchConnection(handle)
+        }
+    }
+
+    /// Whether the handle refers to an established connection.
+    /// False for stale handles, and for connections not yet announced with `NetworkEvent::Connected`.
+    pub fn is_connected(&self, handle: ConnectionHandle) -> bool {
+        self.connections.contains_key(&handle) && !self.connects.is_connecting(handle)
+    }
+
+    /// Handles of all established connections, like `is_connected` without outgoing
+    /// connections the server hasn't accepted yet
+    pub fn handles(&self) -> impl Iterator<Item = ConnectionHandle> + '_ {
+        self.connections
+            .keys()
+            .copied()
+            .filter(|handle| !self.connects.is_connecting(*handle))
+    }
+
+    /// Established connections and their handles
+    pub fn iter_connections(
+        &self,
+    ) -> impl Iterator<Item = (ConnectionHandle, &dyn Connection)> + '_ {
+        self.connections
+            .iter()
+            .filter(|(handle, _)| !self.connects.is_connecting(**handle))
+            .map(|(handle, connection)| (*handle, connection.as_ref()))
+    }
+
+    /// Established connections and their handles, mutably
+    pub fn iter_connections_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (ConnectionHandle, &mut (dyn Connection + 'static))> + '_ {
+        let connects = &self.connects;
+        self.connections
+            .iter_mut()
+            .filter(move |(handle, _)| !connects.is_connecting(**handle))
+            .map(|(handle, connection)| (*handle, connection.as_mut()))
+    }
+
+    fn queue_connection(&mut self, handle: ConnectionHandle, connection: Box<dyn Connection>) {
         self.pending_connections
             .lock()
             .unwrap()
-            .push(Box::new(transport::ClientConnection::new(
-                self.task_pool.clone(),
-                client_socket,
-                sender,
-            )));
+            .push((handle, connection));
     }
 
     // removes handle and connection, but doesn't signal peer in any way.
+    // The handle becomes stale.
     // Peer will eventually do HeartbeatMissed and clean up.
     // (you should probably use the same idle timeout on server & client)
+    // Peers of links which close with the connection, like local ones, get Disconnected instead.
     pub fn disconnect(&mut self, handle: ConnectionHandle) {
+        self.link_conditioner.clear_connection_conditions(handle);
+        self.suspensions.remove(&handle);
+        self.connects.cancel(handle);
+        self.connection_info.remove(&handle);
+        self.release_handle(handle);
         // on wasm32 we can't be a webrtc server, so cleanup is simpler
         cfg_if::cfg_if! {
             if #[cfg(target_arch = "wasm32")] {
                 self.connections.remove(&handle);
             } else {
                 if let Some(removed_connection) = self.connections.remove(&handle) {
-                    if let Some(client_addr) = removed_connection.remote_address() {
-                        self.server_channels.write().expect("server connections lock poisoned").remove(&client_addr);
+                    if let (Some(listener), Some(client_addr)) = (
+                        removed_connection.listener(),
+                        removed_connection.remote_address(),
+                    ) {
+                        self.server_channels
+                            .write()
+                            .expect("server connections lock poisoned")
+                            .remove(&(listener, client_addr));
+                        if let Some(custom_listener) = self.custom_listeners.get_mut(&listener) {
+                            custom_listener.disconnected(client_addr);
+                        }
                     }
                 }
             }
@@ -368,11 +811,7 @@ impl NetworkResource {
     ) -> Result<(), Box<dyn Error + Sync + Send + 'static>> {
         match self.connections.get_mut(&handle) {
             Some(connection) => connection.send(payload),
-            None => Err(Box::new(std::io::Error::new(
-                // FIXME: move to enum Error
-                std::io::ErrorKind::NotFound,
-                "No such connection",
-            ))),
+            None => Err(Box::new(self.handle_error(handle))),
         }
     }
 
@@ -382,6 +821,10 @@ impl NetworkResource {
         }
     }
 
+    pub fn set_log_format(&mut self, log_format: LogFormat) {
+        self.log_format = log_format;
+    }
+
     pub fn set_channels_builder<F>(&mut self, builder: F)
     where
         F: Fn(&mut ConnectionChannelsBuilder) + Send + Sync + 'static,
@@ -403,11 +846,7 @@ impl NetworkResource {
                 }
                 Ok(unsent)
             }
-            None => Err(Box::new(std::io::Error::new(
-                // FIXME: move to enum Error
-                std::io::ErrorKind::NotFound,
-                "No such connection",
-            ))),
+            None => Err(Box::new(self.handle_error(handle))),
         }
     }
 
@@ -441,15 +880,44 @@ impl NetworkResource {
 
 // check every connection for timeouts.
 // ie. check how long since we last saw a packet.
-pub fn heartbeats_and_timeouts(
-    mut net: ResMut<NetworkResource>,
-    mut network_events: ResMut<Events<NetworkEvent>>,
+pub fn heartbeats_and_timeouts<L: NetworkLabel>(
+    time: Res<Time>,
+    mut last_check: Local<f64>,
+    mut settings: ResMut<NetworkSettings<L>>,
+    mut net: ResMut<NetworkResource<L>>,
+    mut network_events: ResMut<Events<L::Event>>,
 ) {
+    let now = time.seconds_since_startup();
+    if now - *last_check < settings.heartbeats_and_timeouts_timestep_in_seconds {
+        return;
+    }
+    *last_check = now;
+
+    if settings.has_stale_overrides(|handle| net.connections.contains_key(handle)) {
+        settings.retain_overrides(|handle| net.connections.contains_key(handle));
+    }
+    for handle in net.expire_connects(settings.connect_timeout_ms) {
+        warn!("Connect timeout for h:{}", handle);
+        // never announced with `Connected`, so no `Disconnected` either
+        network_events.send(NetworkEvent::ConnectFailed(handle, ConnectError::Timeout).into());
+        net.disconnect(handle);
+    }
+    if !settings.is_enabled() {
+        return;
+    }
+
+    let net = &mut *net;
     let mut silent_handles = Vec::new();
     let mut needs_hb_handles = Vec::new();
-    let idle_limit = net.idle_timeout_ms;
-    let heartbeat_limit = net.auto_heartbeat_ms;
     for (handle, connection) in net.connections.iter_mut() {
+        let ConnectionSettings {
+            idle_timeout_ms: mut idle_limit,
+            auto_heartbeat_ms: heartbeat_limit,
+        } = settings.connection(*handle);
+        if let Some(timeout_ms) = net.suspensions.get(handle).and_then(Suspension::timeout_ms) {
+            // suspended connections get a longer window before being idle-disconnected
+            idle_limit = idle_limit.map(|idle_limit| idle_limit.max(timeout_ms as usize));
+        }
         let (rx_ms, tx_ms) = connection.last_packet_timings();
         debug!("millis since last rx: {} tx: {}", rx_ms, tx_ms);
         if idle_limit.is_some() && rx_ms > idle_limit.unwrap() as u128 {
@@ -469,22 +937,119 @@ pub fn heartbeats_and_timeouts(
     for handle in silent_handles {
         warn!("Idle disconnect for h:{}", handle);
         // Error doesn't imply Disconnected, so we send both
-        network_events.send(NetworkEvent::Error(handle, NetworkError::MissedHeartbeat));
-        network_events.send(NetworkEvent::Disconnected(handle));
+        network_events.send(NetworkEvent::Error(handle, NetworkError::MissedHeartbeat).into());
+        network_events.send(NetworkEvent::Disconnected(handle).into());
         net.disconnect(handle);
     }
 }
 
-pub fn receive_packets(
-    mut net: ResMut<NetworkResource>,
-    mut network_events: ResMut<Events<NetworkEvent>>,
+/// Binds the addresses naia is about to listen on and releases them right away, reporting bind
+/// errors instead of letting naia panic, and resolving port 0 to a free port.
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
+fn reserve_listen_addresses(
+    socket_address: SocketAddr,
+    webrtc_listen_address: Option<SocketAddr>,
+) -> Result<(SocketAddr, SocketAddr), NetworkError> {
+    fn bind_error(address: SocketAddr) -> impl FnOnce(std::io::Error) -> NetworkError {
+        move |err| NetworkError::BindError(address, err)
+    }
+    // session server on TCP, WebRTC data on UDP
+    #[cfg(feature = "use-webrtc")]
+    let addresses = {
+        let socket_address = std::net::TcpListener::bind(socket_address)
+            .and_then(|listener| listener.local_addr())
+            .map_err(bind_error(socket_address))?;
+        let webrtc_listen_address = webrtc_listen_address.unwrap_or_else(|| {
+            let mut listen_addr = socket_address;
+            listen_addr.set_port(socket_address.port() + 1);
+            listen_addr
+        });
+        let webrtc_listen_address = std::net::UdpSocket::bind(webrtc_listen_address)
+            .and_then(|socket| socket.local_addr())
+            .map_err(bind_error(webrtc_listen_address))?;
+        (socket_address, webrtc_listen_address)
+    };
+    #[cfg(not(feature = "use-webrtc"))]
+    let addresses = {
+        let socket_address = std::net::UdpSocket::bind(socket_address)
+            .and_then(|socket| socket.local_addr())
+            .map_err(bind_error(socket_address))?;
+        // unused by the UDP server socket
+        (
+            socket_address,
+            webrtc_listen_address.unwrap_or(socket_address),
+        )
+    };
+    Ok(addresses)
+}
+
+/// Gracefully closes all connections and listeners when the app exits.
+pub fn shutdown_on_exit<L: NetworkLabel>(
+    mut exit_events: EventReader<AppExit>,
+    mut net: ResMut<NetworkResource<L>>,
 ) {
-    let pending_connections: Vec<Box<dyn Connection>> =
+    if exit_events.iter().next().is_some() {
+        net.shutdown();
+        // there is no next frame to expire them in, stopped listeners get their grace period now
+        #[cfg(not(target_arch = "wasm32"))]
+        net.finish_closing_listeners();
+    }
+}
+
+pub fn receive_packets<L: NetworkLabel>(
+    mut net: ResMut<NetworkResource<L>>,
+    mut network_events: ResMut<Events<L::Event>>,
+) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let now = Instant::now();
+        net.closing_listeners
+            .retain(|(deadline, _listener, _task)| *deadline > now);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    net.poll_listeners();
+    for (handle, err) in net.poll_connects() {
+        warn!("Connect failed for h:{}: {}", handle, err);
+        network_events.send(NetworkEvent::ConnectFailed(handle, err).into());
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    for event in net.poll_queries() {
+        network_events.send(event.into());
+    }
+
+    let pending_connections: Vec<(ConnectionHandle, Box<dyn Connection>)> =
         net.pending_connections.lock().unwrap().drain(..).collect();
-    for mut conn in pending_connections {
-        let handle: ConnectionHandle = net
-            .connection_sequence
-            .fetch_add(1, atomic::Ordering::Relaxed);
+    for (handle, mut conn) in pending_connections {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(listener) = conn.listener() {
+            if !net.listeners.contains_key(&listener)
+                && !net.custom_listeners.contains_key(&listener)
+            {
+                // arrived while the listener was being stopped
+                net.release_handle(handle);
+                continue;
+            }
+        }
+        if !net
+            .handles
+            .lock()
+            .expect("handles lock poisoned")
+            .is_current(handle)
+        {
+            // disconnected before it was set up, eg. a cancelled connect
+            continue;
+        }
+        if let Some(capture) = net.capture.as_ref() {
+            conn.set_capture(Some(ConnectionCapture {
+                handle,
+                capture: capture.clone(),
+            }));
+        }
+        conn.set_link_conditioner(Some(ConnectionConditioner {
+            handle,
+            conditioner: net.link_conditioner.clone(),
+        }));
         if let Some(channels_builder_fn) = net.channels_builder_fn.as_ref() {
             conn.build_channels(
                 channels_builder_fn,
@@ -492,23 +1057,106 @@ pub fn receive_packets(
                 net.packet_pool.clone(),
             );
         }
+        // outgoing connections are announced once the server accepts them
+        let connecting = net.connects.is_connecting(handle);
+        if connecting {
+            let sequence = next_control_sequence(&mut net.control_sequence);
+            if let Err(err) = conn.send(ControlPacket::Connect.encode(sequence)) {
+                debug!("Can't ask [{}] to accept connection: {}", handle, err);
+            }
+        }
+        net.connection_info
+            .insert(handle, ConnectionInfo::new(conn.as_ref()));
         net.connections.insert(handle, conn);
-        network_events.send(NetworkEvent::Connected(handle));
+        net.advertise_compression(handle);
+        if !connecting {
+            network_events.send(NetworkEvent::Connected(handle).into());
+        }
     }
 
     let packet_pool = net.packet_pool.clone();
-    for (handle, connection) in net.connections.iter_mut() {
+    let mut closed_handles = Vec::new();
+    let NetworkResource {
+        connections,
+        connection_info,
+        log_format,
+        suspensions,
+        control_sequence,
+        connects,
+        ..
+    } = &mut *net;
+    for (handle, connection) in connections.iter_mut() {
         while let Some(result) = connection.receive() {
             match result {
                 Ok(packet) => {
+                    if connects.answered(*handle) {
+                        if let Some(info) = connection_info.get_mut(handle) {
+                            info.connected_at = Instant::now();
+                        }
+                        network_events.send(NetworkEvent::Connected(*handle).into());
+                    }
                     // heartbeat packets are empty
                     if packet.is_empty() {
                         debug!("Received heartbeat packet");
                         // discard without sending a NetworkEvent
                         continue;
                     }
-                    let message = String::from_utf8_lossy(&packet);
-                    debug!("Received on [{}] {} RAW: {}", handle, packet.len(), message);
+                    if let Some((sequence, control)) = ControlPacket::decode(&packet) {
+                        debug!(
+                            "Received control packet {} on [{}]: {:?}",
+                            sequence, handle, control
+                        );
+                        match control {
+                            ControlPacket::Suspend { timeout_ms } => {
+                                let suspension = suspensions.entry(*handle).or_default();
+                                if suspension.remote(sequence, Some(timeout_ms)) {
+                                    network_events.send(NetworkEvent::Suspended(*handle).into());
+                                }
+                            }
+                            ControlPacket::Resume => {
+                                let suspension = suspensions.entry(*handle).or_default();
+                                if suspension.remote(sequence, None) {
+                                    network_events.send(NetworkEvent::Resumed(*handle).into());
+                                }
+                            }
+                            ControlPacket::Disconnect => {
+                                closed_handles.push(*handle);
+                                break;
+                            }
+                            ControlPacket::Connect => {
+                                let sequence = next_control_sequence(control_sequence);
+                                if let Err(err) =
+                                    connection.send(ControlPacket::Accept.encode(sequence))
+                                {
+                                    debug!("Can't accept connection [{}]: {}", handle, err);
+                                }
+                            }
+                            ControlPacket::Accept => {}
+                            ControlPacket::Compression {
+                                accepts,
+                                dictionary,
+                                acknowledged,
+                            } => {
+                                let answer = connection.compressor().and_then(|compressor| {
+                                    compressor.negotiate(accepts, dictionary, acknowledged)
+                                });
+                                if let Some(answer) = answer {
+                                    let sequence = next_control_sequence(control_sequence);
+                                    if let Err(err) = connection.send(answer.encode(sequence)) {
+                                        debug!("Can't answer compression of [{}]: {}", handle, err);
+                                    }
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    let has_channels = connection.channels_rx().is_some();
+                    debug!(
+                        "Received on [{}] {} RAW: {}",
+                        handle,
+                        packet.len(),
+                        log_format.format(&packet, has_channels)
+                    );
                     if let Some(channels_rx) = connection.channels_rx() {
                         debug!("Processing as message");
                         let mut pool_packet = packet_pool.acquire();
@@ -520,22 +1168,35 @@ pub fn receive_packets(
                             }
                             Err(err) => {
                                 error!("Channel Incoming Error: {}", err);
-                                network_events.send(NetworkEvent::Error(
-                                    *handle,
-                                    NetworkError::TurbulenceChannelError(err),
-                                ));
+                                network_events.send(
+                                    NetworkEvent::Error(
+                                        *handle,
+                                        NetworkError::TurbulenceChannelError(err),
+                                    )
+                                    .into(),
+                                );
                             }
                         }
                     } else {
                         debug!("Processing as packet");
-                        network_events.send(NetworkEvent::Packet(*handle, packet));
+                        network_events.send(NetworkEvent::Packet(*handle, packet).into());
                     }
                 }
+                // link is gone for good, eg. the local peer dropped its end
+                Err(NetworkError::Disconnected) => {
+                    closed_handles.push(*handle);
+                    break;
+                }
                 Err(err) => {
                     error!("Receive Error: {:?}", err);
-                    network_events.send(NetworkEvent::Error(*handle, err));
+                    network_events.send(NetworkEvent::Error(*handle, err).into());
                 }
             }
         }
     }
+    for handle in closed_handles {
+        debug!("Peer closed connection [{}]", handle);
+        network_events.send(NetworkEvent::Disconnected(handle).into());
+        net.disconnect(handle);
+    }
 }
@@ -0,0 +1,203 @@
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::tasks::Task;
+use bevy::{prelude::error, tasks::TaskPool};
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use futures_lite::StreamExt;
+use turbulence::{
+    buffer::BufferPacketPool,
+    message_channels::MessageChannels,
+    packet_multiplexer::{IncomingMultiplexedPackets, MuxPacketPool, PacketMultiplexer},
+};
+
+use super::{
+    channels::{SimpleBufferPool, TaskPoolRuntime},
+    codec::EncodedChannels,
+    transport::{
+        Connection, ConnectionChannelsBuilder, MultiplexedPacket, PacketStats, TransportKind,
+    },
+    ConnectionHandle, NetworkError, NetworkResource, Packet,
+};
+
+/// In-memory end of a connection, used for the local player of a listen server
+/// (see `NetworkResource::connect_local`).
+///
+/// Raw packets and turbulence channels work as usual, minus the socket, compression and
+/// link conditioning. Channel messages are still serialized: `Connection::channels` hands out
+/// turbulence's `MessageChannels`, which only speak packets, so both ends keep the exact
+/// behaviour of remote connections. Local connections never idle out.
+pub struct LocalConnection {
+    task_pool: TaskPool,
+
+    packet_tx: Sender<Packet>,
+    packet_rx: Receiver<Packet>,
+    disconnected: bool,
+    stats: Arc<RwLock<PacketStats>>,
+
+    channels: Option<MessageChannels>,
+    encoded_channels: Option<EncodedChannels>,
+    channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    channels_task: Option<Task<()>>,
+}
+
+impl LocalConnection {
+    /// Creates both ends of a local connection
+    pub fn pair(task_pool: TaskPool) -> (LocalConnection, LocalConnection) {
+        let (a_tx, a_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+        (
+            LocalConnection::new(task_pool.clone(), b_tx, a_rx),
+            LocalConnection::new(task_pool, a_tx, b_rx),
+        )
+    }
+
+    fn new(task_pool: TaskPool, packet_tx: Sender<Packet>, packet_rx: Receiver<Packet>) -> Self {
+        LocalConnection {
+            task_pool,
+            packet_tx,
+            packet_rx,
+            disconnected: false,
+            stats: Arc::new(RwLock::new(PacketStats::default())),
+            channels: None,
+            encoded_channels: None,
+            channels_rx: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            channels_task: None,
+        }
+    }
+}
+
+impl Connection for LocalConnection {
+    fn remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn transport(&self) -> TransportKind {
+        TransportKind::Local
+    }
+
+    fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.stats
+            .write()
+            .expect("stats lock poisoned")
+            .add_tx(payload.len(), payload.len());
+        self.packet_tx.send(payload).map_err(|_| {
+            Box::new(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Local peer disconnected",
+            )) as Box<dyn Error + Sync + Send>
+        })
+    }
+
+    fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+        match self.packet_rx.try_recv() {
+            Ok(packet) => {
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_rx(packet.len(), packet.len());
+                Some(Ok(packet))
+            }
+            Err(TryRecvError::Empty) => None,
+            // report the peer going away once
+            Err(TryRecvError::Disconnected) if !self.disconnected => {
+                self.disconnected = true;
+                Some(Err(NetworkError::Disconnected))
+            }
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    fn build_channels(
+        &mut self,
+        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
+        runtime: TaskPoolRuntime,
+        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
+    ) {
+        let mut builder = ConnectionChannelsBuilder::new(runtime, pool);
+        builder_fn(&mut builder);
+
+        let mut multiplexer = PacketMultiplexer::new();
+        let (channels, encoded_channels) = builder.build(&mut multiplexer);
+        self.channels = Some(channels);
+        self.encoded_channels = Some(encoded_channels);
+        let (channels_rx, mut channels_tx) = multiplexer.start();
+        self.channels_rx = Some(channels_rx);
+
+        let packet_tx = self.packet_tx.clone();
+        let stats = self.stats.clone();
+
+        let closure = async move {
+            while let Some(packet) = channels_tx.next().await {
+                stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_tx(packet.len(), packet.len());
+                if packet_tx.send(Packet::copy_from_slice(&packet)).is_err() {
+                    break;
+                }
+            }
+            error!("Local channel stream Disconnected");
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.channels_task = Some(self.task_pool.spawn(closure));
+        }
+        #[cfg(target_arch = "wasm32")]
+        self.task_pool.spawn(closure);
+    }
+
+    fn channels(&mut self) -> Option<&mut MessageChannels> {
+        self.channels.as_mut()
+    }
+
+    fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+        self.channels_rx.as_mut()
+    }
+
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        self.encoded_channels.as_mut()
+    }
+
+    fn stats(&self) -> PacketStats {
+        self.stats.read().expect("stats lock poisoned").clone()
+    }
+
+    fn last_packet_timings(&self) -> (u128, u128) {
+        // the peer is in the same app, no need for heartbeats or timeouts
+        (0, 0)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+unsafe impl Send for LocalConnection {}
+
+#[cfg(target_arch = "wasm32")]
+unsafe impl Sync for LocalConnection {}
+
+impl<L> NetworkResource<L> {
+    /// Host mode: connects the local player's `client` network to this (listen) server
+    /// without going through a socket. `client` is usually the `NetworkResource` of another
+    /// `NetworkLabel`, so the same client code serves local and remote play.
+    ///
+    /// Returns `(server_handle, client_handle)` - this server sees the local player as a regular
+    /// connection at `server_handle`, while `client` reaches the server at `client_handle`.
+    /// Each network announces its end with `NetworkEvent::Connected` on its next `receive_packets` run.
+    pub fn connect_local<C>(
+        &mut self,
+        client: &mut NetworkResource<C>,
+    ) -> (ConnectionHandle, ConnectionHandle) {
+        let (server_end, client_end) = LocalConnection::pair(self.task_pool.clone());
+        let server_handle = self.add_pending_connection(Box::new(server_end));
+        let client_handle = client.add_pending_connection(Box::new(client_end));
+        (server_handle, client_handle)
+    }
+}
@@ -0,0 +1,231 @@
+use bevy::prelude::{debug, warn};
+use instant::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, RwLock},
+};
+
+use super::{control::plugin_magic, NetworkError, NetworkEvent, NetworkResource, Packet};
+
+/// Prefix of connectionless query packets
+const QUERY_MAGIC: [u8; 8] = plugin_magic(b"BNTQRY");
+const OP_REQUEST: u8 = 1;
+const OP_RESPONSE: u8 = 2;
+/// Responses larger than this are sent empty, so they fit a single unfragmented datagram
+const MAX_QUERY_RESPONSE: usize = 1200;
+/// Magic, op and nonce
+const QUERY_HEADER: usize = QUERY_MAGIC.len() + 5;
+/// Requests are padded to the largest response, so a spoofed source gets no more bytes than
+/// the attacker sent
+const QUERY_REQUEST_LEN: usize = QUERY_HEADER + MAX_QUERY_RESPONSE;
+/// Unanswered queries are reported with `NetworkEvent::QueryFailed` after this long
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Builds the payload answering a `query_server` from the given address
+pub type QueryHandler = dyn Fn(SocketAddr) -> Vec<u8> + Send + Sync;
+
+/// Answer to `NetworkResource::query_server`
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub address: SocketAddr,
+    pub rtt: Duration,
+    /// what the server's `QueryHandler` returned
+    pub payload: Packet,
+}
+
+/// Server side of queries, shared with listeners. `listen_with` hands it to
+/// `Listener::answer_queries`.
+#[derive(Clone, Default)]
+pub struct QueryResponder(Arc<RwLock<Option<Box<QueryHandler>>>>);
+
+impl QueryResponder {
+    /// Response to a query packet, None if the packet isn't a query.
+    /// The payload is left out when it would make the response larger than the request.
+    pub fn respond(&self, from: SocketAddr, packet: &[u8]) -> Option<Vec<u8>> {
+        let nonce = match packet.strip_prefix(&QUERY_MAGIC[..])?.split_first()? {
+            (&OP_REQUEST, body) if body.len() >= 4 => &body[..4],
+            _ => return None,
+        };
+        let mut payload = match &*self.0.read().expect("query handler lock poisoned") {
+            Some(handler) => handler(from),
+            // still useful for measuring latency
+            None => Vec::new(),
+        };
+        if payload.len() > MAX_QUERY_RESPONSE {
+            warn!(
+                "Query response of {} bytes exceeds {} bytes, sending it empty",
+                payload.len(),
+                MAX_QUERY_RESPONSE
+            );
+            payload.clear();
+        } else if QUERY_HEADER + payload.len() > packet.len() {
+            debug!("Query of {} isn't padded, answering it empty", from);
+            payload.clear();
+        }
+        let mut response = QUERY_MAGIC.to_vec();
+        response.push(OP_RESPONSE);
+        response.extend_from_slice(nonce);
+        response.extend_from_slice(&payload);
+        Some(response)
+    }
+}
+
+/// Client side, queries waiting for an answer
+#[derive(Default)]
+pub(crate) struct QueryState {
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
+    pending: HashMap<u32, (SocketAddr, Instant)>,
+    next_nonce: u32,
+    buffer: Vec<u8>,
+}
+
+impl QueryState {
+    fn socket(&mut self, ipv4: bool) -> io::Result<&UdpSocket> {
+        let socket = if ipv4 {
+            &mut self.socket_v4
+        } else {
+            &mut self.socket_v6
+        };
+        if socket.is_none() {
+            let bound = if ipv4 {
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+            } else {
+                UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+            };
+            bound.set_nonblocking(true)?;
+            *socket = Some(bound);
+        }
+        Ok(socket.as_ref().expect("bound above"))
+    }
+
+    fn receive(&mut self, events: &mut Vec<NetworkEvent>) {
+        self.buffer.resize(QUERY_REQUEST_LEN, 0);
+        for socket in self.socket_v4.iter().chain(self.socket_v6.iter()) {
+            loop {
+                let (len, from) = match socket.recv_from(&mut self.buffer) {
+                    Ok(received) => received,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    // eg. ICMP port unreachable, the query times out
+                    Err(err) => {
+                        debug!("Query receive error: {}", err);
+                        break;
+                    }
+                };
+                let body = match self.buffer[..len]
+                    .strip_prefix(&QUERY_MAGIC[..])
+                    .and_then(|body| body.split_first())
+                {
+                    Some((&OP_RESPONSE, body)) if body.len() >= 4 => body,
+                    _ => continue,
+                };
+                let nonce = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                match self.pending.get(&nonce) {
+                    // answers from anyone else are ignored
+                    Some((address, sent_at)) if *address == from => {
+                        events.push(NetworkEvent::ServerInfo(ServerInfo {
+                            address: from,
+                            rtt: sent_at.elapsed(),
+                            payload: Packet::copy_from_slice(&body[4..]),
+                        }));
+                        self.pending.remove(&nonce);
+                    }
+                    _ => debug!("Unexpected query response from {}", from),
+                }
+            }
+        }
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// Sets what the server answers to `query_server`, eg. serialized name and player count.
+    /// Called from listener tasks, outside of the ECS. Without a handler queries are answered
+    /// with an empty payload.
+    ///
+    /// Answered by naia's socket, `UdpListener` and other listeners implementing
+    /// `Listener::answer_queries`.
+    pub fn set_query_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(SocketAddr) -> Vec<u8> + Send + Sync + 'static,
+    {
+        *self
+            .query_handler
+            .0
+            .write()
+            .expect("query handler lock poisoned") = Some(Box::new(handler));
+    }
+
+    /// Asks a server for its info without connecting to it. The answer arrives as
+    /// `NetworkEvent::ServerInfo`, or `NetworkEvent::QueryFailed` if there is none.
+    pub fn query_server(&mut self, address: SocketAddr) -> Result<(), NetworkError> {
+        let queries = &mut self.queries;
+        let nonce = queries.next_nonce;
+        queries.next_nonce = queries.next_nonce.wrapping_add(1);
+
+        let mut request = QUERY_MAGIC.to_vec();
+        request.push(OP_REQUEST);
+        request.extend_from_slice(&nonce.to_le_bytes());
+        request.resize(QUERY_REQUEST_LEN, 0);
+        queries
+            .socket(address.is_ipv4())
+            .and_then(|socket| socket.send_to(&request, address))
+            .map_err(|err| NetworkError::IoError(Box::new(err)))?;
+        queries.pending.insert(nonce, (address, Instant::now()));
+        Ok(())
+    }
+
+    /// Query answers and timeouts, for `receive_packets`
+    pub(crate) fn poll_queries(&mut self) -> Vec<NetworkEvent> {
+        let mut events = Vec::new();
+        if self.queries.pending.is_empty() {
+            return events;
+        }
+        self.queries.receive(&mut events);
+        self.queries.pending.retain(|_, (address, sent_at)| {
+            let waiting = sent_at.elapsed() < QUERY_TIMEOUT;
+            if !waiting {
+                events.push(NetworkEvent::QueryFailed(*address));
+            }
+            waiting
+        });
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(len: usize) -> Vec<u8> {
+        let mut request = QUERY_MAGIC.to_vec();
+        request.push(OP_REQUEST);
+        request.extend_from_slice(&7u32.to_le_bytes());
+        request.resize(len, 0);
+        request
+    }
+
+    #[test]
+    fn responses_are_never_larger_than_requests() {
+        let responder = QueryResponder::default();
+        *responder.0.write().unwrap() = Some(Box::new(|_| vec![1; 100]));
+        let from = "127.0.0.1:1000".parse().unwrap();
+
+        let response = responder
+            .respond(from, &request(QUERY_REQUEST_LEN))
+            .unwrap();
+        assert_eq!(response.len(), QUERY_HEADER + 100);
+        assert_eq!(
+            &response[QUERY_MAGIC.len() + 1..QUERY_HEADER],
+            7u32.to_le_bytes()
+        );
+
+        for len in [QUERY_HEADER, QUERY_HEADER + 99] {
+            let response = responder.respond(from, &request(len)).unwrap();
+            assert_eq!(response.len(), QUERY_HEADER);
+        }
+        assert_eq!(responder.respond(from, &request(QUERY_HEADER - 1)), None);
+        assert_eq!(responder.respond(from, b"not a query"), None);
+    }
+}
@@ -0,0 +1,409 @@
+use bevy::prelude::{debug, info, warn};
+use instant::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use super::control::plugin_magic;
+
+/// Prefix of rendezvous packets
+const RENDEZVOUS_MAGIC: [u8; 8] = plugin_magic(b"BNTRDV");
+
+const OP_REGISTER: u8 = 1;
+const OP_REGISTERED: u8 = 2;
+const OP_INTRODUCE: u8 = 3;
+const OP_PEER: u8 = 4;
+const OP_UNKNOWN: u8 = 5;
+const OP_PUNCH: u8 = 6;
+const OP_TAKEN: u8 = 7;
+
+/// Hosts re-register this often, keeping their NAT mapping to the rendezvous server open
+const REGISTER_INTERVAL: Duration = Duration::from_secs(5);
+/// Registrations not refreshed for this long are dropped by the rendezvous server
+const REGISTRATION_EXPIRY: Duration = Duration::from_secs(30);
+/// Clients ask for an introduction this often until they get one
+const INTRODUCE_INTERVAL: Duration = Duration::from_millis(250);
+const INTRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Punches sent by each side, some may die on a NAT which didn't open yet
+const PUNCH_REPEAT: usize = 3;
+const MAX_RENDEZVOUS_PACKET: usize = 512;
+
+/// Rendezvous protocol, exchanged as raw datagrams like control packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RendezvousPacket {
+    /// host offers a session
+    Register(String),
+    /// rendezvous answer to `Register`, with the host's public endpoint
+    Registered(SocketAddr),
+    /// client asks to be introduced to the host of a session
+    Introduce(String),
+    /// public endpoint of the other side, sent to both the client and the host
+    Peer(SocketAddr),
+    /// nobody registered the session
+    Unknown(String),
+    /// opens the sender's NAT towards the peer, dropped on arrival
+    Punch,
+    /// another host registered the session and keeps it alive
+    Taken(String),
+}
+
+impl RendezvousPacket {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut packet = RENDEZVOUS_MAGIC.to_vec();
+        let (op, body) = match self {
+            RendezvousPacket::Register(session) => (OP_REGISTER, session.clone()),
+            RendezvousPacket::Registered(address) => (OP_REGISTERED, address.to_string()),
+            RendezvousPacket::Introduce(session) => (OP_INTRODUCE, session.clone()),
+            RendezvousPacket::Peer(address) => (OP_PEER, address.to_string()),
+            RendezvousPacket::Unknown(session) => (OP_UNKNOWN, session.clone()),
+            RendezvousPacket::Punch => (OP_PUNCH, String::new()),
+            RendezvousPacket::Taken(session) => (OP_TAKEN, session.clone()),
+        };
+        packet.push(op);
+        packet.extend_from_slice(body.as_bytes());
+        packet
+    }
+
+    pub(crate) fn decode(packet: &[u8]) -> Option<Self> {
+        let (op, body) = packet.strip_prefix(&RENDEZVOUS_MAGIC[..])?.split_first()?;
+        let body = std::str::from_utf8(body).ok()?;
+        match *op {
+            OP_REGISTER => Some(RendezvousPacket::Register(body.to_owned())),
+            OP_REGISTERED => body.parse().ok().map(RendezvousPacket::Registered),
+            OP_INTRODUCE => Some(RendezvousPacket::Introduce(body.to_owned())),
+            OP_PEER => body.parse().ok().map(RendezvousPacket::Peer),
+            OP_UNKNOWN => Some(RendezvousPacket::Unknown(body.to_owned())),
+            OP_PUNCH if body.is_empty() => Some(RendezvousPacket::Punch),
+            OP_TAKEN => Some(RendezvousPacket::Taken(body.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+fn punch(socket: &UdpSocket, peer: SocketAddr) {
+    let packet = RendezvousPacket::Punch.encode();
+    for _ in 0..PUNCH_REPEAT {
+        if let Err(err) = socket.send_to(&packet, peer) {
+            debug!("Can't punch towards {}: {}", peer, err);
+        }
+    }
+}
+
+/// Introducer for peers behind NAT: hosts register a session, clients ask to be introduced,
+/// and both get told the public endpoint of the other side to punch towards.
+///
+/// Run it on a publicly reachable machine, with `run` or by calling `poll` from a game loop.
+pub struct RendezvousServer {
+    socket: UdpSocket,
+    sessions: HashMap<String, (SocketAddr, Instant)>,
+    buffer: Vec<u8>,
+}
+
+impl RendezvousServer {
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(RendezvousServer {
+            socket,
+            sessions: HashMap::new(),
+            buffer: vec![0; MAX_RENDEZVOUS_PACKET],
+        })
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Handles pending requests without blocking
+    pub fn poll(&mut self) {
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, from)) => self.handle(len, from),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // eg. ICMP port unreachable of a peer which went away, reported on Windows
+                Err(err) => {
+                    debug!("Rendezvous receive error: {}", err);
+                    break;
+                }
+            }
+        }
+        self.expire();
+    }
+
+    /// Serves forever
+    pub fn run(&mut self) -> io::Result<()> {
+        self.socket.set_nonblocking(false)?;
+        self.socket.set_read_timeout(Some(REGISTER_INTERVAL))?;
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, from)) => self.handle(len, from),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) => debug!("Rendezvous receive error: {}", err),
+            }
+            self.expire();
+        }
+    }
+
+    fn handle(&mut self, len: usize, from: SocketAddr) {
+        match RendezvousPacket::decode(&self.buffer[..len]) {
+            Some(RendezvousPacket::Register(session)) => {
+                match self.sessions.get(&session) {
+                    Some((host, _)) if *host == from => {}
+                    // the host refreshes it well before it expires
+                    Some((host, registered)) if registered.elapsed() < REGISTRATION_EXPIRY => {
+                        debug!(
+                            "Refusing session {:?} to {}, hosted by {}",
+                            session, from, host
+                        );
+                        self.send(RendezvousPacket::Taken(session), from);
+                        return;
+                    }
+                    _ => info!("Session {:?} hosted by {}", session, from),
+                }
+                self.sessions.insert(session, (from, Instant::now()));
+                self.send(RendezvousPacket::Registered(from), from);
+            }
+            Some(RendezvousPacket::Introduce(session)) => match self.sessions.get(&session) {
+                Some((host, _)) => {
+                    let host = *host;
+                    debug!("Introducing {} to {} of session {:?}", from, host, session);
+                    self.send(RendezvousPacket::Peer(host), from);
+                    self.send(RendezvousPacket::Peer(from), host);
+                }
+                None => self.send(RendezvousPacket::Unknown(session), from),
+            },
+            _ => debug!("Unexpected rendezvous packet from {}", from),
+        }
+    }
+
+    fn expire(&mut self) {
+        self.sessions
+            .retain(|_, (_, registered)| registered.elapsed() < REGISTRATION_EXPIRY);
+    }
+
+    fn send(&self, packet: RendezvousPacket, to: SocketAddr) {
+        if let Err(err) = self.socket.send_to(&packet.encode(), to) {
+            debug!("Can't send rendezvous packet to {}: {}", to, err);
+        }
+    }
+}
+
+/// Session a `UdpListener` hosts through a rendezvous server
+pub(crate) struct Registration {
+    server: SocketAddr,
+    session: String,
+    last_sent: Option<Instant>,
+    public_address: Option<SocketAddr>,
+    /// the session was taken by another host, warned about once
+    refused: bool,
+}
+
+impl Registration {
+    pub(crate) fn new(server: SocketAddr, session: String) -> Self {
+        Registration {
+            server,
+            session,
+            last_sent: None,
+            public_address: None,
+            refused: false,
+        }
+    }
+
+    /// Our endpoint as seen by the rendezvous server, once it answered
+    pub(crate) fn public_address(&self) -> Option<SocketAddr> {
+        self.public_address
+    }
+
+    /// Registers again when it's time to
+    pub(crate) fn refresh(&mut self, socket: &UdpSocket) {
+        if matches!(self.last_sent, Some(sent) if sent.elapsed() < REGISTER_INTERVAL) {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+        let packet = RendezvousPacket::Register(self.session.clone()).encode();
+        if let Err(err) = socket.send_to(&packet, self.server) {
+            debug!("Can't register with rendezvous {}: {}", self.server, err);
+        }
+    }
+}
+
+/// Handles a rendezvous packet arriving at a listener socket.
+/// Returns false if it isn't one, so it belongs to a connection.
+pub(crate) fn consume(
+    registration: Option<&mut Registration>,
+    socket: &UdpSocket,
+    from: SocketAddr,
+    packet: &[u8],
+) -> bool {
+    let packet = match RendezvousPacket::decode(packet) {
+        Some(packet) => packet,
+        None => return false,
+    };
+    match (packet, registration) {
+        (RendezvousPacket::Punch, _) => {}
+        (RendezvousPacket::Registered(address), Some(registration))
+            if from == registration.server =>
+        {
+            registration.refused = false;
+            if registration.public_address != Some(address) {
+                info!(
+                    "Hosting session {:?} at public address {}",
+                    registration.session, address
+                );
+                registration.public_address = Some(address);
+            }
+        }
+        (RendezvousPacket::Peer(peer), Some(registration)) if from == registration.server => {
+            debug!("Punching towards client {}", peer);
+            punch(socket, peer);
+        }
+        (RendezvousPacket::Taken(session), Some(registration)) if from == registration.server => {
+            if !registration.refused {
+                warn!(
+                    "Session {:?} is hosted by someone else, retrying until it expires",
+                    session
+                );
+                registration.refused = true;
+            }
+            registration.public_address = None;
+        }
+        (packet, _) => debug!("Unexpected rendezvous packet {:?} from {}", packet, from),
+    }
+    true
+}
+
+/// Client side: asks the rendezvous server for the host of `session` and punches towards it.
+/// Blocks, run it on a thread of its own. Returns the socket to connect from and the host address.
+pub(crate) fn introduce(server: SocketAddr, session: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let unspecified: IpAddr = if server.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.set_read_timeout(Some(INTRODUCE_INTERVAL))?;
+
+    let request = RendezvousPacket::Introduce(session.to_owned()).encode();
+    let mut buffer = vec![0; MAX_RENDEZVOUS_PACKET];
+    let started = Instant::now();
+    let host = 'introduce: loop {
+        if started.elapsed() > INTRODUCE_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "rendezvous server didn't answer",
+            ));
+        }
+        socket.send_to(&request, server)?;
+        let sent = Instant::now();
+        while sent.elapsed() < INTRODUCE_INTERVAL {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(err) => return Err(err),
+            };
+            if from != server {
+                continue;
+            }
+            match RendezvousPacket::decode(&buffer[..len]) {
+                Some(RendezvousPacket::Peer(host)) => break 'introduce host,
+                Some(RendezvousPacket::Unknown(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no host registered session {:?}", session),
+                    ))
+                }
+                _ => {}
+            }
+        }
+    };
+
+    debug!("Punching towards host {} of session {:?}", host, session);
+    punch(&socket, host);
+    Ok((socket, host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let address: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        for packet in [
+            RendezvousPacket::Register("lobby".to_owned()),
+            RendezvousPacket::Registered(address),
+            RendezvousPacket::Introduce("lobby".to_owned()),
+            RendezvousPacket::Peer("[2001:db8::1]:4000".parse().unwrap()),
+            RendezvousPacket::Unknown("lobby".to_owned()),
+            RendezvousPacket::Punch,
+            RendezvousPacket::Taken("lobby".to_owned()),
+        ] {
+            assert_eq!(RendezvousPacket::decode(&packet.encode()), Some(packet));
+        }
+        let mut bad_address = RENDEZVOUS_MAGIC.to_vec();
+        bad_address.push(OP_PEER);
+        bad_address.extend_from_slice(b"nowhere");
+        assert_eq!(RendezvousPacket::decode(&bad_address), None);
+        assert_eq!(RendezvousPacket::decode(&RENDEZVOUS_MAGIC), None);
+        assert_eq!(RendezvousPacket::decode(b"not rendezvous"), None);
+    }
+
+    #[test]
+    fn live_sessions_cant_be_taken_over() {
+        let mut server = RendezvousServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_address = server.local_address().unwrap();
+        let register = |socket: &UdpSocket, server: &mut RendezvousServer| {
+            socket
+                .send_to(
+                    &RendezvousPacket::Register("lobby".to_owned()).encode(),
+                    server_address,
+                )
+                .unwrap();
+            let mut buffer = [0; MAX_RENDEZVOUS_PACKET];
+            for _ in 0..100 {
+                server.poll();
+                if let Ok(len) = socket.recv(&mut buffer) {
+                    return RendezvousPacket::decode(&buffer[..len]);
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            None
+        };
+        let bind = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_nonblocking(true).unwrap();
+            socket
+        };
+        let host = bind();
+        let host_address = host.local_addr().unwrap();
+        let hijacker = bind();
+
+        assert_eq!(
+            register(&host, &mut server),
+            Some(RendezvousPacket::Registered(host_address))
+        );
+        assert_eq!(
+            register(&hijacker, &mut server),
+            Some(RendezvousPacket::Taken("lobby".to_owned()))
+        );
+        // refreshing is still fine
+        assert_eq!(
+            register(&host, &mut server),
+            Some(RendezvousPacket::Registered(host_address))
+        );
+        assert_eq!(server.sessions["lobby"].0, host_address);
+    }
+}
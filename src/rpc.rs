@@ -0,0 +1,496 @@
+use bevy::prelude::*;
+use instant::{Duration, Instant};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, fmt, marker::PhantomData};
+
+use turbulence::message_channels::MessageChannels;
+
+use super::{
+    ConnectionHandle, MessageFlushingStrategy, NetworkError, NetworkLabel, NetworkResource,
+};
+
+pub type RpcId = u32;
+
+/// A request that can be sent with `NetworkResource::call`.
+pub trait RpcRequest: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Name identifying the procedure on the wire. Must be the same on both peers.
+    const METHOD: &'static str;
+}
+
+/// Wire message carrying RPC requests and responses.
+///
+/// Register it on a reliable channel to enable RPC on the connections:
+///
+/// ```
+/// use bevy_networking_turbulence::{
+///     ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings,
+///     ReliableChannelSettings, RpcMessage,
+/// };
+/// use std::time::Duration;
+///
+/// const RPC_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+///     channel: 0,
+///     channel_mode: MessageChannelMode::Reliable {
+///         reliability_settings: ReliableChannelSettings {
+///             bandwidth: 4096,
+///             recv_window_size: 1024,
+///             send_window_size: 1024,
+///             burst_bandwidth: 1024,
+///             init_send: 512,
+///             wakeup_time: Duration::from_millis(100),
+///             initial_rtt: Duration::from_millis(200),
+///             max_rtt: Duration::from_secs(2),
+///             rtt_update_factor: 0.1,
+///             rtt_resend_factor: 1.5,
+///         },
+///         max_message_len: 1024,
+///     },
+///     message_buffer_size: 8,
+///     packet_buffer_size: 8,
+/// };
+///
+/// fn channels_builder(builder: &mut ConnectionChannelsBuilder) {
+///     builder.register::<RpcMessage>(RPC_MESSAGE_SETTINGS).unwrap();
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcMessage {
+    id: RpcId,
+    body: RpcBody,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum RpcBody {
+    Request { method: String, payload: Vec<u8> },
+    Response(Vec<u8>),
+    Error(RpcError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// no response arrived within the RPC timeout
+    Timeout,
+    /// connection went away before the response arrived
+    Disconnected,
+    /// `call` on the handle of a closed connection
+    StaleHandle(ConnectionHandle),
+    /// `call` on a handle which never referred to a connection
+    NoSuchConnection(ConnectionHandle),
+    /// peer has no handler registered for the method
+    NoHandler(String),
+    /// handler returned an error
+    Handler(String),
+    /// request or response could not be (de)serialized
+    Codec(String),
+    /// `RpcMessage` is not registered or its channel is full
+    Channel(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "RPC timed out"),
+            RpcError::Disconnected => write!(f, "RPC connection lost"),
+            RpcError::StaleHandle(handle) => write!(f, "connection [{}] is gone", handle),
+            RpcError::NoSuchConnection(handle) => write!(f, "no connection [{}]", handle),
+            RpcError::NoHandler(method) => write!(f, "no RPC handler for `{}`", method),
+            RpcError::Handler(error) => write!(f, "RPC handler error: {}", error),
+            RpcError::Codec(error) => write!(f, "RPC codec error: {}", error),
+            RpcError::Channel(error) => write!(f, "RPC channel error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Pending call returned by `NetworkResource::call`, poll it with `NetworkResource::poll_rpc`.
+#[derive(Debug)]
+pub struct RpcCall<Resp> {
+    pub handle: ConnectionHandle,
+    pub id: RpcId,
+    _response: PhantomData<fn() -> Resp>,
+}
+
+type RpcHandler =
+    Box<dyn Fn(ConnectionHandle, &[u8]) -> Result<Vec<u8>, RpcError> + Send + Sync + 'static>;
+
+struct PendingCall {
+    sent: Instant,
+    /// when the result arrived
+    completed: Option<Instant>,
+    result: Option<Result<Vec<u8>, RpcError>>,
+}
+
+impl PendingCall {
+    fn complete(&mut self, now: Instant, result: Result<Vec<u8>, RpcError>) {
+        self.completed = Some(now);
+        self.result = Some(result);
+    }
+}
+
+pub(crate) struct RpcState {
+    next_id: RpcId,
+    timeout: Duration,
+    pending: HashMap<(ConnectionHandle, RpcId), PendingCall>,
+    handlers: HashMap<String, RpcHandler>,
+}
+
+impl Default for RpcState {
+    fn default() -> Self {
+        RpcState {
+            next_id: 0,
+            timeout: Duration::from_secs(10),
+            pending: HashMap::new(),
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl RpcState {
+    fn track(&mut self, handle: ConnectionHandle, id: RpcId, now: Instant) {
+        self.pending.insert(
+            (handle, id),
+            PendingCall {
+                sent: now,
+                completed: None,
+                result: None,
+            },
+        );
+    }
+
+    fn cancel(&mut self, handle: ConnectionHandle, id: RpcId) {
+        self.pending.remove(&(handle, id));
+    }
+
+    /// Takes the result of a call once it arrived
+    fn poll(&mut self, handle: ConnectionHandle, id: RpcId) -> Option<Result<Vec<u8>, RpcError>> {
+        let key = (handle, id);
+        self.pending.get(&key)?.result.as_ref()?;
+        self.pending.remove(&key)?.result
+    }
+
+    /// Handles an RPC message of the peer on `handle`, returning the response to a request
+    fn receive(
+        &mut self,
+        handle: ConnectionHandle,
+        message: RpcMessage,
+        now: Instant,
+    ) -> Option<RpcMessage> {
+        match message.body {
+            RpcBody::Request { method, payload } => {
+                let body = match self.handlers.get(&method) {
+                    Some(handler) => match handler(handle, &payload) {
+                        Ok(response) => RpcBody::Response(response),
+                        Err(err) => RpcBody::Error(err),
+                    },
+                    None => {
+                        warn!("No RPC handler for `{}` on [{}]", method, handle);
+                        RpcBody::Error(RpcError::NoHandler(method))
+                    }
+                };
+                return Some(RpcMessage {
+                    id: message.id,
+                    body,
+                });
+            }
+            RpcBody::Response(payload) => {
+                if let Some(call) = self.pending.get_mut(&(handle, message.id)) {
+                    call.complete(now, Ok(payload));
+                }
+            }
+            RpcBody::Error(err) => {
+                if let Some(call) = self.pending.get_mut(&(handle, message.id)) {
+                    call.complete(now, Err(err));
+                }
+            }
+        }
+        None
+    }
+
+    /// Fails calls which timed out or lost their connection, and drops results nobody polled
+    fn expire(&mut self, now: Instant, connected: impl Fn(&ConnectionHandle) -> bool) {
+        let timeout = self.timeout;
+        self.pending.retain(|(handle, _id), call| {
+            if let Some(completed) = call.completed {
+                // nobody is polling for it
+                return now.duration_since(completed) <= timeout;
+            }
+            if !connected(handle) {
+                call.complete(now, Err(RpcError::Disconnected));
+            } else if now.duration_since(call.sent) > timeout {
+                call.complete(now, Err(RpcError::Timeout));
+            }
+            true
+        });
+    }
+}
+
+fn send_rpc(
+    channels: &mut MessageChannels,
+    message: RpcMessage,
+    flushing_strategy: MessageFlushingStrategy,
+) -> Result<(), RpcError> {
+    match channels.try_send(message) {
+        Ok(None) => {
+            if flushing_strategy == MessageFlushingStrategy::OnEverySend {
+                channels.flush::<RpcMessage>();
+            }
+            Ok(())
+        }
+        Ok(Some(_unsent)) => Err(RpcError::Channel("channel is full".to_string())),
+        Err(err) => Err(RpcError::Channel(err.to_string())),
+    }
+}
+
+impl<L> NetworkResource<L> {
+    /// How long to wait for a response before failing the call with `RpcError::Timeout`
+    pub fn set_rpc_timeout(&mut self, timeout: Duration) {
+        self.rpc.timeout = timeout;
+    }
+
+    /// Registers the handler answering `Req` requests from peers.
+    /// Returning `Err` sends `RpcError::Handler` back to the caller.
+    pub fn add_rpc_handler<Req, Resp, F>(&mut self, handler: F)
+    where
+        Req: RpcRequest,
+        Resp: Serialize,
+        F: Fn(ConnectionHandle, Req) -> Result<Resp, String> + Send + Sync + 'static,
+    {
+        self.rpc.handlers.insert(
+            Req::METHOD.to_string(),
+            Box::new(move |handle, payload| {
+                let request = bincode::deserialize::<Req>(payload)
+                    .map_err(|err| RpcError::Codec(err.to_string()))?;
+                let response = handler(handle, request).map_err(RpcError::Handler)?;
+                bincode::serialize(&response).map_err(|err| RpcError::Codec(err.to_string()))
+            }),
+        );
+    }
+
+    pub fn remove_rpc_handler<Req: RpcRequest>(&mut self) {
+        self.rpc.handlers.remove(Req::METHOD);
+    }
+
+    /// Sends `request` to the peer. The response is collected with `poll_rpc`.
+    pub fn call<Req: RpcRequest, Resp: DeserializeOwned>(
+        &mut self,
+        handle: ConnectionHandle,
+        request: Req,
+    ) -> Result<RpcCall<Resp>, RpcError> {
+        if !self.connections.contains_key(&handle) {
+            return Err(match self.handle_error(handle) {
+                NetworkError::StaleHandle(handle) => RpcError::StaleHandle(handle),
+                _ => RpcError::NoSuchConnection(handle),
+            });
+        }
+        let payload =
+            bincode::serialize(&request).map_err(|err| RpcError::Codec(err.to_string()))?;
+        let id = self.rpc.next_id;
+        self.rpc.next_id = self.rpc.next_id.wrapping_add(1);
+
+        let flushing_strategy = self.message_flushing_strategy;
+        let channels = self
+            .connections
+            .get_mut(&handle)
+            .expect("checked above")
+            .channels()
+            .ok_or_else(|| RpcError::Channel("connection has no channels".to_string()))?;
+        send_rpc(
+            channels,
+            RpcMessage {
+                id,
+                body: RpcBody::Request {
+                    method: Req::METHOD.to_string(),
+                    payload,
+                },
+            },
+            flushing_strategy,
+        )?;
+
+        self.rpc.track(handle, id, Instant::now());
+        Ok(RpcCall {
+            handle,
+            id,
+            _response: PhantomData,
+        })
+    }
+
+    /// Returns the call result once it is available. After returning `Some` the call
+    /// is forgotten, so subsequent polls return `None`. Results not polled within the
+    /// RPC timeout after they arrived are dropped.
+    pub fn poll_rpc<Resp: DeserializeOwned>(
+        &mut self,
+        call: &RpcCall<Resp>,
+    ) -> Option<Result<Resp, RpcError>> {
+        let result = self.rpc.poll(call.handle, call.id)?;
+        Some(result.and_then(|payload| {
+            bincode::deserialize(&payload).map_err(|err| RpcError::Codec(err.to_string()))
+        }))
+    }
+
+    /// Drops a pending call, a late response to it will be ignored.
+    pub fn cancel_rpc<Resp>(&mut self, call: RpcCall<Resp>) {
+        self.rpc.cancel(call.handle, call.id);
+    }
+}
+
+/// Dispatches incoming RPC requests to handlers, collects responses and expires calls.
+pub fn process_rpc<L: NetworkLabel>(mut net: ResMut<NetworkResource<L>>) {
+    let NetworkResource {
+        connections,
+        rpc,
+        message_flushing_strategy,
+        ..
+    } = &mut *net;
+
+    let now = Instant::now();
+    for (handle, connection) in connections.iter_mut() {
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        // if `RpcMessage` is not registered try_recv errors and we skip the connection
+        while let Ok(Some(message)) = channels.try_recv::<RpcMessage>() {
+            if let Some(response) = rpc.receive(*handle, message, now) {
+                if let Err(err) = send_rpc(channels, response, *message_flushing_strategy) {
+                    error!("Failed RPC response to [{}]: {}", handle, err);
+                }
+            }
+        }
+    }
+
+    rpc.expire(now, |handle| connections.contains_key(handle));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Double(u32);
+
+    impl RpcRequest for Double {
+        const METHOD: &'static str = "double";
+    }
+
+    fn handle() -> ConnectionHandle {
+        ConnectionHandle::new(0, 0)
+    }
+
+    fn request(id: RpcId, method: &str, payload: Vec<u8>) -> RpcMessage {
+        RpcMessage {
+            id,
+            body: RpcBody::Request {
+                method: method.to_string(),
+                payload,
+            },
+        }
+    }
+
+    /// Answers `Double` requests
+    fn server() -> RpcState {
+        let mut state = RpcState::default();
+        state.handlers.insert(
+            Double::METHOD.to_string(),
+            Box::new(|_handle, payload| {
+                let Double(n) = bincode::deserialize(payload).unwrap();
+                n.checked_mul(2)
+                    .ok_or_else(|| RpcError::Handler("overflow".to_string()))
+                    .map(|n| bincode::serialize(&n).unwrap())
+            }),
+        );
+        state
+    }
+
+    #[test]
+    fn responses_complete_calls() {
+        let now = Instant::now();
+        let mut client = RpcState::default();
+        let mut server = server();
+        client.track(handle(), 1, now);
+        client.track(handle(), 2, now);
+        client.track(handle(), 3, now);
+        assert!(client.poll(handle(), 1).is_none());
+
+        let payload = bincode::serialize(&Double(21)).unwrap();
+        for message in [
+            request(1, Double::METHOD, payload),
+            request(
+                2,
+                Double::METHOD,
+                bincode::serialize(&Double(u32::MAX)).unwrap(),
+            ),
+            request(3, "triple", Vec::new()),
+        ] {
+            let response = server.receive(handle(), message, now).unwrap();
+            assert!(client.receive(handle(), response, now).is_none());
+        }
+
+        let answer = client.poll(handle(), 1).unwrap().unwrap();
+        assert_eq!(bincode::deserialize::<u32>(&answer).unwrap(), 42);
+        assert_eq!(
+            client.poll(handle(), 2),
+            Some(Err(RpcError::Handler("overflow".to_string())))
+        );
+        assert_eq!(
+            client.poll(handle(), 3),
+            Some(Err(RpcError::NoHandler("triple".to_string())))
+        );
+        // results are handed out once
+        assert!(client.poll(handle(), 1).is_none());
+        assert!(client.pending.is_empty());
+    }
+
+    #[test]
+    fn unanswered_calls_time_out() {
+        let now = Instant::now();
+        let mut state = RpcState::default();
+        state.track(handle(), 0, now);
+        state.expire(now + state.timeout, |_| true);
+        assert!(state.poll(handle(), 0).is_none());
+        state.expire(now + state.timeout * 2, |_| true);
+        assert_eq!(state.poll(handle(), 0), Some(Err(RpcError::Timeout)));
+    }
+
+    #[test]
+    fn calls_fail_with_their_connection() {
+        let now = Instant::now();
+        let mut state = RpcState::default();
+        state.track(handle(), 0, now);
+        state.expire(now, |_| false);
+        assert_eq!(state.poll(handle(), 0), Some(Err(RpcError::Disconnected)));
+    }
+
+    #[test]
+    fn cancelled_calls_ignore_late_responses() {
+        let now = Instant::now();
+        let mut state = RpcState::default();
+        state.track(handle(), 0, now);
+        state.cancel(handle(), 0);
+        let response = RpcMessage {
+            id: 0,
+            body: RpcBody::Response(Vec::new()),
+        };
+        assert!(state.receive(handle(), response, now).is_none());
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn unpolled_results_expire() {
+        let now = Instant::now();
+        let mut state = RpcState::default();
+        state.track(handle(), 0, now);
+        let later = now + state.timeout / 2;
+        let response = RpcMessage {
+            id: 0,
+            body: RpcBody::Response(Vec::new()),
+        };
+        state.receive(handle(), response, later);
+
+        // kept for a timeout after arriving, though the call is older than that by then
+        state.expire(later + state.timeout, |_| true);
+        assert_eq!(state.pending.len(), 1);
+        state.expire(later + state.timeout * 2, |_| true);
+        assert!(state.pending.is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+use bevy::prelude::*;
+use std::{any::TypeId, cmp::Ordering, collections::HashMap, fmt::Debug};
+
+use turbulence::message_channels::{ChannelMessage, MessageChannels};
+
+use super::{ConnectionHandle, NetworkLabel, NetworkResource};
+
+/// What to do with a scheduled message that did not fit into the current tick's budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// keep it queued and try again next tick, with increased priority
+    Defer,
+    /// drop it - useful for unreliable state that will be superseded next tick anyway
+    Drop,
+}
+
+/// Least priority a deferred message gains per tick, so zero or negative priorities age too
+const MIN_PRIORITY_GAIN: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessagePriority {
+    /// Priority gained every tick a message waits in the queue.
+    /// Higher priority messages get sent first, but low priority ones are never starved,
+    /// as their accumulated priority keeps growing. Priorities below a small positive
+    /// minimum still grow by that minimum.
+    pub priority: f32,
+    pub overflow: Overflow,
+}
+
+impl MessagePriority {
+    pub fn deferred(priority: f32) -> Self {
+        MessagePriority {
+            priority,
+            overflow: Overflow::Defer,
+        }
+    }
+
+    pub fn droppable(priority: f32) -> Self {
+        MessagePriority {
+            priority,
+            overflow: Overflow::Drop,
+        }
+    }
+}
+
+type SendFn = Box<dyn FnMut(&mut MessageChannels) -> SendOutcome + Send + Sync>;
+
+enum SendOutcome {
+    Sent,
+    ChannelFull,
+    Failed,
+}
+
+struct QueuedMessage {
+    size: usize,
+    priority: MessagePriority,
+    accumulated: f32,
+    message_type: TypeId,
+    flush: fn(&mut MessageChannels),
+    send: SendFn,
+}
+
+#[derive(Default)]
+pub(crate) struct SendScheduler {
+    default_budget: Option<usize>,
+    budgets: HashMap<ConnectionHandle, usize>,
+    queues: HashMap<ConnectionHandle, Vec<QueuedMessage>>,
+}
+
+fn flush_channel<M: ChannelMessage>(channels: &mut MessageChannels) {
+    channels.flush::<M>();
+}
+
+impl<L> NetworkResource<L> {
+    /// Maximum number of (serialized message) bytes scheduled messages may use per tick
+    /// on every connection. Unlimited if None.
+    pub fn set_send_budget(&mut self, bytes_per_tick: Option<usize>) {
+        self.scheduler.default_budget = bytes_per_tick;
+    }
+
+    /// Overrides the send budget for a single connection. `None` restores the default.
+    pub fn set_connection_send_budget(
+        &mut self,
+        handle: ConnectionHandle,
+        bytes_per_tick: Option<usize>,
+    ) {
+        match bytes_per_tick {
+            Some(budget) => self.scheduler.budgets.insert(handle, budget),
+            None => self.scheduler.budgets.remove(&handle),
+        };
+    }
+
+    /// Queues a message to be sent by the `send_scheduled_messages` system, which sends
+    /// queued messages in priority order within the connection send budget.
+    pub fn schedule_message<M: ChannelMessage + Debug + Clone>(
+        &mut self,
+        handle: ConnectionHandle,
+        message: M,
+        priority: MessagePriority,
+    ) -> Result<(), Box<dyn std::error::Error + Send>> {
+        if !self.connections.contains_key(&handle) {
+            return Err(Box::new(self.handle_error(handle)));
+        }
+        let size = bincode::serialized_size(&message)
+            .map_err(|err| -> Box<dyn std::error::Error + Send> { err })?
+            as usize;
+        let mut message = Some(message);
+        let send: SendFn = Box::new(move |channels| {
+            let pending = match message.take() {
+                Some(pending) => pending,
+                None => return SendOutcome::Sent,
+            };
+            match channels.try_send(pending) {
+                Ok(None) => SendOutcome::Sent,
+                Ok(Some(unsent)) => {
+                    message = Some(unsent);
+                    SendOutcome::ChannelFull
+                }
+                Err(err) => {
+                    error!("Failed to send scheduled message: {}", err);
+                    SendOutcome::Failed
+                }
+            }
+        });
+        self.scheduler
+            .queues
+            .entry(handle)
+            .or_default()
+            .push(QueuedMessage {
+                size,
+                priority,
+                accumulated: priority.priority,
+                message_type: TypeId::of::<M>(),
+                flush: flush_channel::<M>,
+                send,
+            });
+        Ok(())
+    }
+
+    pub fn broadcast_scheduled_message<M: ChannelMessage + Debug + Clone>(
+        &mut self,
+        message: M,
+        priority: MessagePriority,
+    ) {
+        let handles: Vec<ConnectionHandle> = self.connections.keys().copied().collect();
+        for handle in handles {
+            if let Err(err) = self.schedule_message(handle, message.clone(), priority) {
+                error!("Failed scheduled broadcast to [{}]: {:?}", handle, err);
+            }
+        }
+    }
+
+    /// Number of messages waiting in the send queue of a connection
+    pub fn scheduled_messages(&self, handle: ConnectionHandle) -> usize {
+        self.scheduler
+            .queues
+            .get(&handle)
+            .map_or(0, |queue| queue.len())
+    }
+}
+
+/// Sends scheduled messages, highest accumulated priority first, until the budget
+/// of each connection is used up. Add it late in the frame, i.e. in `CoreStage::PostUpdate`.
+pub fn send_scheduled_messages<L: NetworkLabel>(mut net: ResMut<NetworkResource<L>>) {
+    let NetworkResource {
+        connections,
+        scheduler,
+        ..
+    } = &mut *net;
+
+    scheduler
+        .queues
+        .retain(|handle, queue| connections.contains_key(handle) && !queue.is_empty());
+    scheduler
+        .budgets
+        .retain(|handle, _| connections.contains_key(handle));
+
+    for (handle, queue) in scheduler.queues.iter_mut() {
+        let channels = match connections
+            .get_mut(handle)
+            .and_then(|connection| connection.channels())
+        {
+            Some(channels) => channels,
+            None => continue,
+        };
+        let budget = scheduler
+            .budgets
+            .get(handle)
+            .copied()
+            .or(scheduler.default_budget);
+
+        let mut to_flush: HashMap<TypeId, fn(&mut MessageChannels)> = HashMap::new();
+        send_queue(queue, budget, |queued| {
+            let outcome = (queued.send)(channels);
+            if let SendOutcome::Sent = outcome {
+                to_flush.insert(queued.message_type, queued.flush);
+            }
+            outcome
+        });
+
+        for flush in to_flush.values() {
+            flush(channels);
+        }
+    }
+}
+
+/// Passes queued messages to `send`, highest accumulated priority first, until the budget
+/// is used up. Deferred messages that didn't make it age, the others get dropped.
+fn send_queue(
+    queue: &mut Vec<QueuedMessage>,
+    budget: Option<usize>,
+    mut send: impl FnMut(&mut QueuedMessage) -> SendOutcome,
+) {
+    queue.sort_by(|a, b| {
+        b.accumulated
+            .partial_cmp(&a.accumulated)
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let mut used = 0;
+    let mut deferred = Vec::with_capacity(queue.len());
+    for mut queued in queue.drain(..) {
+        let fits = match budget {
+            // always let one message through, so oversized ones do not get stuck forever
+            Some(budget) => used == 0 || used + queued.size <= budget,
+            None => true,
+        };
+        if fits {
+            match send(&mut queued) {
+                SendOutcome::Sent => {
+                    used += queued.size;
+                    continue;
+                }
+                SendOutcome::Failed => continue,
+                SendOutcome::ChannelFull => {}
+            }
+        }
+        if queued.priority.overflow == Overflow::Defer {
+            queued.accumulated += queued.priority.priority.max(MIN_PRIORITY_GAIN);
+            deferred.push(queued);
+        }
+    }
+    *queue = deferred;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queued message told apart by its size
+    fn queued(size: usize, priority: MessagePriority) -> QueuedMessage {
+        QueuedMessage {
+            size,
+            priority,
+            accumulated: priority.priority,
+            message_type: TypeId::of::<u32>(),
+            flush: flush_channel::<u32>,
+            send: Box::new(|_| SendOutcome::Sent),
+        }
+    }
+
+    /// Sizes of the messages sent in a tick
+    fn tick(queue: &mut Vec<QueuedMessage>, budget: Option<usize>) -> Vec<usize> {
+        let mut sent = Vec::new();
+        send_queue(queue, budget, |queued| {
+            sent.push(queued.size);
+            SendOutcome::Sent
+        });
+        sent
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let mut queue = vec![
+            queued(1, MessagePriority::deferred(1.0)),
+            queued(3, MessagePriority::deferred(3.0)),
+            queued(2, MessagePriority::droppable(2.0)),
+        ];
+        assert_eq!(tick(&mut queue, None), [3, 2, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn budget_cuts_off_the_tick() {
+        let mut queue = vec![
+            queued(40, MessagePriority::deferred(3.0)),
+            queued(50, MessagePriority::deferred(2.0)),
+            queued(10, MessagePriority::deferred(1.0)),
+        ];
+        // smaller messages still fill what is left
+        assert_eq!(tick(&mut queue, Some(60)), [40, 10]);
+        assert_eq!(tick(&mut queue, Some(60)), [50]);
+
+        // one message gets through however large it is
+        let mut queue = vec![queued(100, MessagePriority::deferred(1.0))];
+        assert_eq!(tick(&mut queue, Some(60)), [100]);
+    }
+
+    #[test]
+    fn droppable_messages_do_not_wait() {
+        let mut queue = vec![
+            queued(50, MessagePriority::deferred(3.0)),
+            queued(20, MessagePriority::droppable(2.0)),
+            queued(30, MessagePriority::deferred(1.0)),
+        ];
+        assert_eq!(tick(&mut queue, Some(50)), [50]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(tick(&mut queue, Some(50)), [30]);
+
+        // a full channel counts as not fitting
+        let mut queue = vec![
+            queued(1, MessagePriority::deferred(1.0)),
+            queued(2, MessagePriority::droppable(1.0)),
+        ];
+        send_queue(&mut queue, None, |_| SendOutcome::ChannelFull);
+        assert_eq!(tick(&mut queue, None), [1]);
+    }
+
+    #[test]
+    fn zero_priorities_age_too() {
+        for priority in [0.0, -1.0] {
+            let mut queue = vec![queued(1, MessagePriority::deferred(priority))];
+            let mut ticks = 0;
+            loop {
+                // a fresh message outranking it every tick, with room for just one
+                queue.push(queued(2, MessagePriority::deferred(0.5)));
+                if tick(&mut queue, Some(2)) == [1] {
+                    break;
+                }
+                ticks += 1;
+                assert!(ticks < 1000, "priority {} starved", priority);
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::HashMap, marker::PhantomData};
+
+use super::{connect::DEFAULT_CONNECT_TIMEOUT_MS, ConnectionHandle, DefaultNetwork};
+
+/// Timeouts and heartbeats of a single connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionSettings {
+    /// Disconnect if no packets received in this number of milliseconds
+    pub idle_timeout_ms: Option<usize>,
+    /// Send a heartbeat packet if nothing else was sent in this number of milliseconds
+    pub auto_heartbeat_ms: Option<usize>,
+}
+
+impl ConnectionSettings {
+    fn is_enabled(&self) -> bool {
+        self.idle_timeout_ms.is_some() || self.auto_heartbeat_ms.is_some()
+    }
+}
+
+/// Runtime adjustable timeouts and heartbeats, read by `heartbeats_and_timeouts` on every check.
+///
+/// Initialized from the `NetworkingPlugin` fields. Each labelled network instance has its own.
+#[derive(Debug, Clone)]
+pub struct NetworkSettings<L = DefaultNetwork> {
+    /// Disconnect if no packets received in this number of milliseconds
+    pub idle_timeout_ms: Option<usize>,
+    /// Send a heartbeat packet if nothing else was sent in this number of milliseconds
+    pub auto_heartbeat_ms: Option<usize>,
+    /// How often `heartbeats_and_timeouts` checks for idle connections and sends heartbeats.
+    /// Does not need to be every frame.
+    pub heartbeats_and_timeouts_timestep_in_seconds: f64,
+    /// Send `NetworkEvent::ConnectFailed` if the server doesn't answer `connect` within this number of milliseconds
+    pub connect_timeout_ms: Option<usize>,
+    overrides: HashMap<ConnectionHandle, ConnectionSettings>,
+    label: PhantomData<fn() -> L>,
+}
+
+impl<L> Default for NetworkSettings<L> {
+    fn default() -> Self {
+        NetworkSettings {
+            idle_timeout_ms: None,
+            auto_heartbeat_ms: None,
+            heartbeats_and_timeouts_timestep_in_seconds: 0.5,
+            connect_timeout_ms: Some(DEFAULT_CONNECT_TIMEOUT_MS),
+            overrides: HashMap::new(),
+            label: PhantomData,
+        }
+    }
+}
+
+impl<L> NetworkSettings<L> {
+    /// Effective settings of a connection - its override, or the global settings
+    pub fn connection(&self, handle: ConnectionHandle) -> ConnectionSettings {
+        self.overrides
+            .get(&handle)
+            .copied()
+            .unwrap_or(ConnectionSettings {
+                idle_timeout_ms: self.idle_timeout_ms,
+                auto_heartbeat_ms: self.auto_heartbeat_ms,
+            })
+    }
+
+    /// Overrides the global settings for a connection, eg. a longer timeout for a client on a loading screen.
+    /// Overrides are dropped when the connection goes away.
+    pub fn set_connection(&mut self, handle: ConnectionHandle, settings: ConnectionSettings) {
+        self.overrides.insert(handle, settings);
+    }
+
+    /// Makes the connection use the global settings again
+    pub fn clear_connection(&mut self, handle: ConnectionHandle) {
+        self.overrides.remove(&handle);
+    }
+
+    /// Whether any connection may need heartbeats or timeout checks
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.idle_timeout_ms.is_some()
+            || self.auto_heartbeat_ms.is_some()
+            || self.overrides.values().any(ConnectionSettings::is_enabled)
+    }
+
+    pub(crate) fn has_stale_overrides<F: Fn(&ConnectionHandle) -> bool>(
+        &self,
+        is_alive: F,
+    ) -> bool {
+        self.overrides.keys().any(|handle| !is_alive(handle))
+    }
+
+    pub(crate) fn retain_overrides<F: Fn(&ConnectionHandle) -> bool>(&mut self, is_alive: F) {
+        self.overrides.retain(|handle, _| is_alive(handle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_beat_the_global_settings() {
+        let mut settings = NetworkSettings::<DefaultNetwork> {
+            idle_timeout_ms: Some(3000),
+            ..Default::default()
+        };
+        let handle = ConnectionHandle::new(0, 0);
+        let other = ConnectionHandle::new(1, 0);
+        let loading = ConnectionSettings {
+            idle_timeout_ms: Some(30000),
+            auto_heartbeat_ms: None,
+        };
+        settings.set_connection(handle, loading);
+        assert_eq!(settings.connection(handle), loading);
+        assert_eq!(settings.connection(other).idle_timeout_ms, Some(3000));
+
+        // global changes leave overrides alone
+        settings.idle_timeout_ms = Some(1000);
+        assert_eq!(settings.connection(handle), loading);
+        assert_eq!(settings.connection(other).idle_timeout_ms, Some(1000));
+
+        settings.clear_connection(handle);
+        assert_eq!(settings.connection(handle).idle_timeout_ms, Some(1000));
+    }
+
+    #[test]
+    fn overrides_alone_enable_checks() {
+        let mut settings = NetworkSettings::<DefaultNetwork>::default();
+        assert!(!settings.is_enabled());
+        let handle = ConnectionHandle::new(0, 0);
+        settings.set_connection(
+            handle,
+            ConnectionSettings {
+                idle_timeout_ms: Some(1000),
+                auto_heartbeat_ms: None,
+            },
+        );
+        assert!(settings.is_enabled());
+
+        assert!(settings.has_stale_overrides(|_| false));
+        settings.retain_overrides(|_| false);
+        assert!(!settings.is_enabled());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Path naia's WebRTC clients post their session offer to
+const SESSION_PATH: &str = "/new_rtc_session";
+
+/// Server's reply to a `probe_webrtc_session` offer
+#[derive(Debug, Clone)]
+pub struct WebRtcSessionAnswer {
+    /// HTTP status code
+    pub status: u16,
+    /// JSON body with the SDP answer and ICE candidate
+    pub body: String,
+    /// Address from the ICE candidate, where the server expects WebRTC data
+    pub candidate: Option<SocketAddr>,
+    /// ICE username fragment of the server, the first half of STUN usernames
+    pub ice_ufrag: Option<String>,
+    /// SHA-256 fingerprint of the server's DTLS certificate, as `AB:CD:...`
+    pub fingerprint: Option<String>,
+}
+
+/// Runs the signalling step of a browser client against a `use-webrtc` server, natively.
+///
+/// Posts a data channel offer to the session server at `server_address` (the `socket_address`
+/// given to `listen`) and returns the answer, so headless tests can check the WebRTC server
+/// is up and advertises the expected candidate. DTLS, SCTP and the data channel itself
+/// are not negotiated here, `WebRtcTransport` of the `webrtc-client` feature opens one.
+pub fn probe_webrtc_session(
+    server_address: SocketAddr,
+    timeout: Duration,
+) -> io::Result<WebRtcSessionAnswer> {
+    let (offer, _ice_ufrag) = session_offer();
+    request_session(server_address, &offer, timeout)
+}
+
+/// Posts `offer` to the session server and parses its answer
+pub(crate) fn request_session(
+    server_address: SocketAddr,
+    offer: &str,
+    timeout: Duration,
+) -> io::Result<WebRtcSessionAnswer> {
+    let mut stream = TcpStream::connect_timeout(&server_address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        SESSION_PATH,
+        server_address,
+        offer.len(),
+        offer
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid_data("truncated HTTP response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_data("malformed HTTP status line"))?;
+
+    Ok(WebRtcSessionAnswer {
+        status,
+        candidate: parse_candidate(body),
+        ice_ufrag: sdp_attribute(body, "a=ice-ufrag:"),
+        fingerprint: sdp_attribute(body, "a=fingerprint:sha-256 "),
+        body: body.to_owned(),
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Minimal data channel offer and its ICE username fragment, unique enough for loopback
+pub(crate) fn session_offer() -> (String, String) {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let fingerprint = (0..32)
+        .map(|i| format!("{:02X}", (nanos >> (i % 16 * 8)) as u8))
+        .collect::<Vec<_>>()
+        .join(":");
+    let ice_ufrag = format!("{:08x}", nanos as u32);
+    let offer = format!(
+        "v=0\r\n\
+         o=- {} 2 IN IP4 127.0.0.1\r\n\
+         s=-\r\n\
+         t=0 0\r\n\
+         a=group:BUNDLE 0\r\n\
+         m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+         c=IN IP4 0.0.0.0\r\n\
+         a=ice-ufrag:{}\r\n\
+         a=ice-pwd:{:032x}\r\n\
+         a=fingerprint:sha-256 {}\r\n\
+         a=setup:actpass\r\n\
+         a=mid:0\r\n\
+         a=sctp-port:5000\r\n",
+        nanos as u64, ice_ufrag, nanos, fingerprint
+    );
+    (offer, ice_ufrag)
+}
+
+/// Value of an SDP attribute line in the JSON answer, where line breaks are escaped
+fn sdp_attribute(body: &str, prefix: &str) -> Option<String> {
+    let value = &body[body.find(prefix)? + prefix.len()..];
+    let end = value.find(|c: char| c == '\\' || c == '"' || c.is_whitespace())?;
+    Some(value[..end].to_owned())
+}
+
+/// Address of the first `candidate:<foundation> <component> <protocol> <priority> <ip> <port>`
+fn parse_candidate(body: &str) -> Option<SocketAddr> {
+    let candidate = &body[body.find("candidate:")?..];
+    let mut fields = candidate.split_whitespace().skip(4);
+    let ip = fields.next()?.parse().ok()?;
+    let port = fields
+        .next()?
+        .trim_end_matches(|c: char| !c.is_ascii_digit())
+        .parse()
+        .ok()?;
+    Some(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,751 @@
+use bevy::{app::Events, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    ConnectionHandle, MessageFlushingStrategy, NetworkEvent, NetworkLabel, NetworkResource, Packet,
+};
+
+pub type TransferId = u32;
+
+/// Wire message carrying blob transfers.
+///
+/// Register it on a reliable channel whose `max_message_len` fits the transfer chunk size
+/// (plus a few bytes of framing):
+///
+/// ```ignore
+/// builder.register::<TransferMessage>(TRANSFER_MESSAGE_SETTINGS).unwrap();
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferMessage {
+    id: TransferId,
+    body: TransferBody,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum TransferBody {
+    Begin {
+        total_len: u64,
+    },
+    Chunk {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// sender aborted the transfer
+    Cancel,
+    /// receiver aborted the transfer
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferDirection {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    Started {
+        id: TransferId,
+        direction: TransferDirection,
+        total_len: usize,
+    },
+    Progress {
+        id: TransferId,
+        direction: TransferDirection,
+        transferred: usize,
+        total_len: usize,
+    },
+    /// incoming transfer fully reassembled
+    Received { id: TransferId, data: Packet },
+    /// outgoing transfer fully handed over to the channel
+    Sent { id: TransferId },
+    Cancelled {
+        id: TransferId,
+        direction: TransferDirection,
+    },
+}
+
+struct OutgoingTransfer {
+    data: Packet,
+    sent: usize,
+    began: bool,
+}
+
+impl OutgoingTransfer {
+    /// Hands chunks to `send` while the allowance (unlimited if None) covers them and `send`
+    /// accepts them
+    fn send_chunks(
+        &mut self,
+        id: TransferId,
+        chunk_size: usize,
+        mut allowance: Option<&mut f64>,
+        mut send: impl FnMut(TransferMessage) -> bool,
+    ) {
+        while self.sent < self.data.len() {
+            let end = (self.sent + chunk_size).min(self.data.len());
+            let len = end - self.sent;
+            if matches!(allowance, Some(ref allowance) if **allowance < len as f64) {
+                break;
+            }
+            let chunk = TransferMessage {
+                id,
+                body: TransferBody::Chunk {
+                    offset: self.sent as u64,
+                    data: self.data[self.sent..end].to_vec(),
+                },
+            };
+            if !send(chunk) {
+                break;
+            }
+            if let Some(allowance) = allowance.as_mut() {
+                **allowance -= len as f64;
+            }
+            self.sent = end;
+        }
+    }
+}
+
+struct IncomingTransfer {
+    total_len: usize,
+    /// reassembled from the start
+    data: Vec<u8>,
+    /// chunks which arrived ahead of the data before them, by offset
+    pending: BTreeMap<usize, Vec<u8>>,
+    /// bytes held in `data` and `pending`
+    buffered: usize,
+}
+
+impl IncomingTransfer {
+    fn new(total_len: usize) -> Self {
+        IncomingTransfer {
+            total_len,
+            // grows as chunks arrive, the peer may never send them
+            data: Vec::new(),
+            pending: BTreeMap::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Adds a chunk, false if it is a duplicate or doesn't fit the transfer
+    fn insert(&mut self, offset: u64, chunk: Vec<u8>) -> bool {
+        let offset = match usize::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => return false,
+        };
+        if offset < self.data.len()
+            || offset.saturating_add(chunk.len()) > self.total_len
+            || self.buffered + chunk.len() > self.total_len
+            || self.pending.contains_key(&offset)
+        {
+            return false;
+        }
+        self.buffered += chunk.len();
+        self.pending.insert(offset, chunk);
+        while let Some(chunk) = self.pending.remove(&self.data.len()) {
+            self.data.extend_from_slice(&chunk);
+        }
+        // a chunk left behind overlaps what was reassembled
+        !matches!(self.pending.keys().next(), Some(offset) if *offset < self.data.len())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.data.len() == self.total_len
+    }
+}
+
+pub(crate) struct TransferState {
+    next_id: TransferId,
+    chunk_size: usize,
+    /// incoming transfers announcing more are rejected
+    max_len: usize,
+    /// incoming transfers in progress per connection, more get rejected
+    max_incoming: usize,
+    /// bytes per second per connection, unlimited if None
+    bandwidth: Option<usize>,
+    allowance: HashMap<ConnectionHandle, f64>,
+    outgoing: HashMap<(ConnectionHandle, TransferId), OutgoingTransfer>,
+    incoming: HashMap<(ConnectionHandle, TransferId), IncomingTransfer>,
+    cancelled: Vec<(ConnectionHandle, TransferId, TransferDirection)>,
+}
+
+impl Default for TransferState {
+    fn default() -> Self {
+        TransferState {
+            next_id: 0,
+            chunk_size: 1000,
+            max_len: 16 * 1024 * 1024,
+            max_incoming: 4,
+            bandwidth: None,
+            allowance: HashMap::new(),
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            cancelled: Vec::new(),
+        }
+    }
+}
+
+impl TransferState {
+    fn cancel(&mut self, handle: ConnectionHandle, id: TransferId, direction: TransferDirection) {
+        let known = match direction {
+            TransferDirection::Outgoing => self.outgoing.remove(&(handle, id)).is_some(),
+            TransferDirection::Incoming => self.incoming.remove(&(handle, id)).is_some(),
+        };
+        if known {
+            self.cancelled.push((handle, id, direction));
+        }
+    }
+
+    /// Handles a transfer message of the peer on `handle`
+    fn receive(
+        &mut self,
+        handle: ConnectionHandle,
+        message: TransferMessage,
+        mut emit: impl FnMut(TransferEvent),
+    ) {
+        let key = (handle, message.id);
+        match message.body {
+            TransferBody::Begin { total_len } => {
+                if total_len > self.max_len as u64 {
+                    warn!(
+                        "Rejecting transfer {} of {} bytes on [{}], more than {}",
+                        message.id, total_len, handle, self.max_len
+                    );
+                    self.cancelled
+                        .push((handle, message.id, TransferDirection::Incoming));
+                    return;
+                }
+                let in_progress = self
+                    .incoming
+                    .keys()
+                    .filter(|(other, id)| *other == handle && *id != message.id)
+                    .count();
+                if in_progress >= self.max_incoming {
+                    warn!(
+                        "Rejecting transfer {} on [{}], {} already in progress",
+                        message.id, handle, in_progress
+                    );
+                    self.cancelled
+                        .push((handle, message.id, TransferDirection::Incoming));
+                    return;
+                }
+                let total_len = total_len as usize;
+                emit(TransferEvent::Started {
+                    id: message.id,
+                    direction: TransferDirection::Incoming,
+                    total_len,
+                });
+                if total_len == 0 {
+                    // no chunks will follow
+                    emit(TransferEvent::Received {
+                        id: message.id,
+                        data: Packet::new(),
+                    });
+                    return;
+                }
+                self.incoming.insert(key, IncomingTransfer::new(total_len));
+            }
+            TransferBody::Chunk { offset, data } => {
+                let transfer = match self.incoming.get_mut(&key) {
+                    Some(transfer) => transfer,
+                    // cancelled locally
+                    None => return,
+                };
+                if !transfer.insert(offset, data) {
+                    error!("Malformed transfer chunk {} on [{}]", message.id, handle);
+                    self.incoming.remove(&key);
+                    self.cancelled
+                        .push((handle, message.id, TransferDirection::Incoming));
+                    return;
+                }
+                emit(TransferEvent::Progress {
+                    id: message.id,
+                    direction: TransferDirection::Incoming,
+                    transferred: transfer.data.len(),
+                    total_len: transfer.total_len,
+                });
+                if transfer.is_complete() {
+                    let transfer = self.incoming.remove(&key).unwrap();
+                    emit(TransferEvent::Received {
+                        id: message.id,
+                        data: transfer.data.into(),
+                    });
+                }
+            }
+            TransferBody::Cancel => {
+                if self.incoming.remove(&key).is_some() {
+                    emit(TransferEvent::Cancelled {
+                        id: message.id,
+                        direction: TransferDirection::Incoming,
+                    });
+                }
+            }
+            TransferBody::Reject => {
+                if self.outgoing.remove(&key).is_some() {
+                    emit(TransferEvent::Cancelled {
+                        id: message.id,
+                        direction: TransferDirection::Outgoing,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Adds a tick of `delta` seconds to the bandwidth allowance of every connection
+    fn refill(&mut self, handles: impl Iterator<Item = ConnectionHandle>, delta: f64) {
+        let bandwidth = match self.bandwidth {
+            Some(bandwidth) => bandwidth,
+            None => return,
+        };
+        let refill = bandwidth as f64 * delta;
+        for handle in handles {
+            let allowance = self.allowance.entry(handle).or_insert(0.0);
+            // do not let idle connections hoard more than a chunk of burst
+            *allowance = (*allowance + refill).min(refill.max(self.chunk_size as f64));
+        }
+    }
+}
+
+/// Tells the peer about a transfer cancelled on this end
+fn cancel_notice(id: TransferId, direction: TransferDirection) -> TransferMessage {
+    let body = match direction {
+        TransferDirection::Outgoing => TransferBody::Cancel,
+        TransferDirection::Incoming => TransferBody::Reject,
+    };
+    TransferMessage { id, body }
+}
+
+impl<L> NetworkResource<L> {
+    /// Size of a single transfer chunk. Must fit the `max_message_len` of the channel
+    /// `TransferMessage` is registered on.
+    pub fn set_transfer_chunk_size(&mut self, chunk_size: usize) {
+        self.transfers.chunk_size = chunk_size.max(1);
+    }
+
+    /// Largest incoming transfer accepted, peers announcing more get rejected.
+    /// Defaults to 16 MiB.
+    pub fn set_max_transfer_len(&mut self, max_len: usize) {
+        self.transfers.max_len = max_len;
+    }
+
+    /// Incoming transfers a connection may have in progress at once, further ones get
+    /// rejected. Together with the max transfer len this bounds what a peer can make us
+    /// buffer. Defaults to 4.
+    pub fn set_max_incoming_transfers(&mut self, max_incoming: usize) {
+        self.transfers.max_incoming = max_incoming;
+    }
+
+    /// Limits outgoing transfers to `bandwidth` bytes per second per connection.
+    pub fn set_transfer_bandwidth(&mut self, bandwidth: Option<usize>) {
+        self.transfers.bandwidth = bandwidth;
+    }
+
+    /// Queues an arbitrarily sized payload for transfer to `handle`.
+    /// Progress is reported with `NetworkEvent::Transfer` events.
+    pub fn send_blob<P: Into<Packet>>(
+        &mut self,
+        handle: ConnectionHandle,
+        data: P,
+    ) -> Result<TransferId, Box<dyn std::error::Error + Send>> {
+        if !self.connections.contains_key(&handle) {
+            return Err(Box::new(self.handle_error(handle)));
+        }
+        let id = self.transfers.next_id;
+        self.transfers.next_id = self.transfers.next_id.wrapping_add(1);
+        self.transfers.outgoing.insert(
+            (handle, id),
+            OutgoingTransfer {
+                data: data.into(),
+                sent: 0,
+                began: false,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Aborts an outgoing or incoming transfer and notifies the peer.
+    pub fn cancel_transfer(
+        &mut self,
+        handle: ConnectionHandle,
+        id: TransferId,
+        direction: TransferDirection,
+    ) {
+        self.transfers.cancel(handle, id, direction);
+    }
+}
+
+/// Sends queued transfer chunks within the bandwidth budget and reassembles incoming ones.
+pub fn process_transfers<L: NetworkLabel>(
+    time: Res<Time>,
+    mut net: ResMut<NetworkResource<L>>,
+    mut network_events: ResMut<Events<L::Event>>,
+) {
+    let NetworkResource {
+        connections,
+        transfers,
+        message_flushing_strategy,
+        ..
+    } = &mut *net;
+    let flush = *message_flushing_strategy == MessageFlushingStrategy::OnEverySend;
+
+    // notify peers about locally cancelled transfers
+    for (handle, id, direction) in transfers.cancelled.drain(..) {
+        if let Some(channels) = connections
+            .get_mut(&handle)
+            .and_then(|connection| connection.channels())
+        {
+            if channels.try_send(cancel_notice(id, direction)).is_ok() && flush {
+                channels.flush::<TransferMessage>();
+            }
+        }
+        network_events.send(
+            NetworkEvent::Transfer(handle, TransferEvent::Cancelled { id, direction }).into(),
+        );
+    }
+
+    // receive
+    for (handle, connection) in connections.iter_mut() {
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        // if `TransferMessage` is not registered try_recv errors and we skip the connection
+        while let Ok(Some(message)) = channels.try_recv::<TransferMessage>() {
+            transfers.receive(*handle, message, |event| {
+                network_events.send(NetworkEvent::Transfer(*handle, event).into())
+            });
+        }
+    }
+
+    // drop state of closed connections
+    transfers
+        .outgoing
+        .retain(|(handle, _id), _| connections.contains_key(handle));
+    transfers
+        .incoming
+        .retain(|(handle, _id), _| connections.contains_key(handle));
+    transfers
+        .allowance
+        .retain(|handle, _| connections.contains_key(handle));
+
+    // send
+    transfers.refill(connections.keys().copied(), time.delta_seconds_f64());
+    let chunk_size = transfers.chunk_size;
+    let mut finished = Vec::new();
+    for ((handle, id), transfer) in transfers.outgoing.iter_mut() {
+        let channels = match connections
+            .get_mut(handle)
+            .and_then(|connection| connection.channels())
+        {
+            Some(channels) => channels,
+            None => continue,
+        };
+
+        if !transfer.began {
+            let begin = TransferMessage {
+                id: *id,
+                body: TransferBody::Begin {
+                    total_len: transfer.data.len() as u64,
+                },
+            };
+            match channels.try_send(begin) {
+                Ok(None) => {
+                    transfer.began = true;
+                    network_events.send(
+                        NetworkEvent::Transfer(
+                            *handle,
+                            TransferEvent::Started {
+                                id: *id,
+                                direction: TransferDirection::Outgoing,
+                                total_len: transfer.data.len(),
+                            },
+                        )
+                        .into(),
+                    );
+                }
+                // channel is full, retry next tick
+                Ok(Some(_)) => continue,
+                Err(err) => {
+                    error!("Transfer channel error on [{}]: {}", handle, err);
+                    continue;
+                }
+            }
+        }
+
+        let sent_before = transfer.sent;
+        transfer.send_chunks(
+            *id,
+            chunk_size,
+            transfers.allowance.get_mut(handle),
+            |chunk| match channels.try_send(chunk) {
+                Ok(None) => true,
+                // channel is full, retry next tick
+                Ok(Some(_)) => false,
+                Err(err) => {
+                    error!("Transfer channel error on [{}]: {}", handle, err);
+                    false
+                }
+            },
+        );
+        if flush {
+            channels.flush::<TransferMessage>();
+        }
+
+        if transfer.sent != sent_before {
+            network_events.send(
+                NetworkEvent::Transfer(
+                    *handle,
+                    TransferEvent::Progress {
+                        id: *id,
+                        direction: TransferDirection::Outgoing,
+                        transferred: transfer.sent,
+                        total_len: transfer.data.len(),
+                    },
+                )
+                .into(),
+            );
+        }
+        if transfer.sent == transfer.data.len() {
+            finished.push((*handle, *id));
+        }
+    }
+    for (handle, id) in finished {
+        transfers.outgoing.remove(&(handle, id));
+        network_events.send(NetworkEvent::Transfer(handle, TransferEvent::Sent { id }).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> ConnectionHandle {
+        ConnectionHandle::new(0, 0)
+    }
+
+    fn outgoing(len: usize) -> OutgoingTransfer {
+        OutgoingTransfer {
+            data: (0..len).map(|byte| byte as u8).collect::<Vec<_>>().into(),
+            sent: 0,
+            began: true,
+        }
+    }
+
+    fn begin(id: TransferId, total_len: u64) -> TransferMessage {
+        TransferMessage {
+            id,
+            body: TransferBody::Begin { total_len },
+        }
+    }
+
+    fn chunk(id: TransferId, offset: u64, data: &[u8]) -> TransferMessage {
+        TransferMessage {
+            id,
+            body: TransferBody::Chunk {
+                offset,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    /// Feeds messages to `state`, returning the events they caused
+    fn receive(
+        state: &mut TransferState,
+        messages: impl IntoIterator<Item = TransferMessage>,
+    ) -> Vec<TransferEvent> {
+        let mut events = Vec::new();
+        for message in messages {
+            state.receive(handle(), message, |event| events.push(event));
+        }
+        events
+    }
+
+    fn received(events: &[TransferEvent]) -> Option<&Packet> {
+        events.iter().find_map(|event| match event {
+            TransferEvent::Received { data, .. } => Some(data),
+            _ => None,
+        })
+    }
+
+    fn rejected(state: &TransferState) -> Vec<TransferId> {
+        state
+            .cancelled
+            .iter()
+            .filter(|(_, _, direction)| *direction == TransferDirection::Incoming)
+            .map(|(_, id, _)| *id)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_reassemble() {
+        let mut transfer = outgoing(2500);
+        let mut chunks = Vec::new();
+        transfer.send_chunks(3, 1000, None, |chunk| {
+            chunks.push(chunk);
+            true
+        });
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(transfer.sent, 2500);
+
+        let mut state = TransferState::default();
+        let events = receive(
+            &mut state,
+            std::iter::once(begin(3, 2500)).chain(chunks.clone()),
+        );
+        assert_eq!(received(&events), Some(&transfer.data));
+        assert!(state.incoming.is_empty());
+
+        // chunks overtaking each other are held back until the gap is filled
+        chunks.reverse();
+        let events = receive(&mut state, std::iter::once(begin(3, 2500)).chain(chunks));
+        assert_eq!(received(&events), Some(&transfer.data));
+        assert!(state.incoming.is_empty());
+        assert!(state.cancelled.is_empty());
+    }
+
+    #[test]
+    fn empty_transfers_need_no_chunks() {
+        let mut state = TransferState::default();
+        let events = receive(&mut state, [begin(0, 0)]);
+        assert_eq!(received(&events), Some(&Packet::new()));
+        assert!(state.incoming.is_empty());
+    }
+
+    #[test]
+    fn oversized_transfers_are_rejected() {
+        let mut state = TransferState {
+            max_len: 100,
+            ..Default::default()
+        };
+        let events = receive(&mut state, [begin(0, 100), begin(1, 101)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(rejected(&state), [1]);
+        assert!(state.incoming.contains_key(&(handle(), 0)));
+    }
+
+    #[test]
+    fn concurrent_transfers_are_capped() {
+        let mut state = TransferState {
+            max_incoming: 2,
+            ..Default::default()
+        };
+        receive(&mut state, [begin(0, 10), begin(1, 10), begin(2, 10)]);
+        assert_eq!(rejected(&state), [2]);
+
+        // finishing one makes room again
+        receive(&mut state, [chunk(0, 0, &[0; 10]), begin(3, 10)]);
+        assert_eq!(rejected(&state), [2]);
+        assert!(state.incoming.contains_key(&(handle(), 3)));
+
+        // other connections have their own share
+        let other = ConnectionHandle::new(1, 0);
+        state.receive(other, begin(4, 10), |_| {});
+        assert!(state.incoming.contains_key(&(other, 4)));
+    }
+
+    #[test]
+    fn malformed_chunks_reject_the_transfer() {
+        for bad in [
+            // past the announced end
+            chunk(0, 8, &[0; 4]),
+            // duplicate
+            chunk(0, 0, &[0; 4]),
+            // overlapping what was received
+            chunk(0, 2, &[0; 4]),
+        ] {
+            let mut state = TransferState::default();
+            let events = receive(&mut state, [begin(0, 10), chunk(0, 0, &[0; 4]), bad]);
+            assert!(received(&events).is_none());
+            assert!(state.incoming.is_empty());
+            assert_eq!(rejected(&state), [0]);
+        }
+
+        // duplicate of a chunk held back
+        let mut state = TransferState::default();
+        receive(
+            &mut state,
+            [begin(0, 10), chunk(0, 4, &[0; 2]), chunk(0, 4, &[0; 2])],
+        );
+        assert_eq!(rejected(&state), [0]);
+
+        // chunks of unknown transfers are dropped
+        let mut state = TransferState::default();
+        assert!(receive(&mut state, [chunk(5, 0, &[0; 4])]).is_empty());
+        assert!(state.cancelled.is_empty());
+    }
+
+    #[test]
+    fn cancelling_notifies_the_peer() {
+        let mut sender = TransferState::default();
+        sender.outgoing.insert((handle(), 0), outgoing(10));
+        let mut receiver = TransferState::default();
+        receive(&mut receiver, [begin(0, 10), chunk(0, 0, &[0; 4])]);
+
+        sender.cancel(handle(), 0, TransferDirection::Outgoing);
+        assert!(sender.outgoing.is_empty());
+        let (_, id, direction) = sender.cancelled.pop().unwrap();
+        let events = receive(&mut receiver, [cancel_notice(id, direction)]);
+        assert!(matches!(
+            events[..],
+            [TransferEvent::Cancelled {
+                id: 0,
+                direction: TransferDirection::Incoming
+            }]
+        ));
+        assert!(receiver.incoming.is_empty());
+
+        // rejections go the other way
+        sender.outgoing.insert((handle(), 1), outgoing(10));
+        receive(&mut receiver, [begin(1, 10)]);
+        receiver.cancel(handle(), 1, TransferDirection::Incoming);
+        let (_, id, direction) = receiver.cancelled.pop().unwrap();
+        let events = receive(&mut sender, [cancel_notice(id, direction)]);
+        assert!(matches!(
+            events[..],
+            [TransferEvent::Cancelled {
+                id: 1,
+                direction: TransferDirection::Outgoing
+            }]
+        ));
+        assert!(sender.outgoing.is_empty());
+
+        // unknown transfers aren't announced
+        sender.cancel(handle(), 7, TransferDirection::Outgoing);
+        assert!(sender.cancelled.is_empty());
+    }
+
+    #[test]
+    fn bandwidth_limits_chunks_per_tick() {
+        let mut state = TransferState {
+            chunk_size: 500,
+            bandwidth: Some(2000),
+            ..Default::default()
+        };
+        let mut transfer = outgoing(5000);
+
+        let tick = |state: &mut TransferState, transfer: &mut OutgoingTransfer| {
+            state.refill(std::iter::once(handle()), 0.5);
+            let mut chunks = 0;
+            transfer.send_chunks(0, 500, state.allowance.get_mut(&handle()), |_| {
+                chunks += 1;
+                true
+            });
+            chunks
+        };
+        assert_eq!(tick(&mut state, &mut transfer), 2);
+        assert_eq!(tick(&mut state, &mut transfer), 2);
+        assert_eq!(transfer.sent, 2000);
+
+        // a full channel keeps the allowance for later
+        state.refill(std::iter::once(handle()), 0.5);
+        transfer.send_chunks(0, 500, state.allowance.get_mut(&handle()), |_| false);
+        assert_eq!(transfer.sent, 2000);
+        assert_eq!(state.allowance[&handle()], 1000.0);
+
+        // unlimited without a bandwidth
+        state.bandwidth = None;
+        state.allowance.clear();
+        assert_eq!(tick(&mut state, &mut transfer), 6);
+        assert_eq!(transfer.sent, 5000);
+    }
+}
@@ -1,48 +1,152 @@
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(
+    not(target_arch = "wasm32"),
+    any(not(feature = "std-udp"), feature = "naia-server-socket")
+))]
 use bevy::tasks::Task;
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
 use bevy::{prelude::error, tasks::TaskPool};
 use bytes::Bytes;
 use instant::{Duration, Instant};
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
+use std::sync::{Arc, RwLock};
 use std::{
+    any::TypeId,
+    collections::{hash_map::Entry, HashMap, HashSet},
     error::Error,
     net::SocketAddr,
-    sync::{Arc, RwLock},
 };
 
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
 use naia_client_socket::{
     ClientSocketTrait, MessageSender as ClientSender, Packet as ClientPacket,
 };
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 use naia_server_socket::{MessageSender as ServerSender, Packet as ServerPacket};
 
 use turbulence::{
     buffer::BufferPacketPool,
-    message_channels::{MessageChannels, MessageChannelsBuilder},
+    message_channels::{
+        ChannelAlreadyRegistered, ChannelMessage, MessageChannelSettings, MessageChannels,
+        MessageChannelsBuilder,
+    },
     packet::PacketPool,
     packet_multiplexer::{IncomingMultiplexedPackets, MuxPacket, MuxPacketPool, PacketMultiplexer},
 };
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 use futures_lite::future::block_on;
 
-use futures_lite::StreamExt;
+use futures_lite::{future, StreamExt};
+use futures_timer::Delay;
 
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
+use super::capture::{CaptureDirection, CaptureSlot};
 use super::{
+    capture::ConnectionCapture,
     channels::{SimpleBufferPool, TaskPoolRuntime},
-    NetworkError,
+    codec::{Codec, Encoded, EncodedChannelSettings, EncodedChannels},
+    compression::PacketCompressor,
+    conditioner::{ConditionedLink, ConnectionConditioner},
+    ListenerId, NetworkError,
 };
 
 pub type Packet = Bytes;
 pub type MultiplexedPacket = MuxPacket<<BufferPacketPool<SimpleBufferPool> as PacketPool>::Packet>;
-pub type ConnectionChannelsBuilder =
-    MessageChannelsBuilder<TaskPoolRuntime, MuxPacketPool<BufferPacketPool<SimpleBufferPool>>>;
+pub(crate) type ChannelsPacketPool = MuxPacketPool<BufferPacketPool<SimpleBufferPool>>;
+
+/// Registers the message types carried by the channels of each connection,
+/// see `NetworkResource::set_channels_builder`
+pub struct ConnectionChannelsBuilder {
+    messages: MessageChannelsBuilder<TaskPoolRuntime, ChannelsPacketPool>,
+    runtime: TaskPoolRuntime,
+    pool: ChannelsPacketPool,
+    channels: HashSet<u8>,
+    encoded: HashMap<TypeId, EncodedChannelSettings>,
+}
+
+impl ConnectionChannelsBuilder {
+    pub fn new(runtime: TaskPoolRuntime, pool: ChannelsPacketPool) -> Self {
+        ConnectionChannelsBuilder {
+            messages: MessageChannelsBuilder::new(runtime.clone(), pool.clone()),
+            runtime,
+            pool,
+            channels: HashSet::new(),
+            encoded: HashMap::new(),
+        }
+    }
+
+    /// Registers a message type serialized with bincode, see `MessageChannelsBuilder::register`
+    pub fn register<M: ChannelMessage>(
+        &mut self,
+        settings: MessageChannelSettings,
+    ) -> Result<(), ChannelAlreadyRegistered> {
+        if self.channels.contains(&settings.channel) {
+            return Err(ChannelAlreadyRegistered::Channel);
+        }
+        let channel = settings.channel;
+        self.messages.register::<M>(settings)?;
+        self.channels.insert(channel);
+        Ok(())
+    }
+
+    /// Registers a message type carried in codec `C` format alone, without bincode framing.
+    /// Use it through `Connection::encoded_channels`. Compressed channel settings are
+    /// treated as reliable ones, with `max_chunk_len` as the maximum message length.
+    pub fn register_encoded<M: 'static, C: Codec<M>>(
+        &mut self,
+        settings: MessageChannelSettings,
+    ) -> Result<(), ChannelAlreadyRegistered> {
+        if self.channels.contains(&settings.channel) {
+            return Err(ChannelAlreadyRegistered::Channel);
+        }
+        match self.encoded.entry(TypeId::of::<Encoded<M, C>>()) {
+            Entry::Occupied(_) => Err(ChannelAlreadyRegistered::MessageType),
+            Entry::Vacant(vacant) => {
+                self.channels.insert(settings.channel);
+                vacant.insert(EncodedChannelSettings::new::<M, C>(settings));
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn build(
+        self,
+        multiplexer: &mut PacketMultiplexer<MultiplexedPacket>,
+    ) -> (MessageChannels, EncodedChannels) {
+        let messages = self.messages.build(multiplexer);
+        let encoded = EncodedChannels::build(self.encoded, self.runtime, self.pool, multiplexer);
+        (messages, encoded)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PacketStats {
     pub packets_tx: usize,
     pub packets_rx: usize,
+    /// bytes sent on the wire
     pub bytes_tx: usize,
+    /// bytes received from the wire
     pub bytes_rx: usize,
+    /// bytes sent before compression
+    pub payload_bytes_tx: usize,
+    /// bytes received after decompression
+    pub payload_bytes_rx: usize,
     pub last_tx: Instant,
     pub last_rx: Instant,
 }
@@ -58,6 +162,8 @@ impl Default for PacketStats {
             packets_rx: 0,
             bytes_tx: 0,
             bytes_rx: 0,
+            payload_bytes_tx: 0,
+            payload_bytes_rx: 0,
             last_tx: now,
             last_rx: now,
         }
@@ -65,18 +171,36 @@ impl Default for PacketStats {
 }
 
 impl PacketStats {
-    fn add_tx(&mut self, num_bytes: usize) {
+    pub(crate) fn add_tx(&mut self, num_bytes: usize, payload_bytes: usize) {
         self.packets_tx += 1;
         self.bytes_tx += num_bytes;
+        self.payload_bytes_tx += payload_bytes;
         self.last_tx = Instant::now();
     }
-    fn add_rx(&mut self, num_bytes: usize) {
+    pub(crate) fn add_rx(&mut self, num_bytes: usize, payload_bytes: usize) {
         self.packets_rx += 1;
         self.bytes_rx += num_bytes;
+        self.payload_bytes_rx += payload_bytes;
         self.last_rx = Instant::now();
     }
+    /// wire bytes / payload bytes sent - below 1.0 when compression pays off
+    pub fn compression_ratio_tx(&self) -> f32 {
+        if self.payload_bytes_tx == 0 {
+            1.0
+        } else {
+            self.bytes_tx as f32 / self.payload_bytes_tx as f32
+        }
+    }
+    /// wire bytes / payload bytes received - below 1.0 when compression pays off
+    pub fn compression_ratio_rx(&self) -> f32 {
+        if self.payload_bytes_rx == 0 {
+            1.0
+        } else {
+            self.bytes_rx as f32 / self.payload_bytes_rx as f32
+        }
+    }
     // returns Duration since last (rx, tx)
-    fn idle_durations(&self) -> (Duration, Duration) {
+    pub(crate) fn idle_durations(&self) -> (Duration, Duration) {
         let now = Instant::now();
         let rx = now.duration_since(self.last_rx);
         let tx = now.duration_since(self.last_tx);
@@ -84,9 +208,26 @@ impl PacketStats {
     }
 }
 
+/// What carries the packets of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    Udp,
+    WebRtc,
+    /// TCP fallback, see `WebSocketTransport`
+    WebSocket,
+    /// in-memory connection of a listen server and its local client
+    Local,
+    /// packets read from a capture
+    Replay,
+    /// custom `Transport` or `Listener` backend
+    Custom(&'static str),
+}
+
 pub trait Connection: Send + Sync {
     fn remote_address(&self) -> Option<SocketAddr>;
 
+    fn transport(&self) -> TransportKind;
+
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>>;
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>>;
@@ -102,69 +243,169 @@ pub trait Connection: Send + Sync {
 
     fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>>;
 
+    /// Channels of message types registered with `ConnectionChannelsBuilder::register_encoded`
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        None
+    }
+
     fn stats(&self) -> PacketStats;
 
     /// returns milliseconds since last (rx, tx)
     fn last_packet_timings(&self) -> (u128, u128);
+
+    /// Records traffic of this connection into a packet capture, or stops recording if None
+    fn set_capture(&mut self, _capture: Option<ConnectionCapture>) {}
+
+    /// Applies a link conditioner to traffic of this connection, or passes it through unchanged if None
+    fn set_link_conditioner(&mut self, _conditioner: Option<ConnectionConditioner>) {}
+
+    /// Sends packets the link conditioner is holding back right away, eg. a disconnect
+    /// notice before the connection is dropped
+    fn flush_link(&mut self) {}
+
+    /// Listener the connection arrived on, None for outgoing connections
+    fn listener(&self) -> Option<ListenerId> {
+        None
+    }
+
+    /// Compression state, for the compression handshake
+    fn compressor(&self) -> Option<&PacketCompressor> {
+        None
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+/// turbulence channel a packet belongs to, for packet capture
+pub(crate) fn packet_channel(has_channels: bool, packet: &[u8]) -> Option<u8> {
+    if has_channels {
+        packet.first().copied()
+    } else {
+        None
+    }
+}
+
+/// Waits for the next outgoing channels packet, or until a packet held back by
+/// the link conditioner is due. Returns None on timeout.
+pub(crate) async fn next_outgoing<S: futures_lite::Stream + Unpin>(
+    channels_tx: &mut S,
+    link: &ConditionedLink,
+) -> Option<Option<S::Item>> {
+    match link.next_outbound_due() {
+        Some(due) => {
+            let now = Instant::now();
+            let timeout = Delay::new(if due > now {
+                due.duration_since(now)
+            } else {
+                Duration::from_secs(0)
+            });
+            future::or(async { Some(channels_tx.next().await) }, async {
+                timeout.await;
+                None
+            })
+            .await
+        }
+        None => Some(channels_tx.next().await),
+    }
+}
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 pub struct ServerConnection {
     task_pool: TaskPool,
 
     packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
-    sender: Option<ServerSender>,
+    sender: ServerSender,
+    /// moved to the channels task once channels are built
+    channels_sender: Option<ServerSender>,
     client_address: SocketAddr,
+    listener: ListenerId,
     stats: Arc<RwLock<PacketStats>>,
+    compressor: Arc<PacketCompressor>,
+    capture: CaptureSlot,
+    link: ConditionedLink,
 
     channels: Option<MessageChannels>,
+    encoded_channels: Option<EncodedChannels>,
     channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
     #[cfg(not(target_arch = "wasm32"))]
     channels_task: Option<Task<()>>,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 impl ServerConnection {
     pub fn new(
         task_pool: TaskPool,
         packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
         sender: ServerSender,
+        channels_sender: ServerSender,
         client_address: SocketAddr,
+        listener: ListenerId,
+        compressor: Arc<PacketCompressor>,
     ) -> Self {
         ServerConnection {
             task_pool,
             packet_rx,
-            sender: Some(sender),
+            sender,
+            channels_sender: Some(channels_sender),
             client_address,
+            listener,
             stats: Arc::new(RwLock::new(PacketStats::default())),
+            compressor,
+            capture: CaptureSlot::default(),
+            link: ConditionedLink::default(),
             channels: None,
+            encoded_channels: None,
             channels_rx: None,
             channels_task: None,
         }
     }
+
+    /// Sends raw packets the link conditioner let through
+    fn flush_outbound(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        while let Some(packet) = self.link.pop_outbound() {
+            block_on(
+                self.sender
+                    .send(ServerPacket::new(self.client_address, packet.to_vec())),
+            )?;
+        }
+        Ok(())
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "naia-server-socket"))]
 impl Connection for ServerConnection {
     fn remote_address(&self) -> Option<SocketAddr> {
         Some(self.client_address)
     }
 
+    fn transport(&self) -> TransportKind {
+        if cfg!(feature = "use-webrtc") {
+            TransportKind::WebRtc
+        } else {
+            TransportKind::Udp
+        }
+    }
+
+    fn listener(&self) -> Option<ListenerId> {
+        Some(self.listener)
+    }
+
+    fn compressor(&self) -> Option<&PacketCompressor> {
+        Some(&self.compressor)
+    }
+
     fn stats(&self) -> PacketStats {
         self.stats.read().expect("stats lock poisoned").clone()
     }
 
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.capture
+            .record(CaptureDirection::Outbound, None, &payload);
+        let packet = self.compressor.compress(&payload);
         self.stats
             .write()
             .expect("stats lock poisoned")
-            .add_tx(payload.len());
-        block_on(
-            self.sender
-                .as_mut()
-                .unwrap()
-                .send(ServerPacket::new(self.client_address, payload.to_vec())),
-        )
+            .add_tx(packet.len(), payload.len());
+        self.link.push_outbound(Packet::from(packet));
+        self.flush_outbound()
     }
 
     fn last_packet_timings(&self) -> (u128, u128) {
@@ -177,23 +418,35 @@ impl Connection for ServerConnection {
     }
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
-        match self.packet_rx.try_recv() {
-            Ok(payload) => match payload {
-                Ok(packet) => {
-                    self.stats
-                        .write()
-                        .expect("stats lock poisoned")
-                        .add_rx(packet.len());
-                    Some(Ok(packet))
-                }
-                Err(err) => Some(Err(err)),
-            },
-            Err(error) => match error {
-                crossbeam_channel::TryRecvError::Empty => None,
-                crossbeam_channel::TryRecvError::Disconnected => {
-                    Some(Err(NetworkError::Disconnected))
+        if let Err(err) = self.flush_outbound() {
+            error!("Server Send Error: {}", err);
+        }
+        loop {
+            match self.packet_rx.try_recv() {
+                Ok(Ok(packet)) => self.link.push_inbound(packet),
+                Ok(Err(err)) => return Some(Err(err)),
+                Err(crossbeam_channel::TryRecvError::Empty) => break,
+                Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                    return Some(Err(NetworkError::Disconnected))
                 }
-            },
+            }
+        }
+
+        let packet = self.link.pop_inbound()?;
+        match self.compressor.decompress(&packet) {
+            Ok(payload) => {
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_rx(packet.len(), payload.len());
+                self.capture.record(
+                    CaptureDirection::Inbound,
+                    packet_channel(self.channels_rx.is_some(), &payload),
+                    &payload,
+                );
+                Some(Ok(Packet::from(payload)))
+            }
+            Err(err) => Some(Err(err)),
         }
     }
 
@@ -203,29 +456,41 @@ impl Connection for ServerConnection {
         runtime: TaskPoolRuntime,
         pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
     ) {
-        let mut builder = MessageChannelsBuilder::new(runtime, pool);
+        let mut builder = ConnectionChannelsBuilder::new(runtime, pool);
         builder_fn(&mut builder);
 
         let mut multiplexer = PacketMultiplexer::new();
-        self.channels = Some(builder.build(&mut multiplexer));
+        let (channels, encoded_channels) = builder.build(&mut multiplexer);
+        self.channels = Some(channels);
+        self.encoded_channels = Some(encoded_channels);
         let (channels_rx, mut channels_tx) = multiplexer.start();
         self.channels_rx = Some(channels_rx);
 
-        let mut sender = self.sender.take().unwrap();
+        let mut sender = self.channels_sender.take().unwrap();
         let client_address = self.client_address;
         let stats = self.stats.clone();
+        let compressor = self.compressor.clone();
+        let capture = self.capture.clone();
+        let link = self.link.clone();
 
         self.channels_task = Some(self.task_pool.spawn(async move {
             loop {
-                let packet = channels_tx.next().await.unwrap();
-                stats
-                    .write()
-                    .expect("stats lock poisoned")
-                    .add_tx(packet.len());
-                sender
-                    .send(ServerPacket::new(client_address, (*packet).into()))
-                    .await
-                    .unwrap();
+                if let Some(packet) = next_outgoing(&mut channels_tx, &link).await {
+                    let packet = packet.unwrap();
+                    capture.record(CaptureDirection::Outbound, packet.first().copied(), &packet);
+                    let compressed = compressor.compress(&packet);
+                    stats
+                        .write()
+                        .expect("stats lock poisoned")
+                        .add_tx(compressed.len(), packet.len());
+                    link.push_outbound(Packet::from(compressed));
+                }
+                while let Some(packet) = link.pop_outbound() {
+                    sender
+                        .send(ServerPacket::new(client_address, packet.to_vec()))
+                        .await
+                        .unwrap();
+                }
             }
         }));
     }
@@ -237,43 +502,109 @@ impl Connection for ServerConnection {
     fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
         self.channels_rx.as_mut()
     }
+
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        self.encoded_channels.as_mut()
+    }
+
+    fn set_capture(&mut self, capture: Option<ConnectionCapture>) {
+        self.capture.set(capture);
+    }
+
+    fn set_link_conditioner(&mut self, conditioner: Option<ConnectionConditioner>) {
+        self.link.set(conditioner);
+    }
+    fn flush_link(&mut self) {
+        self.link.release_outbound();
+        if let Err(err) = self.flush_outbound() {
+            error!("Server Send Error: {}", err);
+        }
+    }
 }
 
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
 pub struct ClientConnection {
     task_pool: TaskPool,
 
     socket: Box<dyn ClientSocketTrait>,
-    sender: Option<ClientSender>,
+    sender: ClientSender,
+    server_address: SocketAddr,
     stats: Arc<RwLock<PacketStats>>,
+    compressor: Arc<PacketCompressor>,
+    capture: CaptureSlot,
+    link: ConditionedLink,
 
     channels: Option<MessageChannels>,
+    encoded_channels: Option<EncodedChannels>,
     channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
     #[cfg(not(target_arch = "wasm32"))]
     channels_task: Option<Task<()>>,
 }
 
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
 impl ClientConnection {
     pub fn new(
         task_pool: TaskPool,
         socket: Box<dyn ClientSocketTrait>,
         sender: ClientSender,
+        server_address: SocketAddr,
+        compressor: Arc<PacketCompressor>,
     ) -> Self {
         ClientConnection {
             task_pool,
             socket,
-            sender: Some(sender),
+            sender,
+            server_address,
             stats: Arc::new(RwLock::new(PacketStats::default())),
+            compressor,
+            capture: CaptureSlot::default(),
+            link: ConditionedLink::default(),
             channels: None,
+            encoded_channels: None,
             channels_rx: None,
             #[cfg(not(target_arch = "wasm32"))]
             channels_task: None,
         }
     }
+
+    /// Sends raw packets the link conditioner let through
+    fn flush_outbound(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        while let Some(packet) = self.link.pop_outbound() {
+            self.sender.send(ClientPacket::new(packet.to_vec()))?;
+        }
+        Ok(())
+    }
 }
 
+#[cfg(any(
+    target_arch = "wasm32",
+    not(feature = "std-udp"),
+    feature = "naia-server-socket"
+))]
 impl Connection for ClientConnection {
     fn remote_address(&self) -> Option<SocketAddr> {
-        None
+        Some(self.server_address)
+    }
+
+    fn compressor(&self) -> Option<&PacketCompressor> {
+        Some(&self.compressor)
+    }
+
+    fn transport(&self) -> TransportKind {
+        // naia client sockets use WebRTC in the browser and plain UDP natively
+        if cfg!(target_arch = "wasm32") {
+            TransportKind::WebRtc
+        } else {
+            TransportKind::Udp
+        }
     }
 
     fn stats(&self) -> PacketStats {
@@ -290,27 +621,46 @@ impl Connection for ClientConnection {
     }
 
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.capture
+            .record(CaptureDirection::Outbound, None, &payload);
+        let packet = self.compressor.compress(&payload);
         self.stats
             .write()
             .expect("stats lock poisoned")
-            .add_tx(payload.len());
-        self.sender
-            .as_mut()
-            .unwrap()
-            .send(ClientPacket::new(payload.to_vec()))
+            .add_tx(packet.len(), payload.len());
+        self.link.push_outbound(Packet::from(packet));
+        self.flush_outbound()
     }
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
-        match self.socket.receive() {
-            Ok(event) => event.map(|packet| {
-                self.stats
-                    .write()
-                    .expect("stats lock poisoned")
-                    .add_rx(packet.payload().len());
-                Ok(Packet::copy_from_slice(packet.payload()))
-            }),
-            Err(err) => Some(Err(NetworkError::IoError(Box::new(err)))),
+        if let Err(err) = self.flush_outbound() {
+            error!("Client Send Error: {}", err);
         }
+        loop {
+            match self.socket.receive() {
+                Ok(Some(packet)) => self
+                    .link
+                    .push_inbound(Packet::copy_from_slice(packet.payload())),
+                Ok(None) => break,
+                Err(err) => return Some(Err(NetworkError::IoError(Box::new(err)))),
+            }
+        }
+
+        let packet = self.link.pop_inbound()?;
+        let payload = match self.compressor.decompress(&packet) {
+            Ok(payload) => payload,
+            Err(err) => return Some(Err(err)),
+        };
+        self.stats
+            .write()
+            .expect("stats lock poisoned")
+            .add_rx(packet.len(), payload.len());
+        self.capture.record(
+            CaptureDirection::Inbound,
+            packet_channel(self.channels_rx.is_some(), &payload),
+            &payload,
+        );
+        Some(Ok(Packet::from(payload)))
     }
 
     fn build_channels(
@@ -319,31 +669,47 @@ impl Connection for ClientConnection {
         runtime: TaskPoolRuntime,
         pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
     ) {
-        let mut builder = MessageChannelsBuilder::new(runtime, pool);
+        let mut builder = ConnectionChannelsBuilder::new(runtime, pool);
         builder_fn(&mut builder);
 
         let mut multiplexer = PacketMultiplexer::new();
-        self.channels = Some(builder.build(&mut multiplexer));
+        let (channels, encoded_channels) = builder.build(&mut multiplexer);
+        self.channels = Some(channels);
+        self.encoded_channels = Some(encoded_channels);
         let (channels_rx, mut channels_tx) = multiplexer.start();
         self.channels_rx = Some(channels_rx);
 
-        let mut sender = self.sender.take().unwrap();
+        let mut sender = self.socket.get_sender();
         let stats = self.stats.clone();
+        let compressor = self.compressor.clone();
+        let capture = self.capture.clone();
+        let link = self.link.clone();
 
         let closure = async move {
             loop {
-                match channels_tx.next().await {
-                    Some(packet) => {
+                match next_outgoing(&mut channels_tx, &link).await {
+                    Some(Some(packet)) => {
+                        capture.record(
+                            CaptureDirection::Outbound,
+                            packet.first().copied(),
+                            &packet,
+                        );
+                        let compressed = compressor.compress(&packet);
                         stats
                             .write()
                             .expect("stats lock poisoned")
-                            .add_tx(packet.len());
-                        sender.send(ClientPacket::new((*packet).into())).unwrap();
+                            .add_tx(compressed.len(), packet.len());
+                        link.push_outbound(Packet::from(compressed));
                     }
-                    None => {
+                    Some(None) => {
                         error!("Channel stream Disconnected");
                         return; // exit task
                     }
+                    // a held back packet is due
+                    None => {}
+                }
+                while let Some(packet) = link.pop_outbound() {
+                    sender.send(ClientPacket::new(packet.to_vec())).unwrap();
                 }
             }
         };
@@ -363,6 +729,24 @@ impl Connection for ClientConnection {
     fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
         self.channels_rx.as_mut()
     }
+
+    fn encoded_channels(&mut self) -> Option<&mut EncodedChannels> {
+        self.encoded_channels.as_mut()
+    }
+
+    fn set_capture(&mut self, capture: Option<ConnectionCapture>) {
+        self.capture.set(capture);
+    }
+
+    fn set_link_conditioner(&mut self, conditioner: Option<ConnectionConditioner>) {
+        self.link.set(conditioner);
+    }
+    fn flush_link(&mut self) {
+        self.link.release_outbound();
+        if let Err(err) = self.flush_outbound() {
+            error!("Client Send Error: {}", err);
+        }
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
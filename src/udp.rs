@@ -0,0 +1,496 @@
+use bevy::prelude::debug;
+#[cfg(not(feature = "naia-server-socket"))]
+use bevy::prelude::info;
+use crossbeam_channel::{unbounded, Receiver, SendError, Sender};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Weak},
+};
+
+use super::{
+    backend::{Listener, PacketSink, PacketSource, Transport, TransportLink},
+    query::QueryResponder,
+    rendezvous::{self, Registration},
+    transport::TransportKind,
+    NetworkError, Packet, MAX_DATAGRAM,
+};
+#[cfg(not(feature = "naia-server-socket"))]
+use super::{ListenerId, NetworkResource};
+
+/// Most datagrams a listener reads in one poll, so a flood can't stall the frame.
+/// Linux reads them with `recvmmsg`, elsewhere with repeated non-blocking reads.
+const RECV_BATCH: usize = 256;
+
+fn send_datagram(result: io::Result<usize>) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match result {
+        Ok(_) => Ok(()),
+        // a full send buffer drops the packet, like the network would
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Client side of the `std-udp` backend: a non-blocking `UdpSocket` connected to the server.
+///
+/// `NetworkResource::connect` uses it when the crate is built without naia's server socket
+/// (`--no-default-features --features std-udp`), otherwise pass it to `connect_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpTransport;
+
+impl Transport for UdpTransport {
+    fn connect(&self, address: SocketAddr) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        let unspecified: IpAddr = if address.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((unspecified, 0))?;
+        Ok(connected_link(socket, address)?)
+    }
+}
+
+/// Link over `socket`, talking only to `address` from now on
+pub(crate) fn connected_link(socket: UdpSocket, address: SocketAddr) -> io::Result<TransportLink> {
+    socket.connect(address)?;
+    socket.set_nonblocking(true)?;
+    let socket = Arc::new(socket);
+    Ok(TransportLink {
+        sink: Arc::new(ConnectedSink(socket.clone())),
+        source: Box::new(ConnectedSource {
+            socket,
+            remote_address: address,
+            buffer: vec![0; MAX_DATAGRAM],
+        }),
+        remote_address: Some(address),
+        kind: TransportKind::Udp,
+    })
+}
+
+struct ConnectedSink(Arc<UdpSocket>);
+
+impl PacketSink for ConnectedSink {
+    fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        send_datagram(self.0.send(packet))
+    }
+}
+
+struct ConnectedSource {
+    socket: Arc<UdpSocket>,
+    remote_address: SocketAddr,
+    buffer: Vec<u8>,
+}
+
+impl PacketSource for ConnectedSource {
+    fn try_recv(&mut self) -> Option<Result<Packet, NetworkError>> {
+        loop {
+            let len = match self.socket.recv(&mut self.buffer) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                // eg. ICMP port unreachable while the server is down
+                Err(err) => return Some(Err(NetworkError::IoError(Box::new(err)))),
+            };
+            let packet = &self.buffer[..len];
+            // punches of a rendezvous host
+            if !rendezvous::consume(None, &self.socket, self.remote_address, packet) {
+                return Some(Ok(Packet::copy_from_slice(packet)));
+            }
+        }
+    }
+}
+
+/// Server side of the `std-udp` backend: a single non-blocking `UdpSocket`,
+/// with datagrams dispatched to connections by peer address.
+///
+/// `NetworkResource::listen` uses it when the crate is built without naia's server socket,
+/// otherwise pass it to `listen_with`.
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    local_address: SocketAddr,
+    peers: HashMap<SocketAddr, Peer>,
+    accepted: VecDeque<TransportLink>,
+    #[cfg(target_os = "linux")]
+    batch: mmsg::RecvBatch,
+    #[cfg(not(target_os = "linux"))]
+    buffer: Vec<u8>,
+    /// answers `query_server`, set up by `NetworkResource::listen_with`
+    query_handler: Option<QueryResponder>,
+    registration: Option<Registration>,
+}
+
+impl UdpListener {
+    /// Port 0 picks a free ephemeral port, see `Listener::local_address`
+    pub fn bind(address: SocketAddr) -> Result<Self, NetworkError> {
+        let bind = || -> io::Result<(UdpSocket, SocketAddr)> {
+            let socket = UdpSocket::bind(address)?;
+            socket.set_nonblocking(true)?;
+            let local_address = socket.local_addr()?;
+            Ok((socket, local_address))
+        };
+        let (socket, local_address) =
+            bind().map_err(|err| NetworkError::BindError(address, err))?;
+        Ok(UdpListener {
+            socket: Arc::new(socket),
+            local_address,
+            peers: HashMap::new(),
+            accepted: VecDeque::new(),
+            #[cfg(target_os = "linux")]
+            batch: mmsg::RecvBatch::new(),
+            #[cfg(not(target_os = "linux"))]
+            buffer: vec![0; MAX_DATAGRAM],
+            query_handler: None,
+            registration: None,
+        })
+    }
+
+    /// Hosts `session` through a rendezvous server, so clients behind NAT can reach this
+    /// listener with `ConnectTarget::Rendezvous`. Registration is kept alive while polled.
+    /// A session still hosted by another listener is refused until that registration expires.
+    pub fn with_rendezvous(mut self, server: SocketAddr, session: impl Into<String>) -> Self {
+        self.registration = Some(Registration::new(server, session.into()));
+        self
+    }
+
+    fn receive_batch(&mut self) {
+        if let Some(registration) = &mut self.registration {
+            registration.refresh(&self.socket);
+        }
+        // connections which were dropped won't read their packets anymore
+        self.peers.retain(|_, peer| peer.alive.strong_count() > 0);
+
+        #[cfg(target_os = "linux")]
+        {
+            let mut received = 0;
+            while received < RECV_BATCH {
+                let datagrams = match self.batch.recv(&self.socket) {
+                    Ok(datagrams) => datagrams,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        debug!("UDP listener receive error: {}", err);
+                        break;
+                    }
+                };
+                received += datagrams.len();
+                let drained = datagrams.len() < mmsg::BATCH_LEN;
+                for (peer, packet) in datagrams {
+                    self.dispatch(peer, packet);
+                }
+                if drained {
+                    break;
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for _ in 0..RECV_BATCH {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, peer)) => {
+                    let packet = Packet::copy_from_slice(&self.buffer[..len]);
+                    self.dispatch(peer, packet);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // eg. ICMP port unreachable of a peer which went away, reported on Windows
+                Err(err) => debug!("UDP listener receive error: {}", err),
+            }
+        }
+    }
+
+    fn dispatch(&mut self, peer: SocketAddr, packet: Packet) {
+        if rendezvous::consume(self.registration.as_mut(), &self.socket, peer, &packet) {
+            return;
+        }
+        if let Some(response) = self
+            .query_handler
+            .as_ref()
+            .and_then(|handler| handler.respond(peer, &packet))
+        {
+            // connectionless queries are answered right here
+            if let Err(err) = send_datagram(self.socket.send_to(&response, peer)) {
+                debug!("Can't answer query of {}: {}", peer, err);
+            }
+            return;
+        }
+        let packet = match self.peers.get(&peer) {
+            Some(Peer { packet_tx, .. }) => match packet_tx.send(packet) {
+                Ok(()) => return,
+                // connection was dropped, the peer starts a new one
+                Err(SendError(packet)) => packet,
+            },
+            None => packet,
+        };
+        let (packet_tx, packet_rx) = unbounded();
+        packet_tx.send(packet).expect("receiver is right here");
+        let alive = Arc::new(());
+        self.peers.insert(
+            peer,
+            Peer {
+                packet_tx,
+                alive: Arc::downgrade(&alive),
+            },
+        );
+        self.accepted.push_back(TransportLink {
+            sink: Arc::new(PeerSink {
+                socket: self.socket.clone(),
+                peer,
+            }),
+            source: Box::new(PeerSource {
+                packet_rx,
+                _alive: alive,
+            }),
+            remote_address: Some(peer),
+            kind: TransportKind::Udp,
+        });
+    }
+}
+
+impl Listener for UdpListener {
+    fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    fn accept(&mut self) -> Option<TransportLink> {
+        // polled once per frame, before connections read their packets
+        self.receive_batch();
+        self.accepted.pop_front()
+    }
+
+    fn disconnected(&mut self, address: SocketAddr) {
+        self.peers.remove(&address);
+    }
+
+    fn answer_queries(&mut self, responder: QueryResponder) {
+        self.query_handler = Some(responder);
+    }
+
+    /// Our address as seen by the rendezvous server, once it answered the registration
+    fn public_address(&self) -> Option<SocketAddr> {
+        self.registration
+            .as_ref()
+            .and_then(|registration| registration.public_address())
+    }
+}
+
+/// Listener end of an accepted peer
+struct Peer {
+    packet_tx: Sender<Packet>,
+    /// gone once the connection dropped its `PeerSource`
+    alive: Weak<()>,
+}
+
+/// Packets of a single peer, dispatched by the listener
+struct PeerSource {
+    packet_rx: Receiver<Packet>,
+    _alive: Arc<()>,
+}
+
+impl PacketSource for PeerSource {
+    fn try_recv(&mut self) -> Option<Result<Packet, NetworkError>> {
+        PacketSource::try_recv(&mut self.packet_rx)
+    }
+}
+
+/// Sends to a single peer through the listener socket
+struct PeerSink {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+}
+
+impl PacketSink for PeerSink {
+    fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        send_datagram(self.socket.send_to(packet, self.peer))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+        os::unix::io::AsRawFd,
+        ptr,
+    };
+
+    use super::{Packet, MAX_DATAGRAM};
+
+    /// Datagrams read by a single `recvmmsg` call
+    pub(super) const BATCH_LEN: usize = 32;
+
+    /// Buffers for `recvmmsg`, kept between polls
+    pub(super) struct RecvBatch {
+        buffers: Vec<Vec<u8>>,
+        addresses: Vec<libc::sockaddr_storage>,
+    }
+
+    impl RecvBatch {
+        pub(super) fn new() -> Self {
+            RecvBatch {
+                buffers: vec![vec![0; MAX_DATAGRAM]; BATCH_LEN],
+                // SAFETY: all zeroes is a valid `sockaddr_storage`
+                addresses: vec![unsafe { mem::zeroed() }; BATCH_LEN],
+            }
+        }
+
+        /// Up to `BATCH_LEN` datagrams waiting on the non-blocking `socket`
+        pub(super) fn recv(&mut self, socket: &UdpSocket) -> io::Result<Vec<(SocketAddr, Packet)>> {
+            let mut iovecs: Vec<libc::iovec> = self
+                .buffers
+                .iter_mut()
+                .map(|buffer| libc::iovec {
+                    iov_base: buffer.as_mut_ptr().cast(),
+                    iov_len: buffer.len(),
+                })
+                .collect();
+            let mut headers: Vec<libc::mmsghdr> = iovecs
+                .iter_mut()
+                .zip(self.addresses.iter_mut())
+                .map(|(iovec, address)| {
+                    // SAFETY: all zeroes is a valid `mmsghdr`, the pointers are set below
+                    let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                    header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+                    header.msg_hdr.msg_namelen =
+                        mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                    header.msg_hdr.msg_iov = iovec;
+                    header.msg_hdr.msg_iovlen = 1;
+                    header
+                })
+                .collect();
+            // SAFETY: every header points to a buffer and an address owned by `self`,
+            // which outlive the call
+            let count = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    headers.len() as libc::c_uint,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                )
+            };
+            if count < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(headers[..count as usize]
+                .iter()
+                .zip(self.buffers.iter().zip(&self.addresses))
+                .filter_map(|(header, (buffer, address))| {
+                    let packet = Packet::copy_from_slice(&buffer[..header.msg_len as usize]);
+                    Some((socket_address(address)?, packet))
+                })
+                .collect())
+        }
+    }
+
+    fn socket_address(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the kernel wrote a `sockaddr_in` for this family
+                let address = unsafe {
+                    &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>()
+                };
+                let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+                Some(SocketAddr::new(ip.into(), u16::from_be(address.sin_port)))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the kernel wrote a `sockaddr_in6` for this family
+                let address = unsafe {
+                    &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+                };
+                Some(
+                    SocketAddrV6::new(
+                        Ipv6Addr::from(address.sin6_addr.s6_addr),
+                        u16::from_be(address.sin6_port),
+                        address.sin6_flowinfo,
+                        address.sin6_scope_id,
+                    )
+                    .into(),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Address of the interface with the default route. Nothing is sent.
+#[cfg(not(feature = "naia-server-socket"))]
+pub fn find_my_ip_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(8, 8, 8, 8), 80)).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+#[cfg(not(feature = "naia-server-socket"))]
+impl<L> NetworkResource<L> {
+    /// Listens for UDP connections with a `UdpListener`.
+    ///
+    /// The WebRTC addresses only matter to naia's WebRTC server, they are ignored here.
+    pub fn listen(
+        &mut self,
+        socket_address: SocketAddr,
+        _webrtc_listen_address: Option<SocketAddr>,
+        _public_webrtc_address: Option<SocketAddr>,
+    ) -> Result<ListenerId, NetworkError> {
+        let listener = UdpListener::bind(socket_address)?;
+        let local_address = listener.local_address();
+        let id = self.listen_with(listener);
+        info!("Listener {} bound to {}", id, local_address);
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    fn accept(listener: &mut UdpListener) -> TransportLink {
+        for _ in 0..1000 {
+            if let Some(link) = listener.accept() {
+                return link;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("nothing accepted");
+    }
+
+    fn listener_and_client() -> (UdpListener, UdpSocket) {
+        let listener = UdpListener::bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client.connect(listener.local_address()).unwrap();
+        (listener, client)
+    }
+
+    #[test]
+    fn batch_keeps_datagram_order() {
+        let (mut listener, client) = listener_and_client();
+        for i in 0..100u8 {
+            client.send(&[i; 3]).unwrap();
+        }
+        let mut link = accept(&mut listener);
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            while let Some(packet) = link.source.try_recv() {
+                received.push(packet.unwrap());
+            }
+            if received.len() == 100 {
+                break;
+            }
+            listener.accept();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(link.remote_address, client.local_addr().ok());
+        let expected: Vec<_> = (0..100u8).map(|i| Packet::from(vec![i; 3])).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn dropped_peers_are_forgotten() {
+        let (mut listener, client) = listener_and_client();
+        client.send(b"hello").unwrap();
+        let link = accept(&mut listener);
+        assert_eq!(listener.peers.len(), 1);
+
+        drop(link);
+        assert!(listener.accept().is_none());
+        assert!(listener.peers.is_empty());
+    }
+}
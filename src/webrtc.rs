@@ -0,0 +1,590 @@
+use bevy::prelude::debug;
+use crc::{Crc, CRC_32_ISCSI};
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use openssl::{
+    hash::MessageDigest,
+    rand::rand_bytes,
+    ssl::{ErrorCode, HandshakeError, SslConnector, SslMethod, SslStream, SslVerifyMode},
+};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    iter,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    backend::{Transport, TransportLink},
+    signalling::{request_session, session_offer},
+    transport::TransportKind,
+    Packet, MAX_DATAGRAM,
+};
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_USERNAME: u16 = 0x0006;
+const STUN_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+/// Both ends use the port of the offer's `a=sctp-port`
+const SCTP_PORT: u16 = 5000;
+const SCTP_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const SCTP_WINDOW: u32 = 0x40000;
+const SCTP_STREAMS: u16 = 16;
+
+const CHUNK_DATA: u8 = 0x00;
+const CHUNK_INIT: u8 = 0x01;
+const CHUNK_INIT_ACK: u8 = 0x02;
+const CHUNK_SACK: u8 = 0x03;
+const CHUNK_HEARTBEAT: u8 = 0x04;
+const CHUNK_HEARTBEAT_ACK: u8 = 0x05;
+const CHUNK_ABORT: u8 = 0x06;
+const CHUNK_SHUTDOWN: u8 = 0x07;
+const CHUNK_SHUTDOWN_ACK: u8 = 0x08;
+const CHUNK_COOKIE_ECHO: u8 = 0x0a;
+const CHUNK_COOKIE_ACK: u8 = 0x0b;
+
+const PARAM_STATE_COOKIE: u16 = 0x0007;
+const PARAM_FORWARD_TSN: u16 = 0xc000;
+
+/// Beginning and end of a message, not fragmented
+const DATA_COMPLETE: u8 = 0x03;
+const DATA_UNORDERED: u8 = 0x04;
+
+const PPID_CONTROL: u32 = 50;
+const PPID_BINARY: u32 = 53;
+const DCEP_OPEN: u8 = 0x03;
+const DCEP_ACK: u8 = 0x02;
+/// Unordered, without retransmits
+const DCEP_CHANNEL_UNRELIABLE: u8 = 0x81;
+
+/// How often the socket reader checks whether the channel was closed
+const READ_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Native WebRTC data channel client, for headless tests of `use-webrtc` servers.
+/// Built with the `webrtc-client` feature.
+///
+/// Pass it to `NetworkResource::connect_with` with the server's session address, the
+/// `socket_address` given to `listen`. It speaks just enough ICE, DTLS and SCTP to open an
+/// unreliable data channel with naia's WebRTC server over loopback: only the server's host
+/// candidate is tried, and lost handshake packets aren't sent again.
+#[derive(Debug, Clone)]
+pub struct WebRtcTransport {
+    /// for signalling and each handshake step
+    pub timeout: Duration,
+}
+
+impl Default for WebRtcTransport {
+    fn default() -> Self {
+        WebRtcTransport {
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Transport for WebRtcTransport {
+    fn connect(&self, address: SocketAddr) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        let (packet_tx, packet_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let timeout = self.timeout;
+        // signalling and handshakes block, failures show up as a disconnect
+        thread::Builder::new()
+            .name(format!("webrtc {}", address))
+            .spawn(move || match DataChannel::open(address, timeout) {
+                Ok(mut channel) => channel.run(packet_tx, outgoing_rx),
+                Err(err) => debug!("WebRTC connect to {} failed: {}", address, err),
+            })?;
+        Ok(TransportLink {
+            sink: Arc::new(outgoing_tx),
+            source: Box::new(packet_rx),
+            remote_address: Some(address),
+            kind: TransportKind::WebRtc,
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0; 4];
+    rand_bytes(&mut bytes).expect("OpenSSL random bytes");
+    u32::from_be_bytes(bytes)
+}
+
+/// Memory BIO of the DTLS stream, a datagram per read and write
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self
+            .incoming
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads datagrams of `socket` into a channel, until the channel or the socket is closed
+fn spawn_reader(socket: UdpSocket, closed: Arc<AtomicBool>) -> io::Result<Receiver<Vec<u8>>> {
+    let (datagram_tx, datagram_rx) = unbounded();
+    socket.set_read_timeout(Some(READ_INTERVAL))?;
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    thread::Builder::new()
+        .name("webrtc reader".to_owned())
+        .spawn(move || {
+            while !closed.load(Ordering::Relaxed) {
+                match socket.recv(&mut buffer) {
+                    Ok(len) => {
+                        if datagram_tx.send(buffer[..len].to_vec()).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) => {}
+                    Err(err) => {
+                        debug!("WebRTC receive failed: {}", err);
+                        return;
+                    }
+                }
+            }
+        })?;
+    Ok(datagram_rx)
+}
+
+/// STUN binding request, with the ICE username the server matches its session by
+fn stun_binding_request(username: &str, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let padded = username.len().div_ceil(4) * 4;
+    let mut request = Vec::with_capacity(24 + padded);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&((4 + padded) as u16).to_be_bytes());
+    request.extend_from_slice(&STUN_COOKIE);
+    request.extend_from_slice(transaction_id);
+    request.extend_from_slice(&STUN_USERNAME.to_be_bytes());
+    request.extend_from_slice(&(username.len() as u16).to_be_bytes());
+    request.extend_from_slice(username.as_bytes());
+    request.resize(24 + padded, 0);
+    request
+}
+
+/// SCTP packet with a single chunk
+fn sctp_packet(verification_tag: u32, chunk_type: u8, flags: u8, value: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + value.len() + 3);
+    packet.extend_from_slice(&SCTP_PORT.to_be_bytes());
+    packet.extend_from_slice(&SCTP_PORT.to_be_bytes());
+    packet.extend_from_slice(&verification_tag.to_be_bytes());
+    packet.extend_from_slice(&[0; 4]);
+    packet.push(chunk_type);
+    packet.push(flags);
+    packet.extend_from_slice(&((4 + value.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(value);
+    packet.resize(packet.len().div_ceil(4) * 4, 0);
+    let checksum = SCTP_CRC.checksum(&packet);
+    packet[8..12].copy_from_slice(&checksum.to_le_bytes());
+    packet
+}
+
+/// Type, flags and value of each chunk of an SCTP packet
+fn sctp_chunks(packet: &[u8]) -> impl Iterator<Item = (u8, u8, &[u8])> {
+    let mut rest = packet.get(12..).unwrap_or_default();
+    iter::from_fn(move || {
+        let len = u16::from_be_bytes([*rest.get(2)?, *rest.get(3)?]) as usize;
+        if len < 4 || len > rest.len() {
+            return None;
+        }
+        let chunk = (rest[0], rest[1], &rest[4..len]);
+        rest = rest.get(len.div_ceil(4) * 4..).unwrap_or_default();
+        Some(chunk)
+    })
+}
+
+/// Type and value of each parameter of an INIT or INIT ACK chunk
+fn sctp_params(mut rest: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    iter::from_fn(move || {
+        let param_type = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+        let len = u16::from_be_bytes([*rest.get(2)?, *rest.get(3)?]) as usize;
+        if len < 4 || len > rest.len() {
+            return None;
+        }
+        let param = (param_type, &rest[4..len]);
+        rest = rest.get(len.div_ceil(4) * 4..).unwrap_or_default();
+        Some(param)
+    })
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Established data channel: DTLS over the UDP socket, SCTP over DTLS
+struct DataChannel {
+    socket: UdpSocket,
+    datagrams: Receiver<Vec<u8>>,
+    /// stops the reader thread
+    closed: Arc<AtomicBool>,
+    dtls: SslStream<Datagrams>,
+    /// decrypted SCTP packets not handled yet
+    received: VecDeque<Vec<u8>>,
+    local_tag: u32,
+    remote_tag: u32,
+    next_tsn: u32,
+    /// highest TSN received, acknowledged with SACKs
+    remote_tsn: u32,
+    timeout: Duration,
+}
+
+impl DataChannel {
+    fn open(server_address: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let (offer, local_ufrag) = session_offer();
+        let answer = request_session(server_address, &offer, timeout)?;
+        if answer.status != 200 {
+            return Err(invalid_data("session server refused the offer"));
+        }
+        let candidate = answer
+            .candidate
+            .ok_or_else(|| invalid_data("answer without ICE candidate"))?;
+        let server_ufrag = answer
+            .ice_ufrag
+            .ok_or_else(|| invalid_data("answer without ICE username"))?;
+        let fingerprint = answer
+            .fingerprint
+            .ok_or_else(|| invalid_data("answer without certificate fingerprint"))?;
+
+        let socket = if candidate.is_ipv4() {
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+        } else {
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+        };
+        socket.connect(candidate)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let datagrams = spawn_reader(socket.try_clone()?, closed.clone())?;
+
+        // the server starts DTLS for addresses which passed a binding request
+        let mut transaction_id = [0; 12];
+        rand_bytes(&mut transaction_id).map_err(io::Error::other)?;
+        let username = format!("{}:{}", server_ufrag, local_ufrag);
+        socket.send(&stun_binding_request(&username, &transaction_id))?;
+        loop {
+            let response = datagrams
+                .recv_timeout(timeout)
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no STUN response"))?;
+            if response.len() >= 20
+                && response[..2] == STUN_BINDING_SUCCESS.to_be_bytes()
+                && response[8..20] == transaction_id
+            {
+                break;
+            }
+        }
+
+        let dtls = Self::dtls_handshake(&socket, &datagrams, timeout)?;
+        let digest = dtls
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| invalid_data("server sent no certificate"))?
+            .digest(MessageDigest::sha256())
+            .map_err(io::Error::other)?;
+        let mut peer_fingerprint = String::new();
+        for (i, byte) in digest.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ":" };
+            let _ = write!(peer_fingerprint, "{}{:02X}", separator, byte);
+        }
+        if !peer_fingerprint.eq_ignore_ascii_case(&fingerprint) {
+            return Err(invalid_data(
+                "certificate doesn't match the answer's fingerprint",
+            ));
+        }
+
+        let mut channel = DataChannel {
+            socket,
+            datagrams,
+            closed,
+            dtls,
+            received: VecDeque::new(),
+            local_tag: random_u32(),
+            remote_tag: 0,
+            next_tsn: random_u32(),
+            remote_tsn: 0,
+            timeout,
+        };
+        channel.sctp_handshake()?;
+        Ok(channel)
+    }
+
+    fn dtls_handshake(
+        socket: &UdpSocket,
+        datagrams: &Receiver<Vec<u8>>,
+        timeout: Duration,
+    ) -> io::Result<SslStream<Datagrams>> {
+        let mut builder = SslConnector::builder(SslMethod::dtls()).map_err(io::Error::other)?;
+        // checked against the fingerprint of the answer instead
+        builder.set_verify(SslVerifyMode::NONE);
+        let ssl = builder
+            .build()
+            .configure()
+            .map_err(io::Error::other)?
+            .verify_hostname(false)
+            .use_server_name_indication(false)
+            .into_ssl("")
+            .map_err(io::Error::other)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut handshake = ssl.connect(Datagrams::default());
+        loop {
+            let mut mid = match handshake {
+                Ok(dtls) => return Ok(dtls),
+                Err(HandshakeError::WouldBlock(mid)) => mid,
+                Err(HandshakeError::Failure(mid)) => {
+                    return Err(io::Error::other(mid.into_error()))
+                }
+                Err(HandshakeError::SetupFailure(err)) => return Err(io::Error::other(err)),
+            };
+            for datagram in mid.get_mut().outgoing.drain(..) {
+                socket.send(&datagram)?;
+            }
+            let datagram = datagrams
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DTLS handshake timed out"))?;
+            mid.get_mut().incoming.push_back(datagram);
+            handshake = mid.handshake();
+        }
+    }
+
+    /// Association setup, then the data channel open message
+    fn sctp_handshake(&mut self) -> io::Result<()> {
+        let mut init = Vec::with_capacity(20);
+        init.extend_from_slice(&self.local_tag.to_be_bytes());
+        init.extend_from_slice(&SCTP_WINDOW.to_be_bytes());
+        init.extend_from_slice(&SCTP_STREAMS.to_be_bytes());
+        init.extend_from_slice(&SCTP_STREAMS.to_be_bytes());
+        init.extend_from_slice(&self.next_tsn.to_be_bytes());
+        // partial reliability, required by the server
+        init.extend_from_slice(&PARAM_FORWARD_TSN.to_be_bytes());
+        init.extend_from_slice(&4u16.to_be_bytes());
+        // INIT is the only chunk sent with a zero verification tag
+        self.send_sctp(0, CHUNK_INIT, 0, &init)?;
+
+        let cookie = self.wait_for(|chunk_type, _, value| match chunk_type {
+            CHUNK_INIT_ACK if value.len() >= 16 => {
+                let cookie = sctp_params(&value[16..])
+                    .find(|(param_type, _)| *param_type == PARAM_STATE_COOKIE)?
+                    .1
+                    .to_vec();
+                Some((be_u32(&value[0..4]), be_u32(&value[12..16]), cookie))
+            }
+            _ => None,
+        })?;
+        let (remote_tag, remote_tsn, cookie) = cookie;
+        self.remote_tag = remote_tag;
+        self.remote_tsn = remote_tsn.wrapping_sub(1);
+        self.send_sctp(self.remote_tag, CHUNK_COOKIE_ECHO, 0, &cookie)?;
+        self.wait_for(|chunk_type, _, _| (chunk_type == CHUNK_COOKIE_ACK).then_some(()))?;
+
+        let label = b"bevy_networking_turbulence";
+        let mut open = vec![DCEP_OPEN, DCEP_CHANNEL_UNRELIABLE];
+        open.extend_from_slice(&0u16.to_be_bytes());
+        open.extend_from_slice(&0u32.to_be_bytes());
+        open.extend_from_slice(&(label.len() as u16).to_be_bytes());
+        open.extend_from_slice(&0u16.to_be_bytes());
+        open.extend_from_slice(label);
+        self.send_data(PPID_CONTROL, &open)?;
+        self.wait_for(|chunk_type, _, value| {
+            (chunk_type == CHUNK_DATA
+                && value.len() > 12
+                && be_u32(&value[8..12]) == PPID_CONTROL
+                && value[12] == DCEP_ACK)
+                .then_some(())
+        })
+    }
+
+    /// Handles incoming chunks until `wanted` picks one, within the timeout
+    fn wait_for<T>(&mut self, mut wanted: impl FnMut(u8, u8, &[u8]) -> Option<T>) -> io::Result<T> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            while let Some(packet) = self.received.pop_front() {
+                let mut found = None;
+                for (chunk_type, flags, value) in sctp_chunks(&packet) {
+                    match wanted(chunk_type, flags, value) {
+                        Some(result) if found.is_none() => found = Some(result),
+                        _ => self.answer_chunk(chunk_type, value, None)?,
+                    }
+                }
+                if let Some(result) = found {
+                    return Ok(result);
+                }
+            }
+            let datagram = self
+                .datagrams
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "SCTP handshake timed out"))?;
+            self.decrypt(datagram)?;
+        }
+    }
+
+    /// Moves packets between the data channel and the connection, until either side goes away
+    fn run(&mut self, packet_tx: Sender<Packet>, outgoing_rx: Receiver<Packet>) {
+        let result = loop {
+            select! {
+                recv(self.datagrams) -> datagram => {
+                    let datagram = match datagram {
+                        Ok(datagram) => datagram,
+                        Err(_) => break Ok(()),
+                    };
+                    if let Err(err) = self.receive(datagram, &packet_tx) {
+                        break Err(err);
+                    }
+                }
+                recv(outgoing_rx) -> packet => {
+                    let packet = match packet {
+                        // connection dropped
+                        Err(_) => break self.send_sctp(self.remote_tag, CHUNK_ABORT, 0, &[]),
+                        Ok(packet) => packet,
+                    };
+                    if let Err(err) = self.send_data(PPID_BINARY, &packet) {
+                        break Err(err);
+                    }
+                }
+            }
+        };
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+                debug!("WebRTC data channel closed by the server")
+            }
+            Err(err) => debug!("WebRTC data channel failed: {}", err),
+            Ok(()) => {}
+        }
+    }
+
+    fn receive(&mut self, datagram: Vec<u8>, packet_tx: &Sender<Packet>) -> io::Result<()> {
+        self.decrypt(datagram)?;
+        while let Some(packet) = self.received.pop_front() {
+            for (chunk_type, _, value) in sctp_chunks(&packet) {
+                self.answer_chunk(chunk_type, value, Some(packet_tx))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Feeds a datagram to DTLS, queueing the SCTP packets it carried
+    fn decrypt(&mut self, datagram: Vec<u8>) -> io::Result<()> {
+        self.dtls.get_mut().incoming.push_back(datagram);
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            match self.dtls.ssl_read(&mut buffer) {
+                Ok(len) => self.received.push_back(buffer[..len].to_vec()),
+                Err(err) if err.code() == ErrorCode::WANT_READ => break,
+                Err(err) if err.code() == ErrorCode::ZERO_RETURN => {
+                    return Err(io::ErrorKind::ConnectionAborted.into())
+                }
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+        // DTLS may have answered on its own
+        self.flush()
+    }
+
+    /// Usual replies to a chunk. Data goes to `packet_tx`, once the channel is open.
+    fn answer_chunk(
+        &mut self,
+        chunk_type: u8,
+        value: &[u8],
+        packet_tx: Option<&Sender<Packet>>,
+    ) -> io::Result<()> {
+        match chunk_type {
+            CHUNK_DATA if value.len() >= 12 => {
+                let tsn = be_u32(&value[0..4]);
+                if tsn.wrapping_sub(self.remote_tsn) < 1 << 31 {
+                    self.remote_tsn = tsn;
+                }
+                if let Some(packet_tx) = packet_tx {
+                    if be_u32(&value[8..12]) == PPID_BINARY {
+                        // connection dropped, noticed on its outgoing channel
+                        let _ = packet_tx.send(Packet::copy_from_slice(&value[12..]));
+                    }
+                }
+                let mut sack = Vec::with_capacity(12);
+                sack.extend_from_slice(&self.remote_tsn.to_be_bytes());
+                sack.extend_from_slice(&SCTP_WINDOW.to_be_bytes());
+                sack.extend_from_slice(&[0; 4]);
+                self.send_sctp(self.remote_tag, CHUNK_SACK, 0, &sack)
+            }
+            CHUNK_HEARTBEAT => self.send_sctp(self.remote_tag, CHUNK_HEARTBEAT_ACK, 0, value),
+            CHUNK_SHUTDOWN => {
+                self.send_sctp(self.remote_tag, CHUNK_SHUTDOWN_ACK, 0, &[])?;
+                Err(io::ErrorKind::ConnectionAborted.into())
+            }
+            CHUNK_ABORT => Err(io::ErrorKind::ConnectionAborted.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn send_data(&mut self, ppid: u32, payload: &[u8]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(12 + payload.len());
+        data.extend_from_slice(&self.next_tsn.to_be_bytes());
+        // stream 0, no sequence number as data is unordered
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&ppid.to_be_bytes());
+        data.extend_from_slice(payload);
+        self.next_tsn = self.next_tsn.wrapping_add(1);
+        self.send_sctp(
+            self.remote_tag,
+            CHUNK_DATA,
+            DATA_COMPLETE | DATA_UNORDERED,
+            &data,
+        )
+    }
+
+    fn send_sctp(
+        &mut self,
+        verification_tag: u32,
+        chunk_type: u8,
+        flags: u8,
+        value: &[u8],
+    ) -> io::Result<()> {
+        let packet = sctp_packet(verification_tag, chunk_type, flags, value);
+        self.dtls.ssl_write(&packet).map_err(io::Error::other)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for datagram in self.dtls.get_mut().outgoing.drain(..) {
+            self.socket.send(&datagram)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DataChannel {
+    fn drop(&mut self) {
+        let _ = self.dtls.shutdown();
+        let _ = self.flush();
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
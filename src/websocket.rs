@@ -0,0 +1,672 @@
+use std::{error::Error, net::SocketAddr};
+
+use super::backend::{Transport, TransportLink};
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::WebSocketListener;
+
+/// WebSocket client backend, a TCP fallback where WebRTC data channels are blocked.
+/// Pass it to `NetworkResource::connect_with`, the server needs a `WebSocketListener`.
+///
+/// Native clients only speak plain `ws://`. In the browser `secure` picks `wss://`,
+/// which needs a TLS terminating proxy in front of the server. Hostnames are passed on
+/// as they are, so the proxy's certificate can be checked against them.
+#[derive(Debug, Clone)]
+pub struct WebSocketTransport {
+    /// request path, "/" by default
+    pub path: String,
+    pub secure: bool,
+}
+
+impl Default for WebSocketTransport {
+    fn default() -> Self {
+        WebSocketTransport {
+            path: "/".to_owned(),
+            secure: false,
+        }
+    }
+}
+
+impl WebSocketTransport {
+    fn url(&self, host: &str) -> String {
+        let scheme = if self.secure { "wss" } else { "ws" };
+        format!("{}://{}{}", scheme, host, self.path)
+    }
+
+    fn open(
+        &self,
+        host: String,
+        address: Option<SocketAddr>,
+    ) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        let url = self.url(&host);
+        #[cfg(not(target_arch = "wasm32"))]
+        return native::connect(url, self.secure, host, address);
+        #[cfg(target_arch = "wasm32")]
+        return web::connect(&url, address);
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&self, address: SocketAddr) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        self.open(address.to_string(), Some(address))
+    }
+
+    fn connect_host(
+        &self,
+        host: &str,
+    ) -> Option<Result<TransportLink, Box<dyn Error + Sync + Send>>> {
+        Some(self.open(host.to_owned(), None))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use bevy::prelude::debug;
+    use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+    use polling::{Event, Poller, Source};
+    use std::{
+        collections::HashMap,
+        error::Error,
+        io,
+        net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+    use tungstenite::{
+        handshake::{
+            server::{NoCallback, ServerHandshake},
+            HandshakeError, MidHandshake,
+        },
+        Message, WebSocket,
+    };
+
+    use super::super::{
+        backend::{Listener, PacketSink, TransportLink},
+        transport::TransportKind,
+        NetworkError, Packet,
+    };
+
+    /// Poller key of the TCP listener, sockets get the keys after it
+    const LISTENER_KEY: usize = 0;
+    /// Longest an I/O thread waits without events, so stalled handshakes expire
+    const WAIT_TIMEOUT: Duration = Duration::from_millis(500);
+    /// Peers which don't finish the WebSocket handshake in time are dropped
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn would_block(err: &tungstenite::Error) -> bool {
+        matches!(err, tungstenite::Error::Io(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Queues packets for the I/O thread and wakes it up
+    struct WebSocketSink {
+        /// taken on drop, so the woken I/O thread sees the link is gone
+        outgoing_tx: Option<Sender<Packet>>,
+        poller: Arc<Poller>,
+    }
+
+    impl PacketSink for WebSocketSink {
+        fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+            self.outgoing_tx
+                .as_ref()
+                .expect("taken on drop")
+                .send(Packet::copy_from_slice(packet))
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "WebSocket closed"))?;
+            Ok(self.poller.notify()?)
+        }
+    }
+
+    impl Drop for WebSocketSink {
+        fn drop(&mut self) {
+            self.outgoing_tx.take();
+            let _ = self.poller.notify();
+        }
+    }
+
+    /// Both ends of the connection side of a link, and the I/O thread side
+    fn link_channels(
+        remote_address: Option<SocketAddr>,
+        poller: Arc<Poller>,
+    ) -> (TransportLink, (Sender<Packet>, Receiver<Packet>)) {
+        let (packet_tx, packet_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let link = TransportLink {
+            sink: Arc::new(WebSocketSink {
+                outgoing_tx: Some(outgoing_tx),
+                poller,
+            }),
+            source: Box::new(packet_rx),
+            remote_address,
+            kind: TransportKind::WebSocket,
+        };
+        (link, (packet_tx, outgoing_rx))
+    }
+
+    /// Open WebSocket and the I/O thread ends of its link
+    struct Peer {
+        socket: WebSocket<TcpStream>,
+        packet_tx: Sender<Packet>,
+        outgoing_rx: Receiver<Packet>,
+        /// the socket didn't take all queued data, wait until it's writable
+        blocked: bool,
+    }
+
+    impl Peer {
+        fn interest(&self, key: usize) -> Event {
+            Event {
+                key,
+                readable: true,
+                writable: self.blocked,
+            }
+        }
+
+        /// Passes received packets to the connection, false once either side went away
+        fn read(&mut self) -> bool {
+            loop {
+                match self.socket.read_message() {
+                    Ok(Message::Binary(data)) => {
+                        if self.packet_tx.send(Packet::from(data)).is_err() {
+                            // connection dropped
+                            return false;
+                        }
+                    }
+                    Ok(Message::Close(_)) => return false,
+                    // pings are answered by tungstenite
+                    Ok(_) => {}
+                    Err(err) if would_block(&err) => return true,
+                    Err(err) => {
+                        debug!("WebSocket read failed: {}", err);
+                        return false;
+                    }
+                }
+            }
+        }
+
+        /// Sends packets queued by the connection, false once either side went away
+        fn write(&mut self) -> bool {
+            loop {
+                match self.outgoing_rx.try_recv() {
+                    Ok(packet) => match self.socket.write_message(Message::Binary(packet.to_vec()))
+                    {
+                        // queued by tungstenite when the socket is busy
+                        Ok(()) => {}
+                        Err(err) if would_block(&err) => {}
+                        Err(err) => {
+                            debug!("WebSocket write failed: {}", err);
+                            return false;
+                        }
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            }
+            match self.socket.write_pending() {
+                Ok(()) => self.blocked = false,
+                Err(err) if would_block(&err) => self.blocked = true,
+                Err(err) => {
+                    debug!("WebSocket write failed: {}", err);
+                    return false;
+                }
+            }
+            true
+        }
+
+        fn close(mut self) {
+            let _ = self.socket.close(None);
+            let _ = self.socket.write_pending();
+        }
+    }
+
+    /// Accepted peer which hasn't finished the WebSocket handshake yet
+    struct Handshake {
+        mid: MidHandshake<ServerHandshake<TcpStream, NoCallback>>,
+        address: SocketAddr,
+        started: Instant,
+    }
+
+    /// Listening socket of a `WebSocketListener`
+    struct Accepting {
+        listener: TcpListener,
+        accepted_tx: Sender<TransportLink>,
+        closed: Arc<AtomicBool>,
+    }
+
+    /// Event loop of an I/O thread, moving the packets of all of its sockets.
+    /// Runs until there's nothing left to accept and all peers are gone.
+    struct Reactor {
+        poller: Arc<Poller>,
+        accepting: Option<Accepting>,
+        handshakes: HashMap<usize, Handshake>,
+        peers: HashMap<usize, Peer>,
+        next_key: usize,
+    }
+
+    impl Reactor {
+        fn new(poller: Arc<Poller>, accepting: Option<Accepting>) -> Self {
+            Reactor {
+                poller,
+                accepting,
+                handshakes: HashMap::new(),
+                peers: HashMap::new(),
+                next_key: LISTENER_KEY + 1,
+            }
+        }
+
+        fn run(mut self) {
+            let mut events = Vec::new();
+            let mut rearm = Vec::new();
+            while self.accepting.is_some() || !self.handshakes.is_empty() || !self.peers.is_empty()
+            {
+                events.clear();
+                if let Err(err) = self.poller.wait(&mut events, Some(WAIT_TIMEOUT)) {
+                    debug!("WebSocket poll failed: {}", err);
+                    return;
+                }
+                if let Some(accepting) = &self.accepting {
+                    if accepting.closed.load(Ordering::Relaxed) {
+                        let _ = self.poller.delete(&accepting.listener);
+                        self.accepting = None;
+                    }
+                }
+
+                // events are oneshot, the sockets which got one are armed again below
+                for event in events.iter() {
+                    if event.key == LISTENER_KEY {
+                        self.accept();
+                    } else if let Some(handshake) = self.handshakes.remove(&event.key) {
+                        self.handshake(event.key, handshake);
+                    } else if let Some(peer) = self.peers.get_mut(&event.key) {
+                        if !peer.read() {
+                            self.remove(event.key);
+                        }
+                    }
+                    rearm.push(event.key);
+                }
+                // packets queued by connections come without a socket event
+                let mut closed = Vec::new();
+                for (key, peer) in self.peers.iter_mut() {
+                    let blocked = peer.blocked;
+                    if !peer.write() {
+                        closed.push(*key);
+                    } else if peer.blocked != blocked {
+                        rearm.push(*key);
+                    }
+                }
+                for key in closed {
+                    self.remove(key);
+                }
+                self.expire_handshakes();
+
+                for key in rearm.drain(..) {
+                    let armed = match (key, &self.accepting) {
+                        (LISTENER_KEY, Some(accepting)) => self
+                            .poller
+                            .modify(&accepting.listener, Event::readable(LISTENER_KEY)),
+                        _ => match (self.handshakes.get(&key), self.peers.get(&key)) {
+                            (Some(handshake), _) => self
+                                .poller
+                                .modify(handshake.mid.get_ref().get_ref(), Event::readable(key)),
+                            (_, Some(peer)) => self
+                                .poller
+                                .modify(peer.socket.get_ref(), peer.interest(key)),
+                            _ => Ok(()),
+                        },
+                    };
+                    if let Err(err) = armed {
+                        debug!("WebSocket poll setup failed: {}", err);
+                    }
+                }
+            }
+        }
+
+        fn next_key(&mut self) -> usize {
+            let key = self.next_key;
+            self.next_key += 1;
+            key
+        }
+
+        /// Starts polling an open socket, with the given ends of its link
+        fn add_peer(
+            &mut self,
+            key: usize,
+            socket: WebSocket<TcpStream>,
+            (packet_tx, outgoing_rx): (Sender<Packet>, Receiver<Packet>),
+        ) {
+            let mut peer = Peer {
+                socket,
+                packet_tx,
+                outgoing_rx,
+                blocked: false,
+            };
+            // frames which came along with the handshake are already buffered
+            if peer.read() {
+                self.peers.insert(key, peer);
+            } else {
+                let _ = self.poller.delete(peer.socket.get_ref());
+                peer.close();
+            }
+        }
+
+        fn remove(&mut self, key: usize) {
+            if let Some(peer) = self.peers.remove(&key) {
+                let _ = self.poller.delete(peer.socket.get_ref());
+                peer.close();
+            }
+        }
+
+        fn accept(&mut self) {
+            let mut accepted = Vec::new();
+            if let Some(accepting) = &self.accepting {
+                loop {
+                    match accepting.listener.accept() {
+                        Ok(stream) => accepted.push(stream),
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            debug!("WebSocket accept failed: {}", err);
+                            break;
+                        }
+                    }
+                }
+            }
+            for (stream, address) in accepted {
+                // accepted sockets don't inherit non-blocking mode on all platforms
+                if let Err(err) = stream.set_nonblocking(true) {
+                    debug!("WebSocket setup for {} failed: {}", address, err);
+                    continue;
+                }
+                let _ = stream.set_nodelay(true);
+                let key = self.next_key();
+                if let Err(err) = self.poller.add(&stream, Event::readable(key)) {
+                    debug!("WebSocket poll setup for {} failed: {}", address, err);
+                    continue;
+                }
+                // the failed handshake takes the stream along
+                let raw = (&stream).raw();
+                match tungstenite::accept(stream) {
+                    Ok(socket) => self.handshake_done(key, socket, address),
+                    Err(HandshakeError::Interrupted(mid)) => {
+                        let handshake = Handshake {
+                            mid,
+                            address,
+                            started: Instant::now(),
+                        };
+                        self.handshakes.insert(key, handshake);
+                    }
+                    Err(HandshakeError::Failure(err)) => {
+                        debug!("WebSocket handshake with {} failed: {}", address, err);
+                        let _ = self.poller.delete(raw);
+                    }
+                }
+            }
+        }
+
+        fn handshake(&mut self, key: usize, handshake: Handshake) {
+            let Handshake { mid, address, .. } = handshake;
+            let raw = mid.get_ref().get_ref().raw();
+            match mid.handshake() {
+                Ok(socket) => self.handshake_done(key, socket, address),
+                Err(HandshakeError::Interrupted(mid)) => {
+                    self.handshakes.insert(key, Handshake { mid, ..handshake });
+                }
+                Err(HandshakeError::Failure(err)) => {
+                    debug!("WebSocket handshake with {} failed: {}", address, err);
+                    let _ = self.poller.delete(raw);
+                }
+            }
+        }
+
+        fn handshake_done(
+            &mut self,
+            key: usize,
+            socket: WebSocket<TcpStream>,
+            address: SocketAddr,
+        ) {
+            let (link, ends) = link_channels(Some(address), self.poller.clone());
+            let accepted = match &self.accepting {
+                Some(accepting) => accepting.accepted_tx.send(link).is_ok(),
+                None => false,
+            };
+            if accepted {
+                self.add_peer(key, socket, ends);
+            } else {
+                // listener dropped meanwhile
+                let _ = self.poller.delete(socket.get_ref());
+            }
+        }
+
+        fn expire_handshakes(&mut self) {
+            let poller = &self.poller;
+            self.handshakes.retain(|_, handshake| {
+                let waiting = handshake.started.elapsed() < HANDSHAKE_TIMEOUT;
+                if !waiting {
+                    debug!("WebSocket handshake with {} timed out", handshake.address);
+                    let _ = poller.delete(handshake.mid.get_ref().get_ref());
+                }
+                waiting
+            });
+        }
+    }
+
+    /// First address of `host` accepting a TCP connection
+    fn connect_stream(host: &str) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for address in host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, HANDSHAKE_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses")))
+    }
+
+    pub(super) fn connect(
+        url: String,
+        secure: bool,
+        host: String,
+        remote_address: Option<SocketAddr>,
+    ) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        if secure {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "native WebSocket clients don't support wss://",
+            )));
+        }
+        let poller = Arc::new(Poller::new()?);
+        let (link, ends) = link_channels(remote_address, poller.clone());
+        // connecting blocks, failures show up as a disconnect and `ConnectFailed` on timeout
+        thread::Builder::new()
+            .name(format!("websocket {}", host))
+            .spawn(move || {
+                let stream = match connect_stream(&host) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!("WebSocket connect to {} failed: {}", host, err);
+                        return;
+                    }
+                };
+                let _ = stream.set_nodelay(true);
+                let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+                let socket = match tungstenite::client(url.as_str(), stream) {
+                    Ok((socket, _response)) => socket,
+                    Err(err) => {
+                        debug!("WebSocket handshake with {} failed: {}", host, err);
+                        return;
+                    }
+                };
+                let mut reactor = Reactor::new(poller, None);
+                let key = reactor.next_key();
+                let polled = socket
+                    .get_ref()
+                    .set_nonblocking(true)
+                    .and_then(|()| reactor.poller.add(socket.get_ref(), Event::readable(key)));
+                match polled {
+                    Ok(()) => {
+                        reactor.add_peer(key, socket, ends);
+                        reactor.run();
+                    }
+                    Err(err) => debug!("WebSocket setup for {} failed: {}", host, err),
+                }
+            })?;
+        Ok(link)
+    }
+
+    /// WebSocket server backend, pass it to `NetworkResource::listen_with`.
+    ///
+    /// A single background thread accepts peers and moves the packets of all of them.
+    pub struct WebSocketListener {
+        local_address: SocketAddr,
+        accepted_rx: Receiver<TransportLink>,
+        closed: Arc<AtomicBool>,
+        poller: Arc<Poller>,
+    }
+
+    impl WebSocketListener {
+        /// Port 0 picks a free ephemeral port, see `Listener::local_address`
+        pub fn bind(address: SocketAddr) -> Result<Self, NetworkError> {
+            let bind = || -> io::Result<(TcpListener, SocketAddr, Poller)> {
+                let listener = TcpListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                let local_address = listener.local_addr()?;
+                let poller = Poller::new()?;
+                poller.add(&listener, Event::readable(LISTENER_KEY))?;
+                Ok((listener, local_address, poller))
+            };
+            let (listener, local_address, poller) =
+                bind().map_err(|err| NetworkError::BindError(address, err))?;
+            let poller = Arc::new(poller);
+
+            let (accepted_tx, accepted_rx) = unbounded();
+            let closed = Arc::new(AtomicBool::new(false));
+            let reactor = Reactor::new(
+                poller.clone(),
+                Some(Accepting {
+                    listener,
+                    accepted_tx,
+                    closed: closed.clone(),
+                }),
+            );
+            thread::Builder::new()
+                .name(format!("websocket listener {}", local_address))
+                .spawn(move || reactor.run())
+                .map_err(|err| NetworkError::BindError(address, err))?;
+
+            Ok(WebSocketListener {
+                local_address,
+                accepted_rx,
+                closed,
+                poller,
+            })
+        }
+    }
+
+    impl Listener for WebSocketListener {
+        fn local_address(&self) -> SocketAddr {
+            self.local_address
+        }
+
+        fn accept(&mut self) -> Option<TransportLink> {
+            self.accepted_rx.try_recv().ok()
+        }
+    }
+
+    impl Drop for WebSocketListener {
+        fn drop(&mut self) {
+            // peers already accepted stay open
+            self.closed.store(true, Ordering::Relaxed);
+            let _ = self.poller.notify();
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use bevy::prelude::debug;
+    use crossbeam_channel::{unbounded, Sender};
+    use std::{cell::RefCell, error::Error, io, net::SocketAddr, rc::Rc, sync::Arc};
+    use wasm_bindgen::{closure::Closure, JsCast};
+    use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+    use super::super::{
+        backend::{PacketSink, TransportLink},
+        transport::TransportKind,
+        Packet,
+    };
+
+    /// Browser WebSocket and its callbacks, which must live as long as the socket
+    struct WebSocketSink {
+        socket: WebSocket,
+        _on_message: Closure<dyn FnMut(MessageEvent)>,
+        _on_close: Closure<dyn FnMut()>,
+    }
+
+    // wasm is single threaded
+    unsafe impl Send for WebSocketSink {}
+    unsafe impl Sync for WebSocketSink {}
+
+    impl PacketSink for WebSocketSink {
+        fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+            if self.socket.ready_state() != WebSocket::OPEN {
+                // still connecting - dropped like a lost datagram
+                return Ok(());
+            }
+            self.socket.send_with_u8_array(packet).map_err(|err| {
+                Box::new(io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+                    as Box<dyn Error + Sync + Send>
+            })
+        }
+    }
+
+    impl Drop for WebSocketSink {
+        fn drop(&mut self) {
+            self.socket.set_onmessage(None);
+            self.socket.set_onclose(None);
+            let _ = self.socket.close();
+        }
+    }
+
+    pub(super) fn connect(
+        url: &str,
+        remote_address: Option<SocketAddr>,
+    ) -> Result<TransportLink, Box<dyn Error + Sync + Send>> {
+        let socket = WebSocket::new(url).map_err(|err| {
+            Box::new(io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+                as Box<dyn Error + Sync + Send>
+        })?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let (packet_tx, packet_rx) = unbounded();
+        // dropped on close, so the connection sees the disconnect
+        let packet_tx: Rc<RefCell<Option<Sender<Packet>>>> = Rc::new(RefCell::new(Some(packet_tx)));
+
+        let message_tx = packet_tx.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Ok(buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
+                let data = js_sys::Uint8Array::new(&buffer).to_vec();
+                if let Some(packet_tx) = message_tx.borrow().as_ref() {
+                    let _ = packet_tx.send(Packet::from(data));
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        let on_close = Closure::wrap(Box::new(move || {
+            debug!("WebSocket closed");
+            packet_tx.borrow_mut().take();
+        }) as Box<dyn FnMut()>);
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(TransportLink {
+            sink: Arc::new(WebSocketSink {
+                socket,
+                _on_message: on_message,
+                _on_close: on_close,
+            }),
+            source: Box::new(packet_rx),
+            remote_address,
+            kind: TransportKind::WebSocket,
+        })
+    }
+}
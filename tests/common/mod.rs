@@ -0,0 +1,80 @@
+#![allow(dead_code)]
+
+use bevy::{
+    app::{App, Events},
+    core::CorePlugin,
+    ecs::world::Mut,
+};
+use bevy_networking_turbulence::{
+    LabelledNetworkEvent, NetworkEvent, NetworkLabel, NetworkResource, NetworkSettings,
+    NetworkingPlugin,
+};
+use std::time::{Duration, Instant};
+
+/// Network of the client side, next to the default network of the server
+pub struct Client;
+
+impl NetworkLabel for Client {
+    type Event = LabelledNetworkEvent<Client>;
+}
+
+/// Headless app running a server network and a `Client` network
+pub fn app() -> App {
+    let mut app = App::new();
+    app.add_plugin(CorePlugin)
+        .add_plugin(NetworkingPlugin::default())
+        .add_plugin(NetworkingPlugin::<Client>::labelled());
+    app
+}
+
+pub fn server(app: &mut App) -> Mut<'_, NetworkResource> {
+    app.world.get_resource_mut::<NetworkResource>().unwrap()
+}
+
+pub fn client(app: &mut App) -> Mut<'_, NetworkResource<Client>> {
+    app.world
+        .get_resource_mut::<NetworkResource<Client>>()
+        .unwrap()
+}
+
+/// Events the server network sent since the last call
+pub fn server_events(app: &mut App) -> Vec<NetworkEvent> {
+    app.world
+        .get_resource_mut::<Events<NetworkEvent>>()
+        .unwrap()
+        .drain()
+        .collect()
+}
+
+/// Events the client network sent since the last call
+pub fn client_events(app: &mut App) -> Vec<NetworkEvent> {
+    app.world
+        .get_resource_mut::<Events<LabelledNetworkEvent<Client>>>()
+        .unwrap()
+        .drain()
+        .map(|event| event.event)
+        .collect()
+}
+
+/// Runs frames until `done` returns true, false if it didn't within 5 seconds
+pub fn update_until(app: &mut App, mut done: impl FnMut(&mut App) -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(5) {
+        app.update();
+        if done(app) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+pub fn client_settings(app: &mut App) -> Mut<'_, NetworkSettings<Client>> {
+    app.world
+        .get_resource_mut::<NetworkSettings<Client>>()
+        .unwrap()
+}
+
+pub fn server_settings(app: &mut App) -> Mut<'_, NetworkSettings> {
+    app.world.get_resource_mut::<NetworkSettings>().unwrap()
+}
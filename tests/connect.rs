@@ -0,0 +1,76 @@
+// native clients reach naia over plain UDP, not a WebRTC session
+#![cfg(all(
+    not(target_arch = "wasm32"),
+    feature = "naia-server-socket",
+    not(feature = "use-webrtc")
+))]
+
+mod common;
+
+use bevy_networking_turbulence::{ConnectError, NetworkEvent};
+use common::{app, client, client_events, client_settings, server, update_until};
+use std::net::UdpSocket;
+
+#[test]
+fn connected_once_the_server_accepts() {
+    let mut app = app();
+    let listener = server(&mut app)
+        .listen("127.0.0.1:0".parse().unwrap(), None, None)
+        .unwrap();
+    let port = server(&mut app).listener_address(listener).unwrap().port();
+
+    // resolved off the task pool
+    let handle = client(&mut app).connect(format!("localhost:{}", port));
+    assert!(!client(&mut app).is_connected(handle));
+    let mut events = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        events.extend(client_events(app));
+        !events.is_empty()
+    }));
+    assert!(matches!(events[..], [NetworkEvent::Connected(connected)] if connected == handle));
+    assert!(client(&mut app).is_connected(handle));
+    assert_eq!(client(&mut app).handles().collect::<Vec<_>>(), [handle]);
+    assert!(client(&mut app).connection_info(handle).is_some());
+}
+
+#[test]
+fn silent_server_is_never_connected() {
+    let mut app = app();
+    client_settings(&mut app).connect_timeout_ms = Some(100);
+    // bound, so the connect isn't refused, but nobody answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let handle = client(&mut app).connect(silent.local_addr().unwrap());
+    app.update();
+    // opened, but not established
+    assert!(client(&mut app).connections.contains_key(&handle));
+    assert_eq!(client(&mut app).handles().count(), 0);
+    assert_eq!(client(&mut app).iter_connections().count(), 0);
+    assert!(client(&mut app).connection_info(handle).is_none());
+
+    let mut events = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        events.extend(client_events(app));
+        !events.is_empty()
+    }));
+    assert!(matches!(
+        events[..],
+        [NetworkEvent::ConnectFailed(failed, ConnectError::Timeout)] if failed == handle
+    ));
+    assert!(!client(&mut app).is_connected(handle));
+}
+
+#[test]
+fn unresolvable_host() {
+    let mut app = app();
+    let handle = client(&mut app).connect("host.invalid:14191");
+    let mut events = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        events.extend(client_events(app));
+        !events.is_empty()
+    }));
+    assert!(matches!(
+        events[..],
+        [NetworkEvent::ConnectFailed(failed, ConnectError::Resolve(..))] if failed == handle
+    ));
+}
@@ -0,0 +1,68 @@
+// native clients reach naia over plain UDP, not a WebRTC session
+#![cfg(all(
+    not(target_arch = "wasm32"),
+    feature = "naia-server-socket",
+    not(feature = "use-webrtc")
+))]
+
+mod common;
+
+use bevy_networking_turbulence::NetworkEvent;
+use common::{app, client, server, server_events, update_until};
+use std::net::SocketAddr;
+
+#[test]
+fn ephemeral_ports_and_listener_tags() {
+    let mut app = app();
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let first = server(&mut app).listen(any, None, None).unwrap();
+    let second = server(&mut app).listen(any, None, None).unwrap();
+    assert_ne!(first, second);
+
+    let first_address = server(&mut app).listener_address(first).unwrap();
+    let second_address = server(&mut app).listener_address(second).unwrap();
+    assert_ne!(first_address.port(), 0);
+    assert_ne!(second_address.port(), 0);
+    assert_ne!(first_address, second_address);
+    assert_eq!(server(&mut app).listeners().count(), 2);
+
+    // binding a taken port is an error, not a panic
+    assert!(server(&mut app).listen(first_address, None, None).is_err());
+
+    client(&mut app).connect(second_address);
+    let mut connected = None;
+    assert!(update_until(&mut app, |app| {
+        connected = server_events(app)
+            .into_iter()
+            .find_map(|event| match event {
+                NetworkEvent::Connected(handle) => Some(handle),
+                _ => None,
+            });
+        connected.is_some()
+    }));
+    let handle = connected.unwrap();
+    let server = server(&mut app);
+    assert_eq!(server.connections[&handle].listener(), Some(second));
+    assert_eq!(
+        server.connection_info(handle).map(|info| info.listener),
+        Some(Some(second))
+    );
+}
+
+#[test]
+fn stop_listening_drops_its_peers() {
+    let mut app = app();
+    let listener = server(&mut app)
+        .listen("127.0.0.1:0".parse().unwrap(), None, None)
+        .unwrap();
+    let address = server(&mut app).listener_address(listener).unwrap();
+    client(&mut app).connect(address);
+    assert!(update_until(&mut app, |app| !server(app)
+        .connections
+        .is_empty()));
+
+    assert!(server(&mut app).stop_listening(listener));
+    assert!(!server(&mut app).stop_listening(listener));
+    assert!(server(&mut app).connections.is_empty());
+    assert_eq!(server(&mut app).listener_address(listener), None);
+}
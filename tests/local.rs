@@ -0,0 +1,86 @@
+mod common;
+
+use bevy_networking_turbulence::{
+    ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings, NetworkEvent,
+    NetworkResource,
+};
+use common::{app, client, client_events, server, server_events, update_until, Client};
+
+const CHANNEL: MessageChannelSettings = MessageChannelSettings {
+    channel: 0,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 8,
+    packet_buffer_size: 8,
+};
+
+fn channels(builder: &mut ConnectionChannelsBuilder) {
+    builder.register::<u32>(CHANNEL).unwrap();
+}
+
+#[test]
+fn local_client_talks_through_channels() {
+    let mut app = app();
+    server(&mut app).set_channels_builder(channels);
+    client(&mut app).set_channels_builder(channels);
+
+    let (server_handle, client_handle) = {
+        let world = app.world.cell();
+        let mut server = world.get_resource_mut::<NetworkResource>().unwrap();
+        let mut client = world.get_resource_mut::<NetworkResource<Client>>().unwrap();
+        server.connect_local(&mut client)
+    };
+    app.update();
+
+    assert!(matches!(
+        server_events(&mut app)[..],
+        [NetworkEvent::Connected(handle)] if handle == server_handle
+    ));
+    assert!(matches!(
+        client_events(&mut app)[..],
+        [NetworkEvent::Connected(handle)] if handle == client_handle
+    ));
+
+    // the local client is not one of the server's connections
+    assert_eq!(server(&mut app).connections.len(), 1);
+    server(&mut app).broadcast_message(7u32);
+
+    let mut received = None;
+    assert!(update_until(&mut app, |app| {
+        received = client(app).recv_message::<u32>(client_handle);
+        received.is_some()
+    }));
+    assert_eq!(received, Some(7));
+    assert!(server(&mut app)
+        .recv_message::<u32>(server_handle)
+        .is_none());
+}
+
+#[test]
+fn disconnecting_closes_the_other_end() {
+    let mut app = app();
+    // the channels task holds on to the link too
+    server(&mut app).set_channels_builder(channels);
+    client(&mut app).set_channels_builder(channels);
+    let (server_handle, client_handle) = {
+        let world = app.world.cell();
+        let mut server = world.get_resource_mut::<NetworkResource>().unwrap();
+        let mut client = world.get_resource_mut::<NetworkResource<Client>>().unwrap();
+        server.connect_local(&mut client)
+    };
+    app.update();
+    server_events(&mut app);
+    client_events(&mut app);
+
+    server(&mut app).disconnect(server_handle);
+    let mut events = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        events.extend(client_events(app));
+        !events.is_empty()
+    }));
+    assert!(matches!(
+        events[..],
+        [NetworkEvent::Disconnected(handle)] if handle == client_handle
+    ));
+    assert!(!client(&mut app).is_connected(client_handle));
+    assert!(server_events(&mut app).is_empty());
+}
@@ -0,0 +1,31 @@
+#![cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+
+mod common;
+
+use bevy_networking_turbulence::{NetworkEvent, UdpListener};
+use common::{app, client, client_events, server, update_until};
+
+#[test]
+fn custom_listeners_answer_queries() {
+    let mut app = app();
+    let listener =
+        server(&mut app).listen_with(UdpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap());
+    let address = server(&mut app).listener_address(listener).unwrap();
+    server(&mut app).set_query_handler(|_| b"lobby".to_vec());
+
+    client(&mut app).query_server(address).unwrap();
+    let mut info = None;
+    assert!(update_until(&mut app, |app| {
+        for event in client_events(app) {
+            if let NetworkEvent::ServerInfo(server_info) = event {
+                info = Some(server_info);
+            }
+        }
+        info.is_some()
+    }));
+    let info = info.unwrap();
+    assert_eq!(info.address, address);
+    assert_eq!(info.payload, &b"lobby"[..]);
+    // queries don't connect
+    assert_eq!(server(&mut app).connections.len(), 0);
+}
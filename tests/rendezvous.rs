@@ -0,0 +1,76 @@
+#![cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+
+mod common;
+
+use bevy_networking_turbulence::{
+    ConnectTarget, NetworkEvent, Packet, RendezvousServer, UdpListener,
+};
+use common::{app, client, client_events, server, server_events, update_until};
+
+#[test]
+fn clients_reach_hosts_through_the_rendezvous_server() {
+    let mut rendezvous = RendezvousServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let rendezvous_address = rendezvous.local_address().unwrap();
+    let mut app = app();
+    let listener = server(&mut app).listen_with(
+        UdpListener::bind("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .with_rendezvous(rendezvous_address, "lobby"),
+    );
+    let host_address = server(&mut app).listener_address(listener).unwrap();
+
+    // registered on the first poll of the listener
+    assert!(update_until(&mut app, |app| {
+        rendezvous.poll();
+        server(app).listener_public_address(listener).is_some()
+    }));
+    assert_eq!(
+        server(&mut app).listener_public_address(listener),
+        Some(host_address)
+    );
+
+    let handle = client(&mut app).connect(ConnectTarget::Rendezvous {
+        server: rendezvous_address,
+        session: "lobby".to_owned(),
+    });
+    let mut accepted = None;
+    let mut connected = false;
+    assert!(update_until(&mut app, |app| {
+        rendezvous.poll();
+        for event in server_events(app) {
+            if let NetworkEvent::Connected(handle) = event {
+                accepted = Some(handle);
+            }
+        }
+        connected |= client_events(app).iter().any(
+            |event| matches!(event, NetworkEvent::Connected(connected) if *connected == handle),
+        );
+        accepted.is_some() && connected
+    }));
+    let accepted = accepted.unwrap();
+
+    server(&mut app)
+        .send(accepted, Packet::from_static(b"welcome"))
+        .unwrap();
+    client(&mut app)
+        .send(handle, Packet::from_static(b"hello"))
+        .unwrap();
+    let mut to_client = Vec::new();
+    let mut to_server = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        for event in client_events(app) {
+            if let NetworkEvent::Packet(_, packet) = event {
+                to_client.push(packet);
+            }
+        }
+        for event in server_events(app) {
+            if let NetworkEvent::Packet(_, packet) = event {
+                to_server.push(packet);
+            }
+        }
+        !to_client.is_empty() && !to_server.is_empty()
+    }));
+    // punches never reach the app
+    assert_eq!(to_client, [Packet::from_static(b"welcome")]);
+    assert_eq!(to_server, [Packet::from_static(b"hello")]);
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use bevy_networking_turbulence::{
+    ConnectionChannelsBuilder, ConnectionHandle, MessageChannelMode, MessageChannelSettings,
+    NetworkResource, ReliableChannelSettings, RpcError, RpcMessage, RpcRequest,
+};
+use common::{app, client, server, update_until, Client};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const RPC_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 0,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 8,
+    packet_buffer_size: 8,
+};
+
+fn channels(builder: &mut ConnectionChannelsBuilder) {
+    builder
+        .register::<RpcMessage>(RPC_MESSAGE_SETTINGS)
+        .unwrap();
+}
+
+#[derive(Serialize, Deserialize)]
+struct Greet(String);
+
+impl RpcRequest for Greet {
+    const METHOD: &'static str = "greet";
+}
+
+#[test]
+fn calls_get_answered() {
+    let mut app = app();
+    server(&mut app).set_channels_builder(channels);
+    client(&mut app).set_channels_builder(channels);
+    server(&mut app).add_rpc_handler(|_handle, Greet(name)| Ok(format!("hello {}", name)));
+    let (_, client_handle) = {
+        let world = app.world.cell();
+        let mut server = world.get_resource_mut::<NetworkResource>().unwrap();
+        let mut client = world.get_resource_mut::<NetworkResource<Client>>().unwrap();
+        server.connect_local(&mut client)
+    };
+    app.update();
+
+    let call = client(&mut app)
+        .call::<_, String>(client_handle, Greet("there".to_string()))
+        .unwrap();
+    let mut result = None;
+    assert!(update_until(&mut app, |app| {
+        result = client(app).poll_rpc(&call);
+        result.is_some()
+    }));
+    assert_eq!(result, Some(Ok("hello there".to_string())));
+}
+
+#[test]
+fn calls_on_closed_connections_fail() {
+    let mut app = app();
+    server(&mut app).set_channels_builder(channels);
+    client(&mut app).set_channels_builder(channels);
+    let (server_handle, _) = {
+        let world = app.world.cell();
+        let mut server = world.get_resource_mut::<NetworkResource>().unwrap();
+        let mut client = world.get_resource_mut::<NetworkResource<Client>>().unwrap();
+        server.connect_local(&mut client)
+    };
+    app.update();
+    server(&mut app).disconnect(server_handle);
+    app.update();
+
+    assert!(matches!(
+        server(&mut app).call::<_, String>(server_handle, Greet("you".to_string())),
+        Err(RpcError::StaleHandle(handle)) if handle == server_handle
+    ));
+    let unknown = ConnectionHandle::from(7);
+    assert!(matches!(
+        server(&mut app).call::<_, String>(unknown, Greet("you".to_string())),
+        Err(RpcError::NoSuchConnection(handle)) if handle == unknown
+    ));
+}
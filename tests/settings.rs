@@ -0,0 +1,37 @@
+mod common;
+
+use bevy_networking_turbulence::{ConnectionSettings, NetworkResource};
+use common::{app, server, server_settings, update_until, Client};
+
+#[test]
+fn overrides_go_away_with_their_connection() {
+    let mut app = app();
+    let (server_handle, _) = {
+        let world = app.world.cell();
+        let mut server = world.get_resource_mut::<NetworkResource>().unwrap();
+        let mut client = world.get_resource_mut::<NetworkResource<Client>>().unwrap();
+        server.connect_local(&mut client)
+    };
+    app.update();
+
+    server_settings(&mut app).idle_timeout_ms = Some(3000);
+    let loading = ConnectionSettings {
+        idle_timeout_ms: Some(60000),
+        auto_heartbeat_ms: None,
+    };
+    server_settings(&mut app).set_connection(server_handle, loading);
+    app.update();
+    assert_eq!(server_settings(&mut app).connection(server_handle), loading);
+
+    server(&mut app).disconnect(server_handle);
+    // swept on the next heartbeats and timeouts check
+    assert!(update_until(&mut app, |app| {
+        server_settings(app).connection(server_handle) != loading
+    }));
+    assert_eq!(
+        server_settings(&mut app)
+            .connection(server_handle)
+            .idle_timeout_ms,
+        Some(3000)
+    );
+}
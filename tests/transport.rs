@@ -0,0 +1,72 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use bevy_networking_turbulence::{Listener, NetworkEvent, Packet, TransportKind, TransportLink};
+use common::{app, server, server_events, update_until};
+use crossbeam_channel::unbounded;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Accepts one in-memory peer and records disconnects
+struct TestListener {
+    pending: Option<TransportLink>,
+    disconnected: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl Listener for TestListener {
+    fn local_address(&self) -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    fn accept(&mut self) -> Option<TransportLink> {
+        self.pending.take()
+    }
+
+    fn disconnected(&mut self, address: SocketAddr) {
+        self.disconnected.lock().unwrap().push(address);
+    }
+}
+
+#[test]
+fn listener_hears_about_disconnects() {
+    let mut app = app();
+    let peer: SocketAddr = "127.0.0.1:2".parse().unwrap();
+    let (sink, _sent) = unbounded::<Packet>();
+    let (_receive, source) = unbounded::<Packet>();
+    let disconnected = Arc::new(Mutex::new(Vec::new()));
+    let listener = server(&mut app).listen_with(TestListener {
+        pending: Some(TransportLink {
+            sink: Arc::new(sink),
+            source: Box::new(source),
+            remote_address: Some(peer),
+            kind: TransportKind::Custom("test"),
+        }),
+        disconnected: disconnected.clone(),
+    });
+
+    let mut connected = None;
+    assert!(update_until(&mut app, |app| {
+        connected = server_events(app)
+            .into_iter()
+            .find_map(|event| match event {
+                NetworkEvent::Connected(handle) => Some(handle),
+                _ => None,
+            });
+        connected.is_some()
+    }));
+    let handle = connected.unwrap();
+    assert_eq!(
+        server(&mut app).connections[&handle].listener(),
+        Some(listener)
+    );
+    assert_eq!(
+        server(&mut app).connections[&handle].transport(),
+        TransportKind::Custom("test")
+    );
+
+    server(&mut app).disconnect(handle);
+    assert_eq!(*disconnected.lock().unwrap(), [peer]);
+}
@@ -0,0 +1,72 @@
+#![cfg(all(not(target_arch = "wasm32"), feature = "std-udp"))]
+
+mod common;
+
+use bevy_networking_turbulence::{NetworkEvent, Packet, UdpListener, UdpTransport};
+use common::{app, client, client_events, server, server_events, update_until};
+use std::sync::Arc;
+
+#[test]
+fn packets_both_ways_over_std_udp() {
+    let mut app = app();
+    let listener =
+        server(&mut app).listen_with(UdpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap());
+    let address = server(&mut app).listener_address(listener).unwrap();
+
+    let handle = client(&mut app).connect_with(Arc::new(UdpTransport), address);
+    let mut accepted = None;
+    let mut connected = false;
+    assert!(update_until(&mut app, |app| {
+        for event in server_events(app) {
+            if let NetworkEvent::Connected(handle) = event {
+                accepted = Some(handle);
+            }
+        }
+        connected |= client_events(app).iter().any(
+            |event| matches!(event, NetworkEvent::Connected(connected) if *connected == handle),
+        );
+        accepted.is_some() && connected
+    }));
+    let accepted = accepted.unwrap();
+    assert_eq!(
+        client(&mut app).connections[&handle].remote_address(),
+        Some(address)
+    );
+
+    // many datagrams read in one frame keep their order
+    for i in 0..50u8 {
+        client(&mut app)
+            .send(handle, Packet::from(vec![i]))
+            .unwrap();
+    }
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        received.extend(
+            server_events(app)
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetworkEvent::Packet(from, packet) if from == accepted => Some(packet[0]),
+                    _ => None,
+                }),
+        );
+        received.len() == 50
+    }));
+    assert_eq!(received, (0..50).collect::<Vec<u8>>());
+
+    server(&mut app)
+        .send(accepted, Packet::from_static(b"pong"))
+        .unwrap();
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        received.extend(
+            client_events(app)
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetworkEvent::Packet(from, packet) if from == handle => Some(packet),
+                    _ => None,
+                }),
+        );
+        !received.is_empty()
+    }));
+    assert_eq!(received, [Packet::from_static(b"pong")]);
+}
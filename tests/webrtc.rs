@@ -0,0 +1,77 @@
+#![cfg(all(
+    not(target_arch = "wasm32"),
+    feature = "use-webrtc",
+    feature = "webrtc-client"
+))]
+
+mod common;
+
+use bevy_networking_turbulence::{NetworkEvent, Packet, WebRtcTransport};
+use common::{app, client, client_events, server, server_events, update_until};
+use std::sync::Arc;
+
+#[test]
+fn packets_both_ways_over_a_data_channel() {
+    let mut app = app();
+    let listener = server(&mut app)
+        .listen(
+            "127.0.0.1:0".parse().unwrap(),
+            Some("127.0.0.1:0".parse().unwrap()),
+            None,
+        )
+        .unwrap();
+    let session_address = server(&mut app).listener_address(listener).unwrap();
+
+    let handle =
+        client(&mut app).connect_with(Arc::new(WebRtcTransport::default()), session_address);
+    let mut accepted = None;
+    let mut connected = false;
+    assert!(update_until(&mut app, |app| {
+        for event in server_events(app) {
+            if let NetworkEvent::Connected(handle) = event {
+                accepted = Some(handle);
+            }
+        }
+        connected |= client_events(app).iter().any(
+            |event| matches!(event, NetworkEvent::Connected(connected) if *connected == handle),
+        );
+        connected
+    }));
+
+    // naia reports the peer once its first packet arrives
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        client(app)
+            .send(handle, Packet::from_static(b"ping"))
+            .unwrap();
+        for event in server_events(app) {
+            match event {
+                NetworkEvent::Connected(handle) => accepted = Some(handle),
+                NetworkEvent::Packet(from, packet) if Some(from) == accepted => {
+                    received.push(packet)
+                }
+                _ => {}
+            }
+        }
+        !received.is_empty()
+    }));
+    assert!(received.iter().all(|packet| packet == &b"ping"[..]));
+    let accepted = accepted.unwrap();
+
+    server(&mut app)
+        .send(accepted, Packet::from_static(b"pong"))
+        .unwrap();
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        received.extend(
+            client_events(app)
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetworkEvent::Packet(from, packet) if from == handle => Some(packet),
+                    _ => None,
+                }),
+        );
+        !received.is_empty()
+    }));
+    assert_eq!(received, [Packet::from_static(b"pong")]);
+}
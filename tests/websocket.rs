@@ -0,0 +1,70 @@
+#![cfg(all(not(target_arch = "wasm32"), feature = "websocket"))]
+
+mod common;
+
+use bevy_networking_turbulence::{NetworkEvent, Packet, WebSocketListener, WebSocketTransport};
+use common::{app, client, client_events, server, server_events, update_until};
+use std::sync::Arc;
+
+#[test]
+fn packets_both_ways_over_a_hostname() {
+    let mut app = app();
+    let listener = server(&mut app)
+        .listen_with(WebSocketListener::bind("127.0.0.1:0".parse().unwrap()).unwrap());
+    let port = server(&mut app).listener_address(listener).unwrap().port();
+
+    // the transport gets the name, the plugin doesn't resolve it
+    let handle = client(&mut app).connect_with(
+        Arc::new(WebSocketTransport::default()),
+        format!("localhost:{}", port),
+    );
+    let mut accepted = None;
+    let mut connected = false;
+    assert!(update_until(&mut app, |app| {
+        for event in server_events(app) {
+            if let NetworkEvent::Connected(handle) = event {
+                accepted = Some(handle);
+            }
+        }
+        connected |= client_events(app).iter().any(
+            |event| matches!(event, NetworkEvent::Connected(connected) if *connected == handle),
+        );
+        accepted.is_some() && connected
+    }));
+    assert_eq!(client(&mut app).connections[&handle].remote_address(), None);
+    let accepted = accepted.unwrap();
+
+    client(&mut app)
+        .send(handle, Packet::from_static(b"ping"))
+        .unwrap();
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        received.extend(
+            server_events(app)
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetworkEvent::Packet(from, packet) if from == accepted => Some(packet),
+                    _ => None,
+                }),
+        );
+        !received.is_empty()
+    }));
+    assert_eq!(received, [Packet::from_static(b"ping")]);
+
+    server(&mut app)
+        .send(accepted, Packet::from_static(b"pong"))
+        .unwrap();
+    let mut received = Vec::new();
+    assert!(update_until(&mut app, |app| {
+        received.extend(
+            client_events(app)
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetworkEvent::Packet(from, packet) if from == handle => Some(packet),
+                    _ => None,
+                }),
+        );
+        !received.is_empty()
+    }));
+    assert_eq!(received, [Packet::from_static(b"pong")]);
+}